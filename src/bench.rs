@@ -0,0 +1,150 @@
+use std::convert::TryFrom;
+use std::fmt::Write;
+use std::time::Duration;
+
+// --benchmark, how long builds took across repos, for picking -j values
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct Stats {
+    pub(crate) builds: usize,
+    pub(crate) min: Duration,
+    pub(crate) max: Duration,
+    pub(crate) mean: Duration,
+    pub(crate) median: Duration,
+}
+
+// None without any builds
+pub(crate) fn stats(mut builds: Vec<Duration>) -> Option<Stats> {
+    builds.sort();
+    let n = builds.len();
+    let (&min, &max) = (builds.first()?, builds.last()?);
+    let median = if n.is_multiple_of(2) {
+        (builds[n / 2 - 1] + builds[n / 2]) / 2
+    } else {
+        builds[n / 2]
+    };
+    Some(Stats {
+        builds: n,
+        min,
+        max,
+        mean: builds.iter().sum::<Duration>() / u32::try_from(n).unwrap_or(u32::MAX),
+        median,
+    })
+}
+
+// cpu time of every command rema has run and waited for so far
+#[cfg(unix)]
+pub(crate) fn children_cpu() -> Option<Duration> {
+    let mut usage = std::mem::MaybeUninit::<libc::rusage>::uninit();
+    // SAFETY: getrusage only writes to the struct it's given
+    if unsafe { libc::getrusage(libc::RUSAGE_CHILDREN, usage.as_mut_ptr()) } != 0 {
+        return None;
+    }
+    // SAFETY: initialised by the successful call
+    let usage = unsafe { usage.assume_init() };
+    let secs = |t: libc::timeval| {
+        Duration::new(
+            u64::try_from(t.tv_sec).unwrap_or(0),
+            u32::try_from(t.tv_usec).unwrap_or(0) * 1000,
+        )
+    };
+    Some(secs(usage.ru_utime) + secs(usage.ru_stime))
+}
+
+#[cfg(not(unix))]
+pub(crate) fn children_cpu() -> Option<Duration> {
+    None
+}
+
+// benchmark: 3 builds
+//   min         1.204s
+//   ...
+//   wall        6.000s
+//   summed     16.400s  2.7x the wall time
+pub(crate) fn render(
+    stats: Option<&Stats>,
+    wall: Duration,
+    summed: Duration,
+    cpu: Option<Duration>,
+) -> String {
+    let mut out = String::new();
+    let row = |out: &mut String, name: &str, d: Duration| {
+        let _ = write!(out, "  {name:<8} {:>9.3}s", d.as_secs_f64());
+    };
+    match stats {
+        Some(s) => {
+            let _ = writeln!(out, "benchmark: {} builds", s.builds);
+            for (name, d) in [
+                ("min", s.min),
+                ("max", s.max),
+                ("mean", s.mean),
+                ("median", s.median),
+            ] {
+                row(&mut out, name, d);
+                out.push('\n');
+            }
+        }
+        None => out.push_str("benchmark: no builds\n"),
+    }
+    row(&mut out, "wall", wall);
+    out.push('\n');
+    row(&mut out, "summed", summed);
+    if !wall.is_zero() {
+        let _ = write!(
+            out,
+            "  {:.1}x the wall time",
+            summed.as_secs_f64() / wall.as_secs_f64()
+        );
+    }
+    out.push('\n');
+    if let Some(cpu) = cpu {
+        row(&mut out, "cpu", cpu);
+        out.push('\n');
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn secs(s: &[u64]) -> Vec<Duration> {
+        s.iter().map(|&s| Duration::from_secs(s)).collect()
+    }
+
+    #[test]
+    fn test_stats() {
+        let s = stats(secs(&[4, 1, 9, 2])).unwrap();
+        assert_eq!(s.builds, 4);
+        assert_eq!(
+            (s.min, s.max),
+            (Duration::from_secs(1), Duration::from_secs(9))
+        );
+        assert_eq!(s.mean, Duration::from_secs(4));
+        assert_eq!(s.median, Duration::from_secs(3));
+        assert_eq!(
+            stats(secs(&[5, 1, 3])).unwrap().median,
+            Duration::from_secs(3)
+        );
+        assert_eq!(stats(Vec::new()), None);
+    }
+
+    #[test]
+    fn test_render() {
+        let s = stats(secs(&[1, 3])).unwrap();
+        let out = render(
+            Some(&s),
+            Duration::from_secs(2),
+            Duration::from_secs(4),
+            Some(Duration::from_millis(3500)),
+        );
+        assert_eq!(
+            out,
+            "benchmark: 2 builds\n  min          1.000s\n  max          3.000s\n  mean         2.000s\n  median       2.000s\n  wall         2.000s\n  summed       4.000s  2.0x the wall time\n  cpu          3.500s\n"
+        );
+        let none = render(None, Duration::ZERO, Duration::ZERO, None);
+        assert_eq!(
+            none,
+            "benchmark: no builds\n  wall         0.000s\n  summed       0.000s\n"
+        );
+    }
+}
@@ -0,0 +1,91 @@
+use std::env;
+use std::ffi::OsString;
+use std::str::FromStr;
+
+// --color, whether output gets ANSI colors: the log lines on stderr and the
+// status table and cells on stdout, each checked for a terminal on its own
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) enum ColorChoice {
+    Always,
+    Auto,
+    Never,
+}
+
+impl FromStr for ColorChoice {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "always" => Ok(Self::Always),
+            "auto" => Ok(Self::Auto),
+            "never" => Ok(Self::Never),
+            _ => Err(format!(
+                "unknown color choice {s:?}, expected always, auto or never"
+            )),
+        }
+    }
+}
+
+impl ColorChoice {
+    // auto colors a terminal unless NO_COLOR is set to anything, see
+    // https://no-color.org
+    pub(crate) fn enabled(self, terminal: bool) -> bool {
+        self.resolve(terminal, env::var_os("NO_COLOR"))
+    }
+
+    fn resolve(self, terminal: bool, no_color: Option<OsString>) -> bool {
+        match self {
+            Self::Always => true,
+            Self::Never => false,
+            Self::Auto => terminal && no_color.is_none_or(|v| v.is_empty()),
+        }
+    }
+}
+
+// what a colored bit of output is saying
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) enum Tone {
+    Good,
+    Attention,
+    Bad,
+}
+
+// `s` in its tone's color, as is when color is off
+pub(crate) fn paint(s: &str, tone: Tone, on: bool) -> String {
+    if !on {
+        return s.into();
+    }
+    let code = match tone {
+        Tone::Good => "32",
+        Tone::Attention => "33",
+        Tone::Bad => "31",
+    };
+    format!("\x1b[{code}m{s}\x1b[0m")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_resolve() {
+        let auto = ColorChoice::Auto;
+        assert!(auto.resolve(true, None));
+        assert!(!auto.resolve(false, None));
+        assert!(!auto.resolve(true, Some("1".into())));
+        assert!(auto.resolve(true, Some("".into())));
+        assert!(ColorChoice::Always.resolve(false, Some("1".into())));
+        assert!(!ColorChoice::Never.resolve(true, None));
+        assert_eq!("always".parse(), Ok(ColorChoice::Always));
+        assert!("yes".parse::<ColorChoice>().is_err());
+    }
+
+    #[test]
+    fn test_paint() {
+        assert_eq!(paint("up to date", Tone::Good, false), "up to date");
+        assert_eq!(
+            paint("behind 2", Tone::Attention, true),
+            "\x1b[33mbehind 2\x1b[0m"
+        );
+    }
+}
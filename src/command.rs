@@ -0,0 +1,187 @@
+use std::collections::BTreeMap;
+use std::convert::TryFrom;
+use std::path::{Path, PathBuf};
+
+use glob::Pattern;
+use serde::Deserialize;
+
+// A build or clean step, written either as a bare command line or as a
+// table with extra options, e.g. { run = "cargo build", if_changed = ["*.rs"] }
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(try_from = "CommandRepr")]
+pub(crate) struct Command {
+    pub(crate) run: String,
+    // only run when a file changed by the pull matches one of these globs
+    pub(crate) if_changed: Vec<String>,
+    // None for the default, see Stdin
+    pub(crate) stdin: Option<Stdin>,
+    // where it runs instead of the repo root, relative to the root unless
+    // absolute
+    pub(crate) cwd: Option<PathBuf>,
+    // who it runs as, unix only and rema has to be root to switch
+    pub(crate) run_as_user: Option<String>,
+    pub(crate) run_as_group: Option<String>,
+    // table keys it doesn't know, warned about when the config loads
+    pub(crate) unknown: Vec<String>,
+}
+
+// What a command reads on stdin: `stdin = "inherit"`, `stdin = "null"` or
+// `stdin = { string = "..." }`. Inheriting is the default with one build
+// job, close it for commands that would otherwise sit waiting on a prompt.
+// With more jobs or under daemon the default is null, several commands
+// can't share a terminal and the daemon has no one to answer.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum Stdin {
+    Inherit,
+    Null,
+    String(String),
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum CommandRepr {
+    Line(String),
+    Table {
+        run: String,
+        #[serde(default)]
+        if_changed: Vec<String>,
+        stdin: Option<Stdin>,
+        cwd: Option<PathBuf>,
+        run_as_user: Option<String>,
+        run_as_group: Option<String>,
+        #[serde(flatten)]
+        unknown: BTreeMap<String, toml::Value>,
+    },
+}
+
+impl TryFrom<CommandRepr> for Command {
+    type Error = String;
+
+    fn try_from(r: CommandRepr) -> Result<Self, Self::Error> {
+        let c = match r {
+            CommandRepr::Line(run) => Self::from(run.as_str()),
+            CommandRepr::Table {
+                run,
+                if_changed,
+                stdin,
+                cwd,
+                run_as_user,
+                run_as_group,
+                unknown,
+            } => Self {
+                run,
+                if_changed,
+                stdin,
+                cwd,
+                run_as_user,
+                run_as_group,
+                unknown: unknown.into_keys().collect(),
+            },
+        };
+        for p in &c.if_changed {
+            Pattern::new(p).map_err(|e| format!("invalid if_changed pattern {p:?}: {e}"))?;
+        }
+        Ok(c)
+    }
+}
+
+impl From<&str> for Command {
+    fn from(run: &str) -> Self {
+        Self {
+            run: run.into(),
+            if_changed: vec![],
+            stdin: None,
+            cwd: None,
+            run_as_user: None,
+            run_as_group: None,
+            unknown: vec![],
+        }
+    }
+}
+
+// Whether any changed path matches the guard. Like .gitignore, a pattern
+// without a slash matches a file name at any depth, otherwise it is matched
+// against the path from the repo root.
+pub(crate) fn matches_changed(patterns: &[String], changed: &[PathBuf]) -> bool {
+    patterns
+        .iter()
+        .filter_map(|p| Pattern::new(p).ok().map(|pat| (p.contains('/'), pat)))
+        .any(|(anchored, pat)| {
+            changed
+                .iter()
+                .any(|path| pat.matches_path(path) || (!anchored && file_name_matches(&pat, path)))
+        })
+}
+
+fn file_name_matches(pat: &Pattern, path: &Path) -> bool {
+    path.file_name()
+        .is_some_and(|n| pat.matches(&n.to_string_lossy()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_command_forms() {
+        #[derive(Deserialize)]
+        struct T {
+            build: Vec<Command>,
+        }
+        let t: T = toml::from_str(
+            r#"build = ["make", { run = "cargo build", if_changed = ["Cargo.toml"] }]"#,
+        )
+        .unwrap();
+        assert_eq!(t.build[0], Command::from("make"));
+        assert_eq!(t.build[1].run, "cargo build");
+        assert_eq!(t.build[1].if_changed, vec!["Cargo.toml".to_string()]);
+
+        let t: T = toml::from_str(r#"build = [{ run = "npm ci", cwd = "web" }]"#).unwrap();
+        assert_eq!(t.build[0].cwd, Some(PathBuf::from("web")));
+        assert!(t.build[0].unknown.is_empty());
+
+        let t: T = toml::from_str(r#"build = [{ run = "make", cdw = "web" }]"#).unwrap();
+        assert_eq!(t.build[0].cwd, None);
+        assert_eq!(t.build[0].unknown, vec!["cdw".to_string()]);
+    }
+
+    #[test]
+    fn test_stdin_forms() {
+        #[derive(Deserialize)]
+        struct T {
+            build: Vec<Command>,
+        }
+        let t: T = toml::from_str(
+            r#"build = [
+                "make",
+                { run = "gpg --import", stdin = "null" },
+                { run = "cat", stdin = { string = "yes" } },
+            ]"#,
+        )
+        .unwrap();
+        assert_eq!(t.build[0].stdin, None);
+        assert_eq!(t.build[1].stdin, Some(Stdin::Null));
+        assert_eq!(t.build[2].stdin, Some(Stdin::String("yes".into())));
+    }
+
+    #[test]
+    fn test_invalid_pattern_rejected() {
+        #[derive(Deserialize)]
+        struct T {
+            #[allow(dead_code)]
+            build: Vec<Command>,
+        }
+        let r: Result<T, _> = toml::from_str(r#"build = [{ run = "x", if_changed = ["[a"] }]"#);
+        assert!(r.is_err());
+    }
+
+    #[test]
+    fn test_matches_changed() {
+        let changed = vec![PathBuf::from("crates/core/Cargo.toml"), "README.md".into()];
+        assert!(matches_changed(&["Cargo.toml".into()], &changed));
+        assert!(matches_changed(&["*.md".into()], &changed));
+        assert!(matches_changed(&["crates/*/Cargo.toml".into()], &changed));
+        assert!(!matches_changed(&["src/*.rs".into()], &changed));
+    }
+}
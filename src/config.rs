@@ -1,35 +1,762 @@
-use crate::errors::ConfigError;
+use crate::command::{self, Command, Stdin};
+use crate::duration;
+use crate::errors::{CommandError, ConfigError};
+use crate::events::{self, Event};
+use crate::git::{self, FileChange, TagUpdates};
+use crate::history;
+use crate::interp;
+use crate::migrate;
+use crate::output::{self, OutputMode, Pipe};
+use crate::resource;
+use crate::signature;
+use crate::status::{self, RepoStatus, StateFilter};
+use crate::timing::{Step, Timings};
+use crate::user;
 
+use std::cell::{Cell, RefCell};
+use std::collections::{BTreeMap, BTreeSet};
 use std::convert::TryFrom;
-use std::fmt;
+use std::fmt::{self, Write as _};
 use std::fs;
+use std::io::{self, Write};
 use std::path::{Path, PathBuf};
+use std::process::{Child, ExitStatus, Stdio};
+use std::thread;
+use std::time::{Duration, Instant};
 
 use git2::Repository;
-use serde::Deserialize;
+use regex::Regex;
+use serde::{Deserialize, Deserializer, Serialize};
+use tracing::{info, warn};
 
-// Config for building a repo
+// Global config, points rema at the directory holding the repos
+#[allow(clippy::struct_excessive_bools)]
 #[derive(Deserialize)]
+pub(crate) struct Config {
+    base_dir: PathBuf,
+    // default per-command timeout for repos that don't set their own
+    #[serde(default, deserialize_with = "duration::deserialize_opt")]
+    pub(crate) timeout: Option<Duration>,
+    // default overall time limit for a repo's pull, build and clean
+    #[serde(default, deserialize_with = "duration::deserialize_opt")]
+    repo_timeout: Option<Duration>,
+    // repo name -> url, `rema clone` clones any missing from base_dir
+    #[serde(default)]
+    clone: BTreeMap<String, String>,
+    // default sentinel for repos that don't set their own skip_file
+    #[serde(default)]
+    skip_file: Option<PathBuf>,
+    // repos whose directory or git repo has gone are skipped with a warning
+    // instead of failing the run, a repo's own skip_missing wins
+    #[serde(default)]
+    skip_missing: bool,
+    // clone [clone] repos that have gone missing, like --auto-clone
+    #[serde(default)]
+    pub(crate) auto_clone: bool,
+    // default built_commit_file for repos that don't set their own
+    #[serde(default)]
+    built_commit_file: Option<PathBuf>,
+    // clone [clone] repos through this shared object cache, see
+    // git::clone_cached for what that costs
+    #[serde(default)]
+    pub(crate) cache_dir: Option<PathBuf>,
+    // runs of each repo kept for `rema history`
+    #[serde(default)]
+    history_len: Option<usize>,
+    // what a bare `rema` runs, e.g. ["pull", "update"], each may have args
+    #[serde(default)]
+    pub(crate) default: Vec<String>,
+    // undefined ${VAR} in a rema.toml is an error instead of empty
+    #[serde(skip)]
+    pub(crate) strict: bool,
+    // open repos on a thread per core, for base dirs with thousands of them
+    #[serde(skip)]
+    pub(crate) parallel_discovery: bool,
+    #[serde(skip)]
+    pub(crate) output: OutputMode,
+    #[serde(skip)]
+    pub(crate) max_repo_output: Option<usize>,
+    #[serde(skip)]
+    pub(crate) filter: StateFilter,
+    // --repos-from, only these repos
+    #[serde(skip)]
+    pub(crate) names: Option<Vec<String>>,
+    // --start-from and --stop-after, only the repos from one to the other
+    #[serde(skip)]
+    pub(crate) start_from: Option<String>,
+    #[serde(skip)]
+    pub(crate) stop_after: Option<String>,
+    // refuse rather than fix a remote that differs from remote_url
+    #[serde(skip)]
+    pub(crate) no_mutate_remotes: bool,
+    // run commands with only PATH, HOME, LANG and the repo's env
+    #[serde(skip)]
+    pub(crate) isolate_env: bool,
+    // --build-arg, over each repo's build_args
+    #[serde(skip)]
+    pub(crate) build_args: BTreeMap<String, String>,
+    // --local-only, nothing touches the network
+    #[serde(skip)]
+    pub(crate) local_only: bool,
+    // more than one build job or the daemon, commands without a stdin of
+    // their own get none
+    #[serde(skip)]
+    pub(crate) close_stdin: bool,
+    // --build-args, added to the end of every build command
+    #[serde(skip)]
+    pub(crate) extra_build_args: Vec<String>,
+    // --since-tag, over each repo's since_tag
+    #[serde(skip)]
+    pub(crate) since_tag: Option<glob::Pattern>,
+    // --trace-timing, time each step of a repo's run
+    #[serde(skip)]
+    pub(crate) trace_timing: bool,
+    // --set, fields put over every repo's rema.toml
+    #[serde(skip)]
+    pub(crate) overrides: toml::value::Table,
+    // --env-prefix, over DEFAULT_ENV_PREFIX
+    #[serde(skip)]
+    pub(crate) env_prefix: Option<String>,
+    // keys rema doesn't know, usually typos
+    #[serde(flatten)]
+    unknown: BTreeMap<String, toml::Value>,
+}
+
+impl Config {
+    // `profile` picks a [profile.<name>] table merged over the top level the
+    // same way rema.local.toml is merged over rema.toml
+    pub(crate) fn load(p: &Path, profile: Option<&str>) -> Result<Self, ConfigError> {
+        let mut v: toml::Value = toml::from_str(&fs::read_to_string(p)?)?;
+        migrate::check(&v).map_err(|e| ConfigError::Version(p.into(), e))?;
+        let mut profiles = v
+            .as_table_mut()
+            .and_then(|t| t.remove("profile"))
+            .and_then(|p| match p {
+                toml::Value::Table(t) => Some(t),
+                _ => None,
+            })
+            .unwrap_or_default();
+        if let Some(name) = profile {
+            let overrides = profiles.remove(name).ok_or_else(|| {
+                let known = profiles.keys().cloned().collect();
+                ConfigError::UnknownProfile(name.into(), known)
+            })?;
+            merge(&mut v, overrides);
+        }
+        let mut c: Self = v.try_into()?;
+        load_warnings(p, unknown_keys(&c.unknown));
+        let expanded = shellexpand::tilde(&c.base_dir.to_string_lossy()).into_owned();
+        c.base_dir = PathBuf::from(expanded);
+        c.cache_dir = c
+            .cache_dir
+            .map(|d| PathBuf::from(shellexpand::tilde(&d.to_string_lossy()).into_owned()));
+
+        if c.base_dir.is_relative() {
+            return Err(ConfigError::BaseDirRelative(p.into()));
+        }
+        if !c.base_dir.is_dir() {
+            return Err(ConfigError::BaseDirNotDir(c.base_dir));
+        }
+        Ok(c)
+    }
+
+    pub(crate) fn history_len(&self) -> usize {
+        self.history_len.unwrap_or(history::DEFAULT_LEN)
+    }
+
+    // every direct subdirectory of base_dir containing a rema.toml
+    pub(crate) fn repos(&self) -> Result<Vec<RemaConfig>, ConfigError> {
+        let mut dirs = fs::read_dir(&self.base_dir)?
+            .filter_map(Result::ok)
+            .map(|e| e.path())
+            .filter(|p| p.join("rema.toml").is_file())
+            .collect::<Vec<_>>();
+        dirs.sort();
+        if let Some(names) = &self.names {
+            let named = |d: &PathBuf, n: &str| d.file_name().is_some_and(|f| f == n);
+            for n in names {
+                if !dirs.iter().any(|d| named(d, n)) {
+                    warn!("no repo named {:?} in {}", n, self.base_dir.display());
+                }
+            }
+            dirs.retain(|d| names.iter().any(|n| named(d, n)));
+        }
+        select_range(
+            &mut dirs,
+            self.start_from.as_deref(),
+            self.stop_after.as_deref(),
+        )?;
+
+        // every broken repo is reported, not just the first
+        let mut repos = Vec::new();
+        let mut errors = Vec::new();
+        for r in self.load_all(dirs) {
+            match r {
+                Ok(r) => repos.push(r),
+                Err(ConfigError::Missing(p, skip)) if skip.unwrap_or(self.skip_missing) => {
+                    warn!(event = "missing", "{}: missing, skipped", p.display());
+                }
+                Err(e) => errors.push(e),
+            }
+        }
+        if !errors.is_empty() {
+            return Err(ConfigError::Repos(errors));
+        }
+        skip_submodules(&mut repos);
+        for r in &mut repos {
+            r.timeout = r.timeout.or(self.timeout);
+            r.repo_timeout = r.repo_timeout.or(self.repo_timeout);
+            r.skip_file = r.skip_file.take().or_else(|| self.skip_file.clone());
+            r.built_commit_file = r
+                .built_commit_file
+                .take()
+                .or_else(|| self.built_commit_file.clone());
+            r.output = self.output;
+            r.output_cap = output::Cap::new(self.max_repo_output);
+            r.no_mutate_remotes = self.no_mutate_remotes;
+            r.isolate_env = self.isolate_env;
+            r.build_args.extend(self.build_args.clone());
+            r.local_only = self.local_only;
+            r.close_stdin = self.close_stdin;
+            r.extra_build_args.clone_from(&self.extra_build_args);
+            r.trace_timing = self.trace_timing;
+            r.env_prefix.clone_from(&self.env_prefix);
+            if self.since_tag.is_some() {
+                r.since_tag.clone_from(&self.since_tag);
+            }
+        }
+        if self.filter.is_active() {
+            repos.retain(|r| match RepoStatus::of(r) {
+                Ok(s) => self.filter.keep(&s),
+                Err(e) => {
+                    warn!(repo = %r.name(), "could not read status, skipping: {}", e);
+                    false
+                }
+            });
+        }
+        Ok(repos)
+    }
+
+    // repos listed under [clone] whose directory doesn't exist yet, with
+    // the path each would be cloned to
+    pub(crate) fn missing_clones(&self) -> Vec<(&str, &str, PathBuf)> {
+        self.clone
+            .iter()
+            .map(|(name, url)| (name.as_str(), url.as_str(), self.base_dir.join(name)))
+            .filter(|(_, _, p)| !p.exists())
+            .collect()
+    }
+
+    // clone a [clone] repo, through cache_dir when there is one
+    pub(crate) fn clone_repo(&self, url: &str, path: &Path) -> Result<Repository, git2::Error> {
+        match &self.cache_dir {
+            Some(cache) => git::clone_cached(url, path, cache, None),
+            None => git::clone(url, path, None),
+        }
+    }
+
+    // results stay in the order of `dirs`
+    fn load_all(&self, dirs: Vec<PathBuf>) -> Vec<Result<RemaConfig, ConfigError>> {
+        let threads = thread::available_parallelism().map_or(1, usize::from);
+        if !self.parallel_discovery || threads < 2 || dirs.len() < 2 {
+            return dirs
+                .into_iter()
+                .map(|p| RemaConfig::load_with(p, self.strict, &self.overrides))
+                .collect();
+        }
+
+        let chunk = dirs.len().div_ceil(threads);
+        thread::scope(|s| {
+            let handles = dirs
+                .chunks(chunk)
+                .map(|ps| {
+                    s.spawn(move || {
+                        ps.iter()
+                            .map(|p| RemaConfig::load_with(p.clone(), self.strict, &self.overrides))
+                            .collect::<Vec<_>>()
+                    })
+                })
+                .collect::<Vec<_>>();
+            handles
+                .into_iter()
+                .flat_map(|h| h.join().unwrap())
+                .collect()
+        })
+    }
+}
+
+// Config for building a repo
+#[allow(clippy::struct_excessive_bools)]
+#[derive(Default, Deserialize)]
 pub(crate) struct RemaConfig {
     #[serde(skip)]
     repo: Option<Repository>,
+    #[serde(skip)]
+    name: String,
+    #[serde(default)]
+    tags: Vec<String>,
+    #[serde(default)]
+    build: Vec<Command>,
     #[serde(default)]
-    build: Vec<String>,
+    clean: Vec<Command>,
     #[serde(default)]
-    clean: Vec<String>,
+    post_update: Vec<Command>,
+    // run before fetching, a failure skips the pull
+    #[serde(default)]
+    pre_pull: Vec<Command>,
+    // paths relative to the working tree that clean must leave absent or empty
+    #[serde(default)]
+    clean_expect_absent: Vec<PathBuf>,
     #[serde(default)]
     autoclean: bool,
+    // also autoclean after a failed build, which can erase what's needed to
+    // debug it
+    #[serde(default)]
+    clean_on_failure: bool,
     #[serde(default)]
     autoupdate: bool,
+    // run clean when the build started by an autoupdate pull fails
+    #[serde(default)]
+    autoupdate_clean_on_failure: bool,
+    #[serde(default)]
+    env: BTreeMap<String, String>,
+    // values for `{name}` in commands, --build-arg overrides them
+    #[serde(default)]
+    build_args: BTreeMap<String, String>,
+    // extra fetch attempts on transient network errors
+    #[serde(default)]
+    pull_retries: u32,
+    // attempts at the whole pull and build after one fails, for repos whose
+    // end to end setup is flaky; pull_retries and the rest still happen
+    // within each attempt
+    #[serde(default)]
+    repo_retries: u32,
+    // run clean between those attempts
+    #[serde(default)]
+    repo_retry_clean: bool,
+    #[serde(skip)]
+    attempts: Cell<u32>,
+    // uncommitted changes before the first attempt, which the reset between
+    // attempts would lose
+    #[serde(skip)]
+    dirty_at_start: Cell<bool>,
+    // kill a command running longer than this
+    #[serde(default, deserialize_with = "duration::deserialize_opt")]
+    timeout: Option<Duration>,
+    // timeout for build and for clean commands, timeout when unset
+    #[serde(default, deserialize_with = "duration::deserialize_opt")]
+    timeout_build: Option<Duration>,
+    #[serde(default, deserialize_with = "duration::deserialize_opt")]
+    timeout_clean: Option<Duration>,
+    // bounds the sum of pull, build and clean, see start_clock
+    #[serde(default, deserialize_with = "duration::deserialize_opt")]
+    repo_timeout: Option<Duration>,
+    #[serde(skip)]
+    deadline: Cell<Option<Instant>>,
+    #[serde(skip)]
+    started: Cell<Option<Instant>>,
+    #[serde(skip)]
+    output: OutputMode,
+    #[serde(skip)]
+    output_buf: output::Buffer,
+    #[serde(skip)]
+    output_cap: output::Cap,
+    // names the run_env and pull_env variables start with, DEFAULT_ENV_PREFIX
+    // when unset
+    #[serde(skip)]
+    env_prefix: Option<String>,
+    // the HEAD the build in progress was pulled from, for run_env
+    #[serde(skip)]
+    since: Cell<Option<git2::Oid>>,
+    // RENAMED and DELETED under env_prefix for the commands run after a pull,
+    // cleared by every build
+    #[serde(skip)]
+    pull_env: RefCell<BTreeMap<String, String>>,
+    // env var with a token for https remotes, defaults to REMA_GIT_TOKEN
+    #[serde(default)]
+    token_env: Option<String>,
+    // niceness for spawned commands, unix only and best-effort: lowering it
+    // below the current value needs privileges and is silently skipped
+    #[serde(default)]
+    nice: Option<i32>,
+    // the remote url must match this before anything is fetched
+    #[serde(default, deserialize_with = "deserialize_regex")]
+    allowed_remote_url: Option<Regex>,
+    // the url the upstream remote should have, fixed before each pull
+    #[serde(default)]
+    remote_url: Option<String>,
+    #[serde(skip)]
+    no_mutate_remotes: bool,
+    #[serde(skip)]
+    isolate_env: bool,
+    #[serde(skip)]
+    local_only: bool,
+    #[serde(skip)]
+    close_stdin: bool,
+    #[serde(skip)]
+    extra_build_args: Vec<String>,
+    // what `rema verify` expects the repo to be on, any revision git
+    // understands for the commit
+    #[serde(default)]
+    pinned_commit: Option<String>,
+    #[serde(default)]
+    expected_branch: Option<String>,
+    // while this file exists in the working tree builds are skipped, relative
+    // to it and .rema-skip by default
+    #[serde(default)]
+    skip_file: Option<PathBuf>,
+    // where each good build writes the commit it built, see
+    // write_built_commit
+    #[serde(default)]
+    built_commit_file: Option<PathBuf>,
+    // checked after the pull and before the build like skip_file, builds are
+    // skipped when it exits 0, e.g. `skip_if = "test -f /etc/maintenance"`
+    #[serde(default)]
+    skip_if: Option<Command>,
+    // globs from the working tree that --hash-cache hashes, untracked files
+    // included; the tracked files when empty
+    #[serde(default)]
+    hash_inputs: Vec<String>,
+    // over the root config's skip_missing, for when the repo's .git is gone
+    #[serde(default)]
+    skip_missing: Option<bool>,
+    // refuse to build a HEAD that isn't signed by one of trusted_keys, GPG
+    // fingerprints or ssh public keys as in authorized_keys
+    #[serde(default)]
+    verify_signatures: bool,
+    #[serde(default)]
+    trusted_keys: Vec<String>,
+    // env var sets the build runs once each with, over env, e.g.
+    // `matrix = [{ PROFILE = "debug" }, { PROFILE = "release" }]`
+    #[serde(default)]
+    matrix: Vec<BTreeMap<String, String>>,
+    // stop at the first failing matrix entry instead of building the rest
+    #[serde(default)]
+    fail_fast: bool,
+    // the matrix entry being built
+    #[serde(skip)]
+    matrix_env: RefCell<BTreeMap<String, String>>,
+    // fetch every tag and report what changed
+    #[serde(default)]
+    fetch_tags: bool,
+    // with fetch_tags, also delete local tags the remote no longer has; off
+    // by default, since tags only ever made locally go with them
+    #[serde(default)]
+    prune_tags: bool,
+    // from the last pull with fetch_tags
+    #[serde(skip)]
+    tag_updates: RefCell<TagUpdates>,
+    // what prefetch already did, so the pull after it does neither again
+    #[serde(skip)]
+    pre_pulled: Cell<bool>,
+    #[serde(skip)]
+    prefetched: Cell<bool>,
+    // the last pull didn't happen, see reattach
+    #[serde(skip)]
+    pull_skipped: Cell<bool>,
+    // what to fetch instead of the remote's configured refspecs, e.g.
+    // "+refs/heads/main:refs/remotes/origin/main" for just main or
+    // "+refs/notes/*:refs/notes/*" for notes; the upstream branch's tracking
+    // ref still has to be among them for the pull to move HEAD
+    #[serde(default)]
+    refspec: Option<String>,
+    // only build when a pull brings in a new tag matching this glob, e.g.
+    // "v*", however far the branch moved; tags are fetched as with fetch_tags
+    #[serde(default, deserialize_with = "deserialize_glob")]
+    since_tag: Option<glob::Pattern>,
+    // repos with the same resource never build at the same time
+    #[serde(default)]
+    resource: Option<String>,
+    // check the branch back out when HEAD is detached, e.g. after update
+    // --ref, and pull it
+    #[serde(default)]
+    reattach: bool,
+    // build a repo the state has no good build of yet even when the pull
+    // finds it up to date, e.g. one that was just cloned
+    #[serde(default)]
+    build_on_first_run: bool,
+    #[serde(skip)]
+    trace_timing: bool,
+    #[serde(skip)]
+    timings: Cell<Timings>,
+    // keys rema doesn't know, usually typos
+    #[serde(flatten)]
+    unknown: BTreeMap<String, toml::Value>,
+}
+
+const DEFAULT_SKIP_FILE: &str = ".rema-skip";
+
+const DEFAULT_ENV_PREFIX: &str = "REMA_";
+
+// a matrix entry as it's reported, e.g. "PROFILE=release,TARGET=arm"
+fn variant_name(vars: &BTreeMap<String, String>) -> String {
+    vars.iter()
+        .map(|(k, v)| format!("{k}={v}"))
+        .collect::<Vec<_>>()
+        .join(",")
+}
+
+// what --isolate-env keeps from rema's own environment
+const ISOLATED_BASELINE: [&str; 3] = ["PATH", "HOME", "LANG"];
+
+// --start-from and --stop-after, the repos between the two in the order
+// they run, both included, e.g. to resume a run that failed partway
+fn select_range(
+    dirs: &mut Vec<PathBuf>,
+    start: Option<&str>,
+    stop: Option<&str>,
+) -> Result<(), ConfigError> {
+    let find = |flag: &str, name: &str| {
+        dirs.iter()
+            .position(|d| d.file_name().is_some_and(|f| f == name))
+            .ok_or_else(|| ConfigError::Range(format!("{flag} {name}: no repo by that name")))
+    };
+    let from = start.map(|n| find("--start-from", n)).transpose()?;
+    let to = stop.map(|n| find("--stop-after", n)).transpose()?;
+    if let (Some(from), Some(to), Some(start), Some(stop)) = (from, to, start, stop) {
+        if from > to {
+            return Err(ConfigError::Range(format!(
+                "--start-from {start} comes after --stop-after {stop}"
+            )));
+        }
+    }
+    if let Some(to) = to {
+        dirs.truncate(to + 1);
+    }
+    if let Some(from) = from {
+        dirs.drain(..from);
+    }
+    Ok(())
+}
+
+// rema.local.toml on top of rema.toml: tables like env are merged key by
+// key, anything else, lists included, is replaced by the local value
+fn merge(base: &mut toml::Value, local: toml::Value) {
+    match (base, local) {
+        (toml::Value::Table(base), toml::Value::Table(local)) => {
+            for (k, v) in local {
+                match base.get_mut(&k) {
+                    Some(b) => merge(b, v),
+                    None => {
+                        base.insert(k, v);
+                    }
+                }
+            }
+        }
+        (base, local) => *base = local,
+    }
+}
+
+// A repo that is also checked out as a submodule of another managed repo,
+// e.g. through a symlink in base_dir, is left to that repo: pulling both
+// would move the submodule's HEAD behind the parent's back
+fn skip_submodules(repos: &mut Vec<RemaConfig>) {
+    let mut parents = BTreeMap::new();
+    for r in repos.iter() {
+        match git::submodule_dirs(r.repo()) {
+            Ok(dirs) => parents.extend(dirs.into_iter().map(|d| (d, r.name.clone()))),
+            Err(e) => warn!(repo = %r.name, "could not list submodules: {}", e),
+        }
+    }
+    if parents.is_empty() {
+        return;
+    }
+    repos.retain(|r| {
+        let dir = r.workdir().canonicalize().ok();
+        let Some(parent) = dir.and_then(|d| parents.get(&d)) else {
+            return true;
+        };
+        warn!(
+            repo = %r.name,
+            event = "submodule",
+            "a submodule of {}, skipped so it isn't managed twice",
+            parent
+        );
+        false
+    });
+}
+
+// keys neither config knows, `version` is read by migrate before either
+fn unknown_keys(keys: &BTreeMap<String, toml::Value>) -> impl Iterator<Item = String> + '_ {
+    keys.keys()
+        .filter(|k| *k != "version")
+        .map(|k| format!("unknown key {k:?}, ignored"))
+}
+
+// keys a structured command doesn't know, e.g. { run = "make", cdw = "web" }
+fn unknown_command_keys(commands: &[Command]) -> impl Iterator<Item = String> + '_ {
+    commands.iter().flat_map(|c| {
+        c.unknown
+            .iter()
+            .map(move |k| format!("unknown key {k:?} in command {:?}, ignored", c.run))
+    })
+}
+
+// what's odd about a config but doesn't stop it loading, -W error fails the
+// load on them through warnings::check_since
+fn load_warnings(p: &Path, warnings: impl Iterator<Item = String>) {
+    for w in warnings {
+        warn!(event = "config_warning", "in {}: {}", p.display(), w);
+    }
+}
+
+// the repo fields --set takes, the ones holding a single value
+const OVERRIDABLE: &[&str] = &[
+    "autoupdate",
+    "autoclean",
+    "clean_on_failure",
+    "autoupdate_clean_on_failure",
+    "pull_retries",
+    "repo_retries",
+    "repo_retry_clean",
+    "timeout",
+    "timeout_build",
+    "timeout_clean",
+    "repo_timeout",
+    "token_env",
+    "nice",
+    "allowed_remote_url",
+    "remote_url",
+    "pinned_commit",
+    "expected_branch",
+    "skip_file",
+    "built_commit_file",
+    "skip_missing",
+    "verify_signatures",
+    "fail_fast",
+    "fetch_tags",
+    "prune_tags",
+    "refspec",
+    "since_tag",
+    "resource",
+    "build_on_first_run",
+    "reattach",
+];
+
+// --set key=value, the value read as a toml bool, number or string, anything
+// else taken as a bare string
+pub(crate) fn parse_override(s: &str) -> Result<(String, toml::Value), String> {
+    let (key, value) = s
+        .split_once('=')
+        .ok_or_else(|| format!("expected key=value, got {s:?}"))?;
+    let key = key.trim();
+    if !OVERRIDABLE.contains(&key) {
+        return Err(format!(
+            "can't --set {key:?}, expected one of {}",
+            OVERRIDABLE.join(", ")
+        ));
+    }
+    let value = value.trim();
+    let parsed = toml::from_str::<toml::value::Table>(&format!("v = {value}"))
+        .ok()
+        .and_then(|mut t| t.remove("v"))
+        .filter(|v| !v.is_table() && !v.is_array())
+        .unwrap_or_else(|| toml::Value::String(value.into()));
+    Ok((key.into(), parsed))
+}
+
+fn deserialize_regex<'de, D>(d: D) -> Result<Option<Regex>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let s = String::deserialize(d)?;
+    Regex::new(&s).map(Some).map_err(serde::de::Error::custom)
+}
+
+fn deserialize_glob<'de, D>(d: D) -> Result<Option<glob::Pattern>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let s = String::deserialize(d)?;
+    glob::Pattern::new(&s)
+        .map(Some)
+        .map_err(serde::de::Error::custom)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum Phase {
+    PrePull,
+    SkipIf,
+    Build,
+    Clean,
+    PostUpdate,
+}
+
+// the names used in rema.toml
+impl fmt::Display for Phase {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.pad(match self {
+            Self::PrePull => "pre_pull",
+            Self::SkipIf => "skip_if",
+            Self::Build => "build",
+            Self::Clean => "clean",
+            Self::PostUpdate => "post_update",
+        })
+    }
+}
+
+// Where a command's variable came from, in set_env's order, later ones win
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) enum EnvSource {
+    // rema's own, or just ISOLATED_BASELINE of it with isolate_env
+    Inherited,
+    // REMA_REPO_NAME and the rest of run_env
+    Run,
+    // RENAMED and DELETED under env_prefix, only set by a pull
+    Pull,
+    // env in rema.toml, rema.local.toml's over it
+    Repo,
+    // the matrix entry being built
+    Matrix,
+}
+
+impl fmt::Display for EnvSource {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::Inherited => "inherited",
+            Self::Run => "run",
+            Self::Pull => "pull",
+            Self::Repo => "env",
+            Self::Matrix => "matrix",
+        })
+    }
+}
+
+// A single command as rema would run it, borrowed from its RemaConfig
+#[derive(Debug, PartialEq)]
+pub(crate) struct CommandSpec<'a> {
+    pub(crate) phase: Phase,
+    pub(crate) line: &'a str,
+    pub(crate) if_changed: &'a [String],
+    pub(crate) cwd: PathBuf,
+    pub(crate) env: &'a BTreeMap<String, String>,
+    pub(crate) stdin: &'a Stdin,
+    pub(crate) run_as_user: Option<&'a str>,
+    pub(crate) run_as_group: Option<&'a str>,
 }
 
+// e.g. `dotfiles [home, shell] /home/me/src/dotfiles b:["make"] c:[] up:true cl:false`
 impl fmt::Debug for RemaConfig {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        let repo = self.repo.as_ref().map(|r| r.path().to_str());
+        fn runs(cmds: &[Command]) -> Vec<&str> {
+            cmds.iter().map(|c| c.run.as_str()).collect()
+        }
+        write!(f, "{}", self.name)?;
+        if !self.tags.is_empty() {
+            write!(f, " [{}]", self.tags.join(", "))?;
+        }
+        match &self.repo {
+            Some(r) => write!(f, " {}", r.workdir().unwrap_or_else(|| r.path()).display())?,
+            None => write!(f, " <no repo>")?,
+        }
         write!(
             f,
-            "{:?} b:{:?} c:{:?} up:{} cl:{}",
-            repo, self.build, self.clean, self.autoupdate, self.autoclean
+            " b:{:?} c:{:?} up:{} cl:{}",
+            runs(&self.build),
+            runs(&self.clean),
+            self.autoupdate,
+            self.autoclean
         )
     }
 }
@@ -39,117 +766,2386 @@ impl TryFrom<PathBuf> for RemaConfig {
     type Error = ConfigError;
 
     fn try_from(p: PathBuf) -> Result<Self, Self::Error> {
-        let f = p.join("rema.toml");
-        let mut c: Self = toml::from_str(&fs::read_to_string(f).unwrap()).unwrap();
-        c.repo = Some(Repository::open(p).unwrap());
-        Ok(c)
+        Self::load(p, false)
     }
 }
 
 impl RemaConfig {
+    // ${VAR} anywhere in rema.toml is expanded from the environment
+    pub(crate) fn load(p: PathBuf, strict: bool) -> Result<Self, ConfigError> {
+        Self::load_with(p, strict, &toml::value::Table::new())
+    }
+
+    // load with --set fields over rema.toml and rema.local.toml
+    pub(crate) fn load_with(
+        p: PathBuf,
+        strict: bool,
+        overrides: &toml::value::Table,
+    ) -> Result<Self, ConfigError> {
+        let f = p.join("rema.toml");
+        let text = match fs::read_to_string(&f) {
+            Ok(s) => s,
+            // deleted since base_dir was listed
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {
+                return Err(ConfigError::Missing(p, None));
+            }
+            Err(e) => return Err(e.into()),
+        };
+        let mut v: toml::Value = toml::from_str(&text)?;
+        match fs::read_to_string(p.join("rema.local.toml")) {
+            Ok(s) => merge(&mut v, toml::from_str(&s)?),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+            Err(e) => return Err(e.into()),
+        }
+        if !overrides.is_empty() {
+            merge(&mut v, toml::Value::Table(overrides.clone()));
+        }
+        migrate::check(&v).map_err(|e| ConfigError::Version(f.clone(), e))?;
+        let undefined = RefCell::new(BTreeSet::new());
+        let lookup = |name: &str| {
+            let v = std::env::var(name).ok();
+            if v.is_none() {
+                undefined.borrow_mut().insert(name.to_string());
+            }
+            v
+        };
+        interp::expand_value(&mut v, strict, &lookup)
+            .map_err(|e| ConfigError::Interpolate(f.clone(), e))?;
+        let mut c: Self = v.try_into()?;
+        let undefined = undefined
+            .into_inner()
+            .into_iter()
+            .map(|n| format!("undefined variable {n:?} expanded to empty"));
+        let commands = [&c.build, &c.clean, &c.post_update, &c.pre_pull];
+        let unknown = unknown_keys(&c.unknown)
+            .chain(commands.iter().flat_map(|cmds| unknown_command_keys(cmds)));
+        load_warnings(&f, undefined.chain(unknown));
+        c.name = p
+            .file_name()
+            .map_or_else(String::new, |n| n.to_string_lossy().into_owned());
+        c.repo = Some(Repository::open(&p).map_err(|e| {
+            if e.code() == git2::ErrorCode::NotFound {
+                ConfigError::Missing(p.clone(), c.skip_missing)
+            } else {
+                ConfigError::NotARepo(p.clone(), e)
+            }
+        })?);
+        Ok(c)
+    }
+
+    pub(crate) fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub(crate) fn tags(&self) -> &[String] {
+        &self.tags
+    }
+
+    pub(crate) fn repo(&self) -> &Repository {
+        self.repo.as_ref().unwrap()
+    }
+
     pub(crate) fn path(&self) -> &Path {
-        self.repo.as_ref().unwrap().path()
+        self.repo().path()
+    }
+
+    // the working tree commands run in, bare repos only have the git dir
+    pub(crate) fn workdir(&self) -> &Path {
+        self.repo().workdir().unwrap_or_else(|| self.path())
     }
 
-    // returns wether update needed or not
-    pub(crate) fn pull(&self) -> bool {
-        let output = std::process::Command::new("git")
-            .current_dir(self.path())
-            .arg("pull")
-            .output()
-            .expect("failed to execute git");
+    // print what OutputMode::Group held back, called once the repo is done
+    pub(crate) fn flush_output(&self) {
+        output::flush(&self.output_buf);
+    }
 
-        let check_phrase = "Already up to date.";
-        let check = String::from_utf8(output.stdout[..check_phrase.len()].to_vec()).unwrap();
+    pub(crate) fn token_env(&self) -> Option<&str> {
+        self.token_env.as_deref()
+    }
 
-        if self.autoupdate {
-            self.build();
-            false
-        } else {
-            output.status.success() && check != check_phrase
-        }
+    pub(crate) fn pinned_commit(&self) -> Option<&str> {
+        self.pinned_commit.as_deref()
+    }
+
+    pub(crate) fn expected_branch(&self) -> Option<&str> {
+        self.expected_branch.as_deref()
+    }
+
+    pub(crate) fn head(&self) -> Option<git2::Oid> {
+        self.repo.as_ref()?.head().ok()?.target()
     }
 
-    pub(crate) fn build(&self) {
-        for line in &self.build {
-            self.run_line_as_cmd(line);
+    // start counting repo_timeout, called when rema starts on the repo
+    pub(crate) fn start_clock(&self) {
+        let now = Instant::now();
+        self.started.set(Some(now));
+        self.deadline.set(self.repo_timeout.map(|t| now + t));
+        if self.repo_retries > 0 {
+            let dirty = status::dirty_files(self.repo()).map_or(true, |f| !f.is_empty());
+            self.dirty_at_start.set(dirty);
         }
+    }
 
-        if self.autoclean {
-            self.clean()
+    // built_commit_file, for tools that need to know what was built, e.g. a
+    // deploy script checking what's live. The file is the commit's full hex
+    // id and a newline, replaced in one rename so it's never half written.
+    // Relative paths are from the working tree, where it's best gitignored;
+    // `{repo}` is the repo's name, so the root config can send every repo to
+    // one directory, e.g. "/var/lib/rema/built/{repo}".
+    pub(crate) fn write_built_commit(&self) -> io::Result<()> {
+        let (Some(file), Some(head)) = (&self.built_commit_file, self.head()) else {
+            return Ok(());
+        };
+        let file = file.to_string_lossy().replace("{repo}", &self.name);
+        let path = self.workdir().join(file);
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir)?;
         }
+        let mut part = path.clone().into_os_string();
+        part.push(".part");
+        fs::write(&part, format!("{head}\n"))?;
+        fs::rename(part, path)
+    }
+
+    // pull and build attempts so far this run, see repo_retries
+    pub(crate) fn attempts(&self) -> u32 {
+        self.attempts.get().max(1)
     }
 
-    pub(crate) fn clean(&self) {
-        for line in &self.clean {
-            self.run_line_as_cmd(line);
+    // after a failed attempt, whether repo_retries allows another. The
+    // working tree is put back to HEAD first, undoing what a half-done
+    // checkout or the build did to tracked files, unless it had uncommitted
+    // changes before the first attempt, and cleaned with repo_retry_clean.
+    pub(crate) fn retry_attempt(&self, why: &dyn fmt::Display) -> bool {
+        let attempt = self.attempts();
+        if attempt > self.repo_retries || self.timed_out() {
+            return false;
         }
+        warn!(
+            repo = %self.name,
+            event = "repo_retry",
+            "attempt {} of {} failed, pulling and building again: {}",
+            attempt,
+            self.repo_retries + 1,
+            why
+        );
+        if self.dirty_at_start.get() {
+            info!(repo = %self.name, "uncommitted changes from before the first attempt, not resetting the working tree");
+        } else if let Err(e) = self
+            .repo()
+            .checkout_head(Some(git2::build::CheckoutBuilder::default().force()))
+        {
+            warn!(repo = %self.name, "could not reset the working tree: {}", e);
+        }
+        if self.repo_retry_clean {
+            if let Err(e) = self.clean() {
+                warn!(repo = %self.name, "clean between attempts failed: {}", e);
+            }
+        }
+        self.attempts.set(attempt + 1);
+        true
     }
 
-    fn run_line_as_cmd(&self, line: &str) {
-        let parts = line.split_whitespace().collect::<Vec<_>>();
-        let (cmd, args) = parts.as_slice().split_first().unwrap();
-        println!("exec: {} {:?} in {:?}", cmd, args, self.path());
+    // time since start_clock
+    pub(crate) fn elapsed(&self) -> Duration {
+        self.started.get().map(|s| s.elapsed()).unwrap_or_default()
+    }
 
-        std::process::Command::new(cmd)
-            .current_dir(self.path())
-            .args(args)
-            .spawn()
-            .expect("failed to run command")
-            .wait()
-            .expect("command failed to run");
+    // whether repo_timeout has run out
+    pub(crate) fn timed_out(&self) -> bool {
+        self.deadline.get().is_some_and(|d| Instant::now() >= d)
     }
-}
 
-#[cfg(test)]
-mod tests {
-    use super::*;
+    // returns wether update needed or not, `url` overrides the remote's
+    // url for this pull only; after a prefetch only the merge is left
+    // --local-only pulls nothing, run::pull builds the current checkout
+    pub(crate) fn pull(&self, url: Option<&str>) -> Result<bool, git2::Error> {
+        if self.local_only {
+            info!(repo = %self.name, event = "local_only", "local only, not fetching");
+            return Ok(false);
+        }
+        if self.reattach && !self.reattach()? {
+            self.pull_skipped.set(true);
+            return Ok(false);
+        }
+        if !self.prefetched.take() {
+            self.fetch(url, false)?;
+        }
+        let merged = Instant::now();
+        let res = git::fast_forward(self.repo());
+        self.add_time(Step::Merge, merged.elapsed());
+        res
+    }
 
-    impl PartialEq for RemaConfig {
-        fn eq(&self, other: &Self) -> bool {
-            self.build == other.build
-                && self.clean == other.clean
-                && self.autoupdate == other.autoupdate
-                && self.clean == other.clean
+    // a detached HEAD goes back to expected_branch or the remote's default
+    // branch before pulling; false when that could lose uncommitted changes,
+    // and the pull is skipped
+    fn reattach(&self) -> Result<bool, git2::Error> {
+        let repo = self.repo();
+        if !repo.head_detached()? {
+            return Ok(true);
+        }
+        if !status::dirty_files(repo)?.is_empty() {
+            warn!(
+                repo = %self.name,
+                event = "reattach_skipped",
+                "skip: HEAD is detached and there are uncommitted changes, not reattaching"
+            );
+            return Ok(false);
         }
+        let branch = git::reattach(repo, self.expected_branch.as_deref())?;
+        info!(repo = %self.name, event = "reattached", "HEAD was detached, checked out {}", branch);
+        Ok(true)
     }
 
-    #[test]
-    fn test_rema_config_full() {
-        let config = r#"
-                build = ["cmd1", "cmd2"]
-                clean = ["clean pls"]
-                autoclean = true
-                autoupdate = true
-            "#;
+    // whether the last pull was skipped rather than up to date
+    pub(crate) fn take_pull_skipped(&self) -> bool {
+        self.pull_skipped.take()
+    }
 
-        // check config is parsed correctly
-        let conf: RemaConfig = toml::from_str(&config).unwrap();
-        let expected = RemaConfig {
-            repo: None,
-            build: vec!["cmd1".into(), "cmd2".into()],
-            clean: vec!["clean pls".into()],
-            autoupdate: true,
-            autoclean: true,
+    // pull --prefetch-then-prompt, the pre_pull and fetch half of a pull
+    pub(crate) fn prefetch(&self, url: Option<&str>) -> Result<(), CommandError> {
+        self.pre_pull()?;
+        self.pre_pulled.set(true);
+        if self.local_only {
+            return Ok(());
+        }
+        self.fetch(url, false).map_err(CommandError::Git)?;
+        self.prefetched.set(true);
+        Ok(())
+    }
+
+    // fetch without moving HEAD, `all_tags` fetches every tag as fetch_tags
+    // does but never prunes, update --ref's rev may be a tag only made here
+    fn fetch(&self, url: Option<&str>, all_tags: bool) -> Result<(), git2::Error> {
+        let repo = self.repo();
+        let tags = all_tags || self.fetch_tags || self.since_tag.is_some();
+        let opts = git::FetchOpts {
+            name: &self.name,
+            retries: self.pull_retries,
+            token_env: self.token_env.as_deref(),
+            url,
+            deadline: self.deadline.get(),
+            allowed_url: self.allowed_remote_url.as_ref(),
+            tags,
+            prune_tags: self.prune_tags && !all_tags,
+            refspec: self.refspec.as_deref(),
         };
-        assert_eq!(conf, expected);
+        if let Some(want) = &self.remote_url {
+            git::ensure_remote_url(repo, &self.name, want, !self.no_mutate_remotes)?;
+        }
+        let fetched = Instant::now();
+        if tags {
+            let before = git::tags(repo)?;
+            git::fetch(repo, &opts)?;
+            let updates = TagUpdates::between(&before, &git::tags(repo)?);
+            if !updates.is_empty() {
+                info!(repo = %self.name, event = "tags", "tags {}", updates);
+            }
+            self.tag_updates.replace(updates);
+        } else {
+            git::fetch(repo, &opts)?;
+        }
+        self.add_time(Step::Fetch, fetched.elapsed());
+        Ok(())
     }
 
-    #[test]
-    fn test_invalid_config_no_panic() {
-        let config = r#"
-            [dir.home]
-            path = "~"
-            "#;
+    // update --ref, fetch then check `rev` out detached for the build to run
+    // on; HEAD is left there
+    pub(crate) fn checkout_ref(&self, rev: &str) -> Result<git2::Oid, CommandError> {
+        self.ensure_clean()?;
+        if !self.local_only {
+            self.fetch(None, true).map_err(CommandError::Git)?;
+        }
+        let (_, oid) = git::checkout_detached(self.repo(), rev).map_err(CommandError::Git)?;
+        warn!(
+            repo = %self.name,
+            event = "detached",
+            "HEAD detached at {} ({}), pulls fail until a branch is checked out again or with reattach",
+            rev,
+            oid
+        );
+        Ok(oid)
+    }
 
-        let conf: RemaConfig = toml::from_str(&config).unwrap();
-        let expected = RemaConfig {
-            repo: None,
-            build: vec![],
-            clean: vec![],
-            autoclean: false,
-            autoupdate: false,
-        };
-        assert_eq!(conf, expected);
+    // always kept, --benchmark needs the build times without --trace-timing
+    fn add_time(&self, step: Step, d: Duration) {
+        let mut t = self.timings.get();
+        t.add(step, d);
+        self.timings.set(t);
+    }
+
+    // time spent building since the last take_timings
+    pub(crate) fn build_time(&self) -> Duration {
+        self.timings.get().get(Step::Build)
+    }
+
+    // with --trace-timing, the time spent in each step since the last take
+    pub(crate) fn take_timings(&self) -> Option<Timings> {
+        let t = self.timings.take();
+        self.trace_timing.then_some(t)
+    }
+
+    pub(crate) fn take_tag_updates(&self) -> TagUpdates {
+        self.tag_updates.take()
+    }
+
+    // wait for the resource, if any, and keep it until dropped
+    pub(crate) fn hold_resource(&self) -> resource::Hold {
+        resource::hold(&self.name, self.resource.as_deref())
+    }
+
+    pub(crate) fn since_tag(&self) -> Option<&glob::Pattern> {
+        self.since_tag.as_ref()
+    }
+
+    // whether the skip file is there or skip_if passes, logging it if so
+    pub(crate) fn skip_build(&self) -> bool {
+        let file = self
+            .skip_file
+            .as_deref()
+            .unwrap_or_else(|| Path::new(DEFAULT_SKIP_FILE));
+        if self.workdir().join(file).exists() {
+            info!(
+                repo = %self.name,
+                event = "skip",
+                "skip: not building while {} exists",
+                file.display()
+            );
+            return true;
+        }
+        let Some(spec) = self.specs(Phase::SkipIf, self.skip_if.as_slice()).next() else {
+            return false;
+        };
+        match self.exec(&spec) {
+            Ok(_) => {
+                info!(
+                    repo = %self.name,
+                    event = "skip",
+                    "skip: not building, {:?} passed",
+                    spec.line
+                );
+                true
+            }
+            Err(CommandError::Failed(..)) => false,
+            // a skip_if that can't run doesn't hold the build back
+            Err(e) => {
+                warn!(repo = %self.name, "skip_if: {}", e);
+                false
+            }
+        }
+    }
+
+    // for --hash-cache, a build whose inputs hash the same as the last good
+    // one can be skipped
+    pub(crate) fn input_hash(&self) -> Result<String, String> {
+        let root = self.workdir();
+        let mut paths = if self.hash_inputs.is_empty() {
+            git::tracked_files(self.repo()).map_err(|e| e.to_string())?
+        } else {
+            let mut paths = Vec::new();
+            for pat in &self.hash_inputs {
+                let full = root.join(pat).to_string_lossy().into_owned();
+                let found = glob::glob(&full).map_err(|e| format!("hash_inputs {pat:?}: {e}"))?;
+                paths.extend(
+                    found
+                        .filter_map(Result::ok)
+                        .filter(|p| p.is_file())
+                        .filter_map(|p| p.strip_prefix(root).ok().map(Path::to_path_buf)),
+                );
+            }
+            paths
+        };
+        paths.sort();
+        paths.dedup();
+        git::hash_files(root, &paths)
+            .map(|o| o.to_string())
+            .map_err(|e| e.to_string())
+    }
+
+    // build straight after a successful pull instead of waiting for update
+    pub(crate) fn autoupdate(&self) -> bool {
+        self.autoupdate
+    }
+
+    pub(crate) fn has_matrix(&self) -> bool {
+        !self.matrix.is_empty()
+    }
+
+    pub(crate) fn build_on_first_run(&self) -> bool {
+        self.build_on_first_run
+    }
+
+    // the build run by an autoupdate pull, a failure can clean up after itself
+    // so a partial build doesn't poison the next attempt
+    pub(crate) fn autoupdate_build(&self, since: Option<git2::Oid>) -> Result<(), CommandError> {
+        let res = self.build(since);
+        // autoclean has already run clean
+        let cleaned = self.autoclean && self.clean_on_failure;
+        if res.is_err() && self.autoupdate_clean_on_failure && !cleaned {
+            if let Err(e) = self.clean() {
+                warn!(repo = %self.name, "clean after failed build failed: {}", e);
+            }
+        }
+        res
+    }
+
+    // effective ordered commands run by an update, without running them
+    pub(crate) fn commands(&self) -> impl Iterator<Item = CommandSpec<'_>> {
+        let clean: &[Command] = if self.autoclean { &self.clean } else { &[] };
+        self.specs(Phase::Build, &self.build)
+            .chain(self.specs(Phase::Clean, clean))
+            .chain(self.specs(Phase::PostUpdate, &self.post_update))
+    }
+
+    // the commands run by a pull before fetching
+    pub(crate) fn pre_pull_commands(&self) -> impl Iterator<Item = CommandSpec<'_>> {
+        self.specs(Phase::PrePull, &self.pre_pull)
+    }
+
+    // the resolved settings of this repo, after the base config and command
+    // line have been applied, unset ones as "-"
+    pub(crate) fn settings(&self) -> Vec<(String, String)> {
+        fn opt<T: fmt::Display>(v: Option<T>) -> String {
+            v.map_or_else(|| "-".into(), |v| v.to_string())
+        }
+        let dur = |d: Option<Duration>| opt(d.map(|d| format!("{d:?}")));
+        let mut out = [
+            ("autoupdate", self.autoupdate.to_string()),
+            ("autoclean", self.autoclean.to_string()),
+            ("clean_on_failure", self.clean_on_failure.to_string()),
+            (
+                "autoupdate_clean_on_failure",
+                self.autoupdate_clean_on_failure.to_string(),
+            ),
+            ("pull_retries", self.pull_retries.to_string()),
+            ("repo_retries", self.repo_retries.to_string()),
+            ("repo_retry_clean", self.repo_retry_clean.to_string()),
+            ("timeout", dur(self.timeout)),
+            ("timeout_build", dur(self.timeout_build)),
+            ("timeout_clean", dur(self.timeout_clean)),
+            ("repo_timeout", dur(self.repo_timeout)),
+            ("token_env", opt(self.token_env.as_deref())),
+            ("nice", opt(self.nice)),
+            ("allowed_remote_url", opt(self.allowed_remote_url.as_ref())),
+            ("remote_url", opt(self.remote_url.as_deref())),
+            ("no_mutate_remotes", self.no_mutate_remotes.to_string()),
+            ("isolate_env", self.isolate_env.to_string()),
+            ("pinned_commit", opt(self.pinned_commit.as_deref())),
+            ("expected_branch", opt(self.expected_branch.as_deref())),
+            ("skip_if", opt(self.skip_if.as_ref().map(|c| &c.run))),
+            (
+                "built_commit_file",
+                opt(self.built_commit_file.as_ref().map(|f| f.display())),
+            ),
+            ("verify_signatures", self.verify_signatures.to_string()),
+            ("fail_fast", self.fail_fast.to_string()),
+            ("fetch_tags", self.fetch_tags.to_string()),
+            ("prune_tags", self.prune_tags.to_string()),
+            ("refspec", opt(self.refspec.as_deref())),
+            ("since_tag", opt(self.since_tag.as_ref())),
+            ("resource", opt(self.resource.as_deref())),
+            ("build_on_first_run", self.build_on_first_run.to_string()),
+            ("reattach", self.reattach.to_string()),
+        ]
+        .map(|(k, v)| (k.to_string(), v))
+        .to_vec();
+        out.extend(
+            self.env
+                .iter()
+                .map(|(k, v)| (format!("env.{k}"), v.clone())),
+        );
+        out.extend(
+            self.matrix
+                .iter()
+                .enumerate()
+                .map(|(i, vars)| (format!("matrix.{i}"), variant_name(vars))),
+        );
+        out.extend(
+            self.build_args
+                .iter()
+                .map(|(k, v)| (format!("build_args.{k}"), v.clone())),
+        );
+        out
+    }
+
+    fn specs<'a>(
+        &'a self,
+        phase: Phase,
+        cmds: &'a [Command],
+    ) -> impl Iterator<Item = CommandSpec<'a>> + 'a {
+        cmds.iter().map(move |c| CommandSpec {
+            phase,
+            line: &c.run,
+            if_changed: &c.if_changed,
+            // join keeps an absolute cwd as it is
+            cwd: c
+                .cwd
+                .as_ref()
+                .map_or_else(|| self.workdir().to_path_buf(), |d| self.workdir().join(d)),
+            env: &self.env,
+            stdin: c.stdin.as_ref().unwrap_or(if self.close_stdin {
+                &Stdin::Null
+            } else {
+                &Stdin::Inherit
+            }),
+            run_as_user: c.run_as_user.as_deref(),
+            run_as_group: c.run_as_group.as_deref(),
+        })
+    }
+
+    // build, `since` is HEAD before the pull and gates if_changed commands;
+    // when unknown every command runs. Stops at the first failing command,
+    // autoclean then only runs if the build succeeded or clean_on_failure
+    // is set.
+    pub(crate) fn build(&self, since: Option<git2::Oid>) -> Result<(), CommandError> {
+        if self.verify_signatures {
+            self.check_signature()?;
+        }
+        self.since.set(since);
+        self.pull_env.borrow_mut().clear();
+        let diff = since.and_then(|old| self.changes_since(old));
+        if let Some(diff) = &diff {
+            self.set_pull_env(diff);
+        }
+        let changed = diff.as_deref().map(git::changed_paths);
+        let mut res = if self.matrix.is_empty() {
+            self.run_build(changed.as_deref())
+        } else {
+            self.run_matrix(changed.as_deref())
+        };
+
+        if self.autoclean && (res.is_ok() || self.clean_on_failure) {
+            let cleaned = self.clean();
+            res = res.and(cleaned);
+        }
+        res
+    }
+
+    // the build commands, stopping at the first that fails
+    fn run_build(&self, changed: Option<&[PathBuf]>) -> Result<(), CommandError> {
+        for spec in self.commands().filter(|s| s.phase == Phase::Build) {
+            if let Some(changed) = changed {
+                if !spec.if_changed.is_empty()
+                    && !command::matches_changed(spec.if_changed, changed)
+                {
+                    info!(
+                        repo = %self.name,
+                        event = "skip",
+                        "skip: {} (no changes matching {:?})",
+                        spec.line,
+                        spec.if_changed
+                    );
+                    continue;
+                }
+            }
+            self.run_line_as_cmd(&spec)?;
+        }
+        Ok(())
+    }
+
+    // the build once per matrix entry, each reported on its own; a failing
+    // entry only stops the rest with fail_fast
+    fn run_matrix(&self, changed: Option<&[PathBuf]>) -> Result<(), CommandError> {
+        let mut failed = Vec::new();
+        for vars in &self.matrix {
+            let variant = variant_name(vars);
+            self.matrix_env.replace(vars.clone());
+            let res = self.run_build(changed);
+            events::emit(&Event::VariantEnd {
+                repo: &self.name,
+                variant: &variant,
+                ok: res.is_ok(),
+            });
+            match res {
+                Ok(()) => info!(
+                    repo = %self.name,
+                    event = "variant_built",
+                    "built variant {}",
+                    variant
+                ),
+                Err(e) => {
+                    warn!(
+                        repo = %self.name,
+                        event = "variant_failed",
+                        "variant {} failed: {}",
+                        variant,
+                        e
+                    );
+                    failed.push((variant, e));
+                    if self.fail_fast {
+                        break;
+                    }
+                }
+            }
+        }
+        self.matrix_env.take();
+        if failed.is_empty() {
+            Ok(())
+        } else {
+            Err(CommandError::Variants(failed, self.matrix.len()))
+        }
+    }
+
+    fn check_signature(&self) -> Result<(), CommandError> {
+        let head = self
+            .head()
+            .ok_or_else(|| CommandError::Signature("no HEAD to verify".into()))?;
+        let (sig, data) = match self.repo().extract_signature(&head, None) {
+            Ok(signed) => signed,
+            Err(e) if e.code() == git2::ErrorCode::NotFound => {
+                return Err(CommandError::Signature(format!("{head} is not signed")));
+            }
+            Err(e) => return Err(CommandError::Git(e)),
+        };
+        let signer = signature::verify(sig.as_str().unwrap_or_default(), &data, &self.trusted_keys)
+            .map_err(|e| CommandError::Signature(format!("{head}: {e}")))?;
+        info!(
+            repo = %self.name,
+            event = "signature",
+            "{} signed by {}",
+            head,
+            signer
+        );
+        Ok(())
+    }
+
+    // what every command is told about the run, under env_prefix:
+    // REPO_NAME, REPO_PATH, BRANCH (empty when detached), OLD_SHA and NEW_SHA
+    // (OLD_SHA empty outside a build after a pull) and UPDATE_STATUS, which
+    // is "updated" when the build's pull moved HEAD, "up_to_date" when it
+    // didn't and "none" without one, e.g. for pre_pull or rebuild
+    fn run_env(&self) -> BTreeMap<String, String> {
+        let prefix = self.env_prefix();
+        let sha = |oid: Option<git2::Oid>| oid.map(|o| o.to_string()).unwrap_or_default();
+        let (old, new) = (self.since.get(), self.head());
+        let status = match old {
+            Some(old) if Some(old) != new => "updated",
+            Some(_) => "up_to_date",
+            None => "none",
+        };
+        let branch = self
+            .repo
+            .as_ref()
+            .and_then(|r| r.head().ok())
+            .filter(git2::Reference::is_branch)
+            .and_then(|h| h.shorthand().map(String::from))
+            .unwrap_or_default();
+        [
+            ("REPO_NAME", self.name.clone()),
+            ("REPO_PATH", self.workdir().display().to_string()),
+            ("BRANCH", branch),
+            ("UPDATE_STATUS", status.into()),
+            ("OLD_SHA", sha(old)),
+            ("NEW_SHA", sha(new)),
+        ]
+        .iter()
+        .map(|(k, v)| (format!("{prefix}{k}"), v.clone()))
+        .collect()
+    }
+
+    fn env_prefix(&self) -> &str {
+        self.env_prefix.as_deref().unwrap_or(DEFAULT_ENV_PREFIX)
+    }
+
+    // newline separated so scripts can clean up after moved files, e.g.
+    // stale symlinks; a rename is "old<TAB>new"
+    fn set_pull_env(&self, changes: &[FileChange]) {
+        let mut renamed = String::new();
+        let mut deleted = String::new();
+        for c in changes {
+            match (c.status, &c.old_path) {
+                ('R', Some(from)) => {
+                    let _ = writeln!(renamed, "{}\t{}", from.display(), c.path.display());
+                }
+                ('D', _) => {
+                    let _ = writeln!(deleted, "{}", c.path.display());
+                }
+                _ => {}
+            }
+        }
+        let prefix = self.env_prefix();
+        let mut env = self.pull_env.borrow_mut();
+        env.insert(format!("{prefix}RENAMED"), renamed);
+        env.insert(format!("{prefix}DELETED"), deleted);
+    }
+
+    fn changes_since(&self, old: git2::Oid) -> Option<Vec<FileChange>> {
+        let repo = self.repo.as_ref()?;
+        let new = self.head()?;
+        match git::file_changes(repo, old, new) {
+            Ok(c) => Some(c),
+            Err(e) => {
+                warn!(repo = %self.name, "could not diff {}..{}: {}", old, new, e);
+                None
+            }
+        }
+    }
+
+    // run post_update hooks, with apply_once they are skipped if HEAD is the
+    // commit they last ran for; returns the commit they ran for
+    pub(crate) fn post_update(
+        &self,
+        last_applied: Option<git2::Oid>,
+        apply_once: bool,
+    ) -> Result<Option<git2::Oid>, CommandError> {
+        if self.post_update.is_empty() {
+            return Ok(None);
+        }
+        let head = self.head();
+        if apply_once && head.is_some() && head == last_applied {
+            info!(
+                repo = %self.name,
+                event = "skip",
+                "skip: post_update already applied for {}",
+                head.unwrap_or_else(git2::Oid::zero)
+            );
+            return Ok(None);
+        }
+        for spec in self.specs(Phase::PostUpdate, &self.post_update) {
+            self.run_line_as_cmd(&spec)?;
+        }
+        Ok(head)
+    }
+
+    pub(crate) fn pre_pull(&self) -> Result<(), CommandError> {
+        if self.pre_pulled.take() {
+            return Ok(());
+        }
+        for spec in self.pre_pull_commands() {
+            self.run_line_as_cmd(&spec)?;
+        }
+        Ok(())
+    }
+
+    // build `rev` checked out detached, then go back to where HEAD was;
+    // refuses a dirty tree so nothing uncommitted is lost. Returns the commit
+    // that was built.
+    pub(crate) fn build_at(&self, rev: &str) -> Result<git2::Oid, CommandError> {
+        self.ensure_clean()?;
+        let repo = self.repo();
+        let (prev, oid) = git::checkout_detached(repo, rev).map_err(CommandError::Git)?;
+        let res = self.build(None);
+        let restored = git::restore_head(repo, &prev).map_err(CommandError::Git);
+        res.and(restored).map(|()| oid)
+    }
+
+    // update --fail-on-dirty, fails the repo rather than building over
+    // uncommitted changes
+    pub(crate) fn ensure_clean(&self) -> Result<(), CommandError> {
+        let files = status::dirty_files(self.repo()).map_err(CommandError::Git)?;
+        if files.is_empty() {
+            Ok(())
+        } else {
+            Err(CommandError::Dirty(files))
+        }
+    }
+
+    pub(crate) fn clean(&self) -> Result<(), CommandError> {
+        for spec in self.specs(Phase::Clean, &self.clean) {
+            self.run_line_as_cmd(&spec)?;
+        }
+
+        // catch clean commands that silently did nothing
+        let left = self
+            .clean_expect_absent
+            .iter()
+            .filter(|p| still_present(&self.workdir().join(p)))
+            .cloned()
+            .collect::<Vec<_>>();
+        if !left.is_empty() {
+            return Err(CommandError::NotCleaned(left));
+        }
+        Ok(())
+    }
+
+    fn run_line_as_cmd(&self, spec: &CommandSpec<'_>) -> Result<(), CommandError> {
+        events::emit(&Event::CommandStart {
+            repo: &self.name,
+            phase: spec.phase,
+            command: spec.line,
+        });
+        let start = Instant::now();
+        let res = self.exec(spec);
+        if let Some(step) = Step::of(spec.phase) {
+            self.add_time(step, start.elapsed());
+        }
+        let exit_code = match &res {
+            Ok(status) | Err(CommandError::Failed(_, status)) => status.code(),
+            Err(_) => None,
+        };
+        events::emit(&Event::CommandEnd {
+            repo: &self.name,
+            phase: spec.phase,
+            command: spec.line,
+            exit_code,
+            duration_ms: start.elapsed().as_millis(),
+        });
+        res.map(|_| ())
+    }
+
+    // whichever of the command's timeout and the repo's remaining time runs
+    // out first, build and clean commands have their own timeouts
+    fn command_timeout(&self, phase: Phase) -> Result<Option<Duration>, CommandError> {
+        let remaining = self
+            .deadline
+            .get()
+            .map(|d| d.saturating_duration_since(Instant::now()));
+        if remaining == Some(Duration::from_secs(0)) {
+            return Err(CommandError::RepoTimedOut(
+                self.repo_timeout.unwrap_or_default(),
+            ));
+        }
+        let timeout = match phase {
+            Phase::Build => self.timeout_build.or(self.timeout),
+            Phase::Clean => self.timeout_clean.or(self.timeout),
+            Phase::PrePull | Phase::SkipIf | Phase::PostUpdate => self.timeout,
+        };
+        Ok(match (timeout, remaining) {
+            (Some(t), Some(r)) => Some(t.min(r)),
+            (t, r) => t.or(r),
+        })
+    }
+
+    // the repo's env over rema's, or just ISOLATED_BASELINE of it, and the
+    // matrix entry over both
+    fn set_env(&self, command: &mut std::process::Command, spec: &CommandSpec<'_>) {
+        if self.isolate_env {
+            command.env_clear();
+            for var in &ISOLATED_BASELINE {
+                if let Some(v) = std::env::var_os(var) {
+                    command.env(var, v);
+                }
+            }
+        }
+        command
+            .envs(self.run_env())
+            .envs(self.pull_env.borrow().iter())
+            .envs(spec.env)
+            .envs(self.matrix_env.borrow().iter());
+    }
+
+    // what set_env leaves `spec` with, each variable with where it came from
+    pub(crate) fn resolved_env(
+        &self,
+        spec: &CommandSpec<'_>,
+    ) -> BTreeMap<String, (String, EnvSource)> {
+        let mut env = BTreeMap::new();
+        let inherited = std::env::vars_os()
+            .map(|(k, v)| {
+                (
+                    k.to_string_lossy().into_owned(),
+                    v.to_string_lossy().into_owned(),
+                )
+            })
+            .filter(|(k, _)| !self.isolate_env || ISOLATED_BASELINE.contains(&k.as_str()));
+        let layers = [
+            (EnvSource::Run, self.run_env()),
+            (EnvSource::Pull, self.pull_env.borrow().clone()),
+            (EnvSource::Repo, spec.env.clone()),
+            (EnvSource::Matrix, self.matrix_env.borrow().clone()),
+        ];
+        for (k, v) in inherited {
+            env.insert(k, (v, EnvSource::Inherited));
+        }
+        for (source, vars) in layers {
+            for (k, v) in vars {
+                env.insert(k, (v, source));
+            }
+        }
+        env
+    }
+
+    fn exec(&self, spec: &CommandSpec<'_>) -> Result<ExitStatus, CommandError> {
+        let timeout = self.command_timeout(spec.phase)?;
+        let line = interp::fill(spec.line, &|k| self.build_args.get(k).cloned())
+            .map_err(CommandError::BuildArg)?;
+        let mut parts = line.split_whitespace().collect::<Vec<_>>();
+        if spec.phase == Phase::Build && !parts.is_empty() {
+            parts.extend(self.extra_build_args.iter().map(String::as_str));
+        }
+        let (cmd, args) = parts.as_slice().split_first().ok_or(CommandError::Empty)?;
+        info!(
+            repo = %self.name,
+            event = "exec",
+            "exec: {} {:?} in {} ({:?})",
+            cmd,
+            args,
+            spec.cwd.display(),
+            spec.phase
+        );
+
+        if !spec.cwd.is_dir() {
+            return Err(CommandError::NoCwd(spec.cwd.clone()));
+        }
+        let run_as =
+            user::resolve(spec.run_as_user, spec.run_as_group).map_err(CommandError::RunAs)?;
+        let mut command = std::process::Command::new(cmd);
+        user::apply(&mut command, run_as);
+        self.set_env(&mut command, spec);
+        command.current_dir(&spec.cwd).args(args);
+        // keep stdout for the event stream or --print0
+        if self.output != OutputMode::Stream || self.output_cap.is_active() {
+            command.stdout(Stdio::piped()).stderr(Stdio::piped());
+        } else if output::stdout_reserved() {
+            command.stdout(std::io::stderr());
+        }
+        if let Some(n) = self.nice {
+            set_nice(&mut command, n);
+        }
+        command.stdin(match spec.stdin {
+            Stdin::Inherit => Stdio::inherit(),
+            Stdin::Null => Stdio::null(),
+            Stdin::String(_) => Stdio::piped(),
+        });
+        let mut child = command
+            .spawn()
+            .map_err(|e| CommandError::Spawn(spec.line.into(), e))?;
+        if let (Stdin::String(input), Some(mut pipe)) = (spec.stdin, child.stdin.take()) {
+            // from a thread so a command that doesn't read it can't block us,
+            // the pipe closes when it's dropped
+            let input = input.clone();
+            thread::spawn(move || {
+                let _ = pipe.write_all(input.as_bytes());
+            });
+        }
+        let mut forwarders = Vec::new();
+        if let Some(out) = child.stdout.take() {
+            forwarders.push(output::forward(
+                out,
+                Pipe::Out,
+                self.output,
+                &self.name,
+                spec.line,
+                &self.output_buf,
+                &self.output_cap,
+            ));
+        }
+        if let Some(err) = child.stderr.take() {
+            forwarders.push(output::forward(
+                err,
+                Pipe::Err,
+                self.output,
+                &self.name,
+                spec.line,
+                &self.output_buf,
+                &self.output_cap,
+            ));
+        }
+        let waited = wait(child, timeout);
+        for f in forwarders {
+            let _ = f.join();
+        }
+        let status = waited
+            .map_err(|e| CommandError::Spawn(spec.line.into(), e))?
+            .ok_or_else(|| {
+                if self.timed_out() {
+                    CommandError::RepoTimedOut(self.repo_timeout.unwrap_or_default())
+                } else {
+                    CommandError::TimedOut(spec.line.into(), timeout.unwrap_or_default())
+                }
+            })?;
+        if status.success() {
+            Ok(status)
+        } else {
+            Err(CommandError::Failed(spec.line.into(), status))
+        }
+    }
+}
+
+#[cfg(unix)]
+fn set_nice(command: &mut std::process::Command, n: i32) {
+    use std::os::unix::process::CommandExt;
+    // SAFETY: setpriority is async-signal-safe, so fine between fork and exec
+    unsafe {
+        command.pre_exec(move || {
+            libc::setpriority(libc::PRIO_PROCESS, 0, n);
+            Ok(())
+        });
+    }
+}
+
+#[cfg(not(unix))]
+fn set_nice(_: &mut std::process::Command, _: i32) {
+    warn!("nice is only supported on unix, ignoring it");
+}
+
+// an empty directory counts as cleaned
+fn still_present(p: &Path) -> bool {
+    match fs::read_dir(p) {
+        Ok(mut entries) => entries.next().is_some(),
+        Err(_) => p.exists(),
+    }
+}
+
+// wait for the child, killing it once `timeout` has passed; None if it was
+// killed
+fn wait(mut child: Child, timeout: Option<Duration>) -> std::io::Result<Option<ExitStatus>> {
+    let Some(timeout) = timeout else {
+        return child.wait().map(Some);
+    };
+    let start = Instant::now();
+    loop {
+        if let Some(status) = child.try_wait()? {
+            return Ok(Some(status));
+        }
+        if start.elapsed() >= timeout {
+            child.kill()?;
+            child.wait()?;
+            return Ok(None);
+        }
+        thread::sleep(Duration::from_millis(20));
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use crate::warnings;
+
+    impl PartialEq for RemaConfig {
+        fn eq(&self, other: &Self) -> bool {
+            self.build == other.build
+                && self.clean == other.clean
+                && self.autoupdate == other.autoupdate
+                && self.autoclean == other.autoclean
+        }
+    }
+
+    // a repo with rema.toml committed on its initial commit
+    pub(crate) fn fixture(config: &str) -> (tempfile::TempDir, RemaConfig) {
+        let dir = tempfile::tempdir().unwrap();
+        let repo = Repository::init(dir.path()).unwrap();
+        fs::write(dir.path().join("rema.toml"), config).unwrap();
+        commit_all(&repo, "init");
+        let conf = RemaConfig::try_from(dir.path().to_path_buf()).unwrap();
+        (dir, conf)
+    }
+
+    pub(crate) fn commit_all(repo: &Repository, msg: &str) -> git2::Oid {
+        let mut index = repo.index().unwrap();
+        index
+            .add_all(["*"].iter(), git2::IndexAddOption::DEFAULT, None)
+            .unwrap();
+        index.write().unwrap();
+        let tree = repo.find_tree(index.write_tree().unwrap()).unwrap();
+        let sig = git2::Signature::now("rema", "rema@example.com").unwrap();
+        let parent = repo.head().ok().and_then(|h| h.peel_to_commit().ok());
+        let parents = parent.iter().collect::<Vec<_>>();
+        repo.commit(Some("HEAD"), &sig, &sig, msg, &tree, &parents)
+            .unwrap()
+    }
+
+    // a base dir with a clone of a fixture("") upstream as "app", for tests
+    // that pull
+    pub(crate) struct Cloned {
+        pub(crate) base: tempfile::TempDir,
+        pub(crate) up: tempfile::TempDir,
+        pub(crate) upstream: RemaConfig,
+        pub(crate) dir: PathBuf,
+    }
+
+    impl Cloned {
+        pub(crate) fn load(&self) -> RemaConfig {
+            RemaConfig::load(self.dir.clone(), false).unwrap()
+        }
+
+        // a root config for the base dir, `extra` added to it
+        pub(crate) fn config(&self, extra: &str) -> Config {
+            base_config(self.base.path(), extra)
+        }
+    }
+
+    // the clone's rema.toml is `config`, left uncommitted
+    pub(crate) fn cloned_fixture(config: &str) -> Cloned {
+        let base = tempfile::tempdir().unwrap();
+        let (up, upstream, dir) = clone_into(base.path(), "app", config);
+        Cloned {
+            base,
+            up,
+            upstream,
+            dir,
+        }
+    }
+
+    // a clone of a new fixture("") at base/name, see cloned_fixture
+    pub(crate) fn clone_into(
+        base: &Path,
+        name: &str,
+        config: &str,
+    ) -> (tempfile::TempDir, RemaConfig, PathBuf) {
+        let (up, upstream) = fixture("");
+        let dir = base.join(name);
+        Repository::clone(up.path().to_str().unwrap(), &dir).unwrap();
+        fs::write(dir.join("rema.toml"), config).unwrap();
+        (up, upstream, dir)
+    }
+
+    // a config.toml finding repos in `base`, `extra` added to it
+    pub(crate) fn base_config(base: &Path, extra: &str) -> Config {
+        let file = base.join("config.toml");
+        fs::write(&file, format!("base_dir = {base:?}\n{extra}")).unwrap();
+        Config::load(&file, None).unwrap()
+    }
+
+    // a remote at `url` that the current branch tracks
+    pub(crate) fn track(conf: &RemaConfig, url: &str) {
+        let repo = conf.repo();
+        repo.remote("origin", url).unwrap();
+        let branch = repo.head().unwrap().shorthand().unwrap().to_string();
+        let mut cfg = repo.config().unwrap();
+        cfg.set_str(&format!("branch.{branch}.remote"), "origin")
+            .unwrap();
+        cfg.set_str(&format!("branch.{branch}.merge"), "refs/heads/main")
+            .unwrap();
+    }
+
+    #[test]
+    fn test_rema_config_full() {
+        let config = r#"
+                build = ["cmd1", "cmd2"]
+                clean = ["clean pls"]
+                autoclean = true
+                autoupdate = true
+            "#;
+
+        // check config is parsed correctly
+        let conf: RemaConfig = toml::from_str(config).unwrap();
+        let expected = RemaConfig {
+            repo: None,
+            build: vec!["cmd1".into(), "cmd2".into()],
+            clean: vec!["clean pls".into()],
+            autoupdate: true,
+            autoclean: true,
+            ..RemaConfig::default()
+        };
+        assert_eq!(conf, expected);
+    }
+
+    #[test]
+    fn test_invalid_config_no_panic() {
+        let config = r#"
+            [dir.home]
+            path = "~"
+            "#;
+
+        let conf: RemaConfig = toml::from_str(config).unwrap();
+        let expected = RemaConfig {
+            repo: None,
+            build: vec![],
+            clean: vec![],
+            autoclean: false,
+            autoupdate: false,
+            ..RemaConfig::default()
+        };
+        assert_eq!(conf, expected);
+    }
+
+    #[test]
+    fn test_commands_order() {
+        let (_dir, conf) = fixture(
+            r#"
+                build = ["make", "make install"]
+                clean = ["make clean"]
+                autoclean = true
+                post_update = ["systemctl restart app"]
+                env = { CC = "clang" }
+            "#,
+        );
+        let cmds = conf.commands().collect::<Vec<_>>();
+        let lines = cmds.iter().map(|c| (c.phase, c.line)).collect::<Vec<_>>();
+        assert_eq!(
+            lines,
+            vec![
+                (Phase::Build, "make"),
+                (Phase::Build, "make install"),
+                (Phase::Clean, "make clean"),
+                (Phase::PostUpdate, "systemctl restart app"),
+            ]
+        );
+        assert!(cmds.iter().all(|c| c.env["CC"] == "clang"));
+        assert!(cmds.iter().all(|c| c.cwd == conf.workdir()));
+    }
+
+    #[test]
+    fn test_post_update_apply_once() {
+        let (_dir, conf) = fixture(r#"post_update = ["touch restarted"]"#);
+        let marker = conf.workdir().join("restarted");
+
+        // first update runs the hook and records the commit
+        let applied = conf.post_update(None, true).unwrap();
+        assert_eq!(applied, conf.head());
+        assert!(marker.exists());
+        fs::remove_file(&marker).unwrap();
+
+        // nothing new was pulled, so the second update must not restart
+        assert_eq!(conf.post_update(applied, true).unwrap(), None);
+        assert!(!marker.exists());
+
+        // without the guard it runs again
+        assert_eq!(conf.post_update(applied, false).unwrap(), applied);
+        assert!(marker.exists());
+    }
+
+    #[test]
+    fn test_autoupdate_clean_on_failure() {
+        let (_dir, conf) = fixture(
+            r#"
+                build = ["false"]
+                clean = ["touch cleaned"]
+                autoupdate_clean_on_failure = true
+            "#,
+        );
+        assert!(conf.build(None).is_err());
+        assert!(!conf.workdir().join("cleaned").exists());
+
+        assert!(conf.autoupdate_build(None).is_err());
+        assert!(conf.workdir().join("cleaned").exists());
+    }
+
+    #[test]
+    fn test_if_changed() {
+        let (dir, conf) =
+            fixture(r#"build = [{ run = "touch docs", if_changed = ["*.md"] }, "touch always"]"#);
+        let built = |f: &str| dir.path().join(f).exists();
+        let old = conf.head().unwrap();
+        fs::write(dir.path().join("main.rs"), "").unwrap();
+        commit_all(conf.repo(), "code");
+        conf.build(Some(old)).unwrap();
+        assert!(built("always"));
+        assert!(!built("docs"));
+
+        let old = conf.head().unwrap();
+        fs::write(dir.path().join("README.md"), "").unwrap();
+        commit_all(conf.repo(), "docs");
+        conf.build(Some(old)).unwrap();
+        assert!(built("docs"));
+    }
+
+    #[test]
+    fn test_debug_readable() {
+        let mut conf: RemaConfig = toml::from_str(
+            r#"
+                tags = ["home", "shell"]
+                build = ["make", { run = "make install", if_changed = ["Makefile"] }]
+                autoupdate = true
+            "#,
+        )
+        .unwrap();
+        conf.name = "dotfiles".into();
+        assert_eq!(
+            format!("{conf:?}"),
+            r#"dotfiles [home, shell] <no repo> b:["make", "make install"] c:[] up:true cl:false"#
+        );
+
+        let (dir, conf) = fixture("");
+        let name = dir.path().file_name().unwrap().to_string_lossy();
+        let workdir = dir.path().canonicalize().unwrap();
+        assert_eq!(
+            format!("{conf:?}"),
+            format!(
+                "{} {}/ b:[] c:[] up:false cl:false",
+                name,
+                workdir.display()
+            )
+        );
+    }
+
+    #[test]
+    fn test_command_timeout() {
+        let (_dir, conf) = fixture(
+            r#"
+                build = ["sleep 5"]
+                timeout = "1s"
+            "#,
+        );
+        let start = Instant::now();
+        let err = conf.build(None).unwrap_err();
+        assert!(matches!(err, CommandError::TimedOut(..)), "{}", err);
+        assert!(start.elapsed() < Duration::from_secs(4));
+    }
+
+    #[test]
+    fn test_timeout_build() {
+        let (_dir, conf) = fixture(
+            r#"
+                build = ["sleep 5"]
+                clean = ["true"]
+                autoclean = true
+                timeout_build = "1s"
+                timeout_clean = "10s"
+            "#,
+        );
+        let start = Instant::now();
+        match conf.build(None).unwrap_err() {
+            CommandError::TimedOut(cmd, t) => {
+                assert_eq!((cmd.as_str(), t), ("sleep 5", Duration::from_secs(1)));
+            }
+            e => panic!("expected a timeout, got {}", e),
+        }
+        assert!(start.elapsed() < Duration::from_secs(4));
+    }
+
+    #[test]
+    fn test_timeout_clean() {
+        let (_dir, conf) = fixture(
+            r#"
+                build = ["true"]
+                clean = ["sleep 5"]
+                autoclean = true
+                timeout = "10s"
+                timeout_clean = "1s"
+            "#,
+        );
+        let start = Instant::now();
+        match conf.build(None).unwrap_err() {
+            CommandError::TimedOut(cmd, t) => {
+                assert_eq!((cmd.as_str(), t), ("sleep 5", Duration::from_secs(1)));
+            }
+            e => panic!("expected a timeout, got {}", e),
+        }
+        assert!(start.elapsed() < Duration::from_secs(4));
+    }
+
+    #[test]
+    fn test_autoclean_after_success() {
+        let (_dir, conf) = fixture(
+            r#"
+                build = ["true"]
+                clean = ["touch cleaned"]
+                autoclean = true
+            "#,
+        );
+        assert!(conf.build(None).is_ok());
+        assert!(conf.workdir().join("cleaned").exists());
+    }
+
+    #[test]
+    fn test_autoclean_skipped_after_failure() {
+        let (_dir, conf) = fixture(
+            r#"
+                build = ["false"]
+                clean = ["touch cleaned"]
+                autoclean = true
+            "#,
+        );
+        assert!(conf.build(None).is_err());
+        assert!(!conf.workdir().join("cleaned").exists());
+    }
+
+    #[test]
+    fn test_clean_on_failure() {
+        let (_dir, conf) = fixture(
+            r#"
+                build = ["false"]
+                clean = ["touch cleaned"]
+                autoclean = true
+                clean_on_failure = true
+            "#,
+        );
+        assert!(conf.build(None).is_err());
+        assert!(conf.workdir().join("cleaned").exists());
+    }
+
+    #[test]
+    fn test_repo_timeout() {
+        let (_dir, conf) = fixture(
+            r#"
+                build = ["sleep 1", "sleep 1", "sleep 1", "touch done"]
+                repo_timeout = "2s"
+            "#,
+        );
+        let start = Instant::now();
+        conf.start_clock();
+        let err = conf.build(None).unwrap_err();
+        assert!(matches!(err, CommandError::RepoTimedOut(_)), "{}", err);
+        assert!(conf.timed_out());
+        // the remaining steps never ran
+        assert!(!conf.workdir().join("done").exists());
+        assert!(start.elapsed() < Duration::from_secs(3));
+    }
+
+    #[test]
+    fn test_commands_run_in_workdir() {
+        let (dir, conf) = fixture(r#"build = ["touch built"]"#);
+        conf.build(None).unwrap();
+        assert!(dir.path().join("built").exists());
+        assert!(!conf.path().join("built").exists());
+    }
+
+    #[test]
+    fn test_command_cwd() {
+        let (dir, conf) = fixture(r#"build = [{ run = "touch built", cwd = "web" }]"#);
+        let err = conf.build(None).unwrap_err();
+        assert!(matches!(err, CommandError::NoCwd(_)), "{}", err);
+
+        fs::create_dir(dir.path().join("web")).unwrap();
+        conf.build(None).unwrap();
+        assert!(dir.path().join("web/built").exists());
+        assert!(!dir.path().join("built").exists());
+    }
+
+    #[test]
+    fn test_build_args() {
+        let (dir, mut conf) = fixture(
+            r#"
+                build = ["touch {flag}", "touch {out}"]
+                build_args = { out = "debug" }
+            "#,
+        );
+        let err = conf.build(None).unwrap_err();
+        assert!(matches!(err, CommandError::BuildArg(_)), "{}", err);
+
+        conf.build_args.insert("out".into(), "release".into());
+        conf.build_args.insert("flag".into(), "stripped".into());
+        conf.build(None).unwrap();
+        assert!(dir.path().join("release").exists());
+        assert!(dir.path().join("stripped").exists());
+        assert!(!dir.path().join("debug").exists());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_run_as_user() {
+        use std::os::unix::fs::PermissionsExt;
+
+        if unsafe { libc::geteuid() } != 0 {
+            return;
+        }
+        let (dir, conf) = fixture(r#"build = [{ run = "sh id.sh", run_as_user = "nobody" }]"#);
+        fs::write(dir.path().join("id.sh"), "id -u > uid\n").unwrap();
+        // so nobody can write the result
+        fs::set_permissions(dir.path(), fs::Permissions::from_mode(0o777)).unwrap();
+        conf.build(None).unwrap();
+        let uid = fs::read_to_string(dir.path().join("uid")).unwrap();
+        assert_ne!(uid.trim(), "0");
+
+        let (_dir, conf) =
+            fixture(r#"build = [{ run = "true", run_as_user = "no-such-user-rema" }]"#);
+        let err = conf.build(None).unwrap_err();
+        assert!(matches!(err, CommandError::RunAs(_)), "{}", err);
+    }
+
+    #[test]
+    fn test_extra_build_args() {
+        let (dir, mut conf) = fixture(
+            r#"
+                build = ["sh args.sh built"]
+                post_update = ["sh args.sh hooked"]
+            "#,
+        );
+        fs::write(dir.path().join("args.sh"), "echo \"$@\" > \"$1\"\n").unwrap();
+        conf.extra_build_args = vec!["--release".into(), "--locked".into()];
+        conf.build(None).unwrap();
+        conf.post_update(None, false).unwrap();
+        let built = fs::read_to_string(dir.path().join("built")).unwrap();
+        assert_eq!(built.trim(), "built --release --locked");
+        // only build commands get them
+        let hooked = fs::read_to_string(dir.path().join("hooked")).unwrap();
+        assert_eq!(hooked.trim(), "hooked");
+    }
+
+    #[test]
+    fn test_trace_timing() {
+        let (_dir, mut conf) = fixture(
+            r#"
+                pre_pull = ["true"]
+                build = ["sleep 0.02"]
+                clean = ["true"]
+                autoclean = true
+                post_update = ["true"]
+            "#,
+        );
+        conf.trace_timing = true;
+        conf.start_clock();
+        conf.pre_pull().unwrap();
+        conf.build(None).unwrap();
+        conf.post_update(None, false).unwrap();
+        let mut summary = crate::report::Summary::default();
+        summary.record(&conf, crate::report::Outcome::Updated, None);
+
+        let phases = summary.json()["repos"][0]["phases_ms"].clone();
+        let names = [
+            "pre_pull",
+            "fetch",
+            "merge",
+            "build",
+            "clean",
+            "post_update",
+        ];
+        for name in names {
+            assert!(phases[name].as_u64().is_some(), "{} in {}", name, phases);
+        }
+        assert!(phases["build"].as_u64().unwrap() >= 20);
+        let text = summary.render(crate::report::GroupBy::None);
+        let timing = text.split_once("timing:\n").unwrap().1;
+        assert!(timing.starts_with(&format!("  {}\n    pre_pull ", conf.name())));
+        // taken with the summary, the next run starts from zero
+        assert_eq!(conf.take_timings(), Some(Timings::default()));
+    }
+
+    #[test]
+    fn test_pull_keeps_local_edits() {
+        let cloned = cloned_fixture("");
+        let (up, upstream, dir) = (&cloned.up, &cloned.upstream, &cloned.dir);
+        let conf = cloned.load();
+        fs::write(dir.join("rema.toml"), "# local\n").unwrap();
+        fs::write(up.path().join("a"), "").unwrap();
+        commit_all(upstream.repo(), "a");
+        assert!(conf.pull(None).unwrap());
+        assert!(dir.join("a").exists());
+        assert_eq!(
+            fs::read_to_string(dir.join("rema.toml")).unwrap(),
+            "# local\n"
+        );
+
+        // upstream changing the edited file refuses the pull
+        fs::write(up.path().join("rema.toml"), "build = []\n").unwrap();
+        commit_all(upstream.repo(), "b");
+        let head = conf.head();
+        assert!(conf.pull(None).is_err());
+        assert_eq!(conf.head(), head);
+        assert_eq!(
+            fs::read_to_string(dir.join("rema.toml")).unwrap(),
+            "# local\n"
+        );
+    }
+
+    #[test]
+    fn test_refspec() {
+        let cloned = cloned_fixture("");
+        let (up, upstream, dir) = (&cloned.up, &cloned.upstream, &cloned.dir);
+        let branch = upstream
+            .repo()
+            .head()
+            .unwrap()
+            .shorthand()
+            .unwrap()
+            .to_string();
+        let load = |spec: &str| {
+            fs::write(dir.join("rema.toml"), format!("refspec = {spec:?}")).unwrap();
+            RemaConfig::load(dir.clone(), false).unwrap()
+        };
+
+        let err = load("refs/heads/*:refs/remotes/origin/**")
+            .pull(None)
+            .unwrap_err();
+        assert!(err.message().starts_with("refspec rejected"), "{}", err);
+        assert_eq!(git::classify(&err), git::Retry::Permanent);
+
+        fs::write(up.path().join("new"), "").unwrap();
+        commit_all(upstream.repo(), "new");
+        let conf = load(&format!(
+            "+refs/heads/{branch}:refs/remotes/origin/{branch}"
+        ));
+        assert!(conf.pull(None).unwrap());
+        assert!(dir.join("new").exists());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_killed_by_signal() {
+        let (dir, conf) = fixture(r#"build = ["sh die.sh"]"#);
+        fs::write(dir.path().join("die.sh"), "kill -9 $$\n").unwrap();
+        let err = conf.build(None).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            r#""sh die.sh" killed by signal 9 (SIGKILL)"#
+        );
+
+        let (dir, conf) = fixture(r#"build = ["sh die.sh"]"#);
+        fs::write(dir.path().join("die.sh"), "exit 3\n").unwrap();
+        let err = conf.build(None).unwrap_err();
+        assert_eq!(err.to_string(), r#""sh die.sh" exited with 3"#);
+    }
+
+    #[test]
+    fn test_matrix() {
+        let (dir, mut conf) = fixture(
+            r#"
+                build = ["sh variant.sh"]
+                matrix = [{ MODE = "bad" }, { MODE = "good", OPT = "3" }]
+            "#,
+        );
+        fs::write(
+            dir.path().join("variant.sh"),
+            "echo \"$MODE$OPT\" >> variants\n[ \"$MODE\" != bad ]\n",
+        )
+        .unwrap();
+        let variants = dir.path().join("variants");
+
+        // a failing variant doesn't stop the next
+        let err = conf.build(None).unwrap_err();
+        assert_eq!(fs::read_to_string(&variants).unwrap(), "bad\ngood3\n");
+        let CommandError::Variants(failed, 2) = &err else {
+            panic!("{}", err);
+        };
+        assert_eq!(failed.len(), 1);
+        assert_eq!(failed[0].0, "MODE=bad");
+        assert!(err.to_string().starts_with("1 of 2 matrix variants failed"));
+
+        fs::remove_file(&variants).unwrap();
+        conf.fail_fast = true;
+        assert!(conf.build(None).is_err());
+        assert_eq!(fs::read_to_string(&variants).unwrap(), "bad\n");
+        assert!(conf.matrix_env.borrow().is_empty());
+    }
+
+    #[test]
+    fn test_verify_signatures() {
+        let (dir, mut conf) = fixture(r#"build = ["touch built"]"#);
+        let head = commit_all(conf.repo(), "init");
+        conf.verify_signatures = true;
+        let err = conf.build(None).unwrap_err();
+        assert!(matches!(err, CommandError::Signature(_)), "{}", err);
+        assert!(!dir.path().join("built").exists());
+
+        let keys = tempfile::tempdir().unwrap();
+        let key = keys.path().join("key");
+        let made = std::process::Command::new("ssh-keygen")
+            .args(["-q", "-t", "ed25519", "-N", "", "-f"])
+            .arg(&key)
+            .status();
+        if !made.is_ok_and(|s| s.success()) {
+            return;
+        }
+        // the same commit again, signed
+        {
+            let repo = conf.repo();
+            let commit = repo.find_commit(head).unwrap();
+            let buf = repo
+                .commit_create_buffer(
+                    &commit.author(),
+                    &commit.committer(),
+                    "signed",
+                    &commit.tree().unwrap(),
+                    &[],
+                )
+                .unwrap();
+            let mut sign = std::process::Command::new("ssh-keygen")
+                .args(["-Y", "sign", "-n", "git", "-f"])
+                .arg(&key)
+                .stdin(Stdio::piped())
+                .stdout(Stdio::piped())
+                .stderr(Stdio::null())
+                .spawn()
+                .unwrap();
+            sign.stdin.take().unwrap().write_all(&buf).unwrap();
+            let sig = String::from_utf8(sign.wait_with_output().unwrap().stdout).unwrap();
+            let signed = repo
+                .commit_signed(buf.as_str().unwrap(), &sig, None)
+                .unwrap();
+            repo.head().unwrap().set_target(signed, "signed").unwrap();
+        }
+
+        let other = fs::read_to_string(key.with_extension("pub")).unwrap();
+        conf.trusted_keys = vec!["0123 4567".into()];
+        assert!(conf.build(None).is_err());
+        conf.trusted_keys = vec![other.trim().to_string()];
+        conf.build(None).unwrap();
+        assert!(dir.path().join("built").exists());
+    }
+
+    #[test]
+    fn test_env_interpolation() {
+        std::env::set_var("REMA_TEST_INTERP", "nightly");
+        let (dir, conf) = fixture(
+            r#"
+                tags = ["${REMA_TEST_INTERP}"]
+                build = ["touch ${REMA_TEST_INTERP}-$$"]
+            "#,
+        );
+        assert_eq!(conf.tags(), ["nightly"]);
+        conf.build(None).unwrap();
+        assert!(conf.workdir().join("nightly-$").exists());
+
+        fs::write(
+            dir.path().join("rema.toml"),
+            r#"tags = ["${REMA_TEST_UNSET}"]"#,
+        )
+        .unwrap();
+        let conf = RemaConfig::load(dir.path().to_path_buf(), false).unwrap();
+        assert_eq!(conf.tags(), [""]);
+        let err = RemaConfig::load(dir.path().to_path_buf(), true).unwrap_err();
+        assert!(err.to_string().contains("REMA_TEST_UNSET"), "{}", err);
+    }
+
+    #[test]
+    fn test_local_overrides() {
+        let (dir, _) = fixture(
+            r#"
+                tags = ["web"]
+                build = ["make"]
+                env = { CC = "gcc", PROFILE = "release" }
+            "#,
+        );
+        fs::write(
+            dir.path().join("rema.local.toml"),
+            r#"
+                build = ["make debug"]
+                env = { CC = "clang" }
+            "#,
+        )
+        .unwrap();
+        let conf = RemaConfig::load(dir.path().to_path_buf(), false).unwrap();
+        let lines = conf.commands().map(|c| c.line).collect::<Vec<_>>();
+        assert_eq!(lines, ["make debug"]);
+        assert_eq!(conf.tags(), ["web"]);
+        assert_eq!(conf.env["CC"], "clang");
+        assert_eq!(conf.env["PROFILE"], "release");
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_skip_submodules() {
+        let (lib, _) = fixture(r#"build = ["make"]"#);
+        let base = tempfile::tempdir().unwrap();
+        let app = base.path().join("app");
+        let repo = Repository::init(&app).unwrap();
+        fs::write(app.join("rema.toml"), r#"build = ["make"]"#).unwrap();
+        let mut sub = repo
+            .submodule(lib.path().to_str().unwrap(), Path::new("vendor/lib"), true)
+            .unwrap();
+        sub.clone(None).unwrap();
+        sub.add_finalize().unwrap();
+        commit_all(&repo, "init");
+        std::os::unix::fs::symlink(app.join("vendor/lib"), base.path().join("lib")).unwrap();
+
+        let config = base_config(base.path(), "");
+        let names = config
+            .repos()
+            .unwrap()
+            .iter()
+            .map(|r| r.name().to_string())
+            .collect::<Vec<_>>();
+        assert_eq!(names, ["app"]);
+    }
+
+    #[test]
+    fn test_names_warn_like_they_filter() {
+        use tracing_subscriber::layer::SubscriberExt;
+
+        let base = tempfile::tempdir().unwrap();
+        let dir = base.path().join("app");
+        Repository::init(&dir).unwrap();
+        fs::write(dir.join("rema.toml"), r#"build = ["make"]"#).unwrap();
+        let mut config = base_config(base.path(), "");
+        config.names = Some(vec!["app".into(), "group/app".into()]);
+
+        let _serial = warnings::SERIAL.lock().unwrap();
+        let subscriber = tracing_subscriber::registry().with(warnings::Sink);
+        tracing::subscriber::with_default(subscriber, || {
+            let from = warnings::count();
+            assert_eq!(config.repos().unwrap().len(), 1);
+            let err = warnings::check_since(from, "--strict").unwrap_err();
+            assert!(
+                err.to_string().contains(r#"no repo named "group/app""#),
+                "{}",
+                err
+            );
+        });
+    }
+
+    #[test]
+    fn test_start_from_stop_after() {
+        let base = tempfile::tempdir().unwrap();
+        for name in ["a", "b", "c", "d"] {
+            let dir = base.path().join(name);
+            Repository::init(&dir).unwrap();
+            fs::write(dir.join("rema.toml"), r#"build = ["make"]"#).unwrap();
+        }
+        let mut config = base_config(base.path(), "");
+        let names = |config: &Config| {
+            config
+                .repos()
+                .unwrap()
+                .iter()
+                .map(|r| r.name().to_string())
+                .collect::<Vec<_>>()
+        };
+
+        config.start_from = Some("b".into());
+        assert_eq!(names(&config), ["b", "c", "d"]);
+        config.stop_after = Some("c".into());
+        assert_eq!(names(&config), ["b", "c"]);
+        config.start_from = None;
+        assert_eq!(names(&config), ["a", "b", "c"]);
+
+        config.start_from = Some("d".into());
+        assert!(matches!(config.repos(), Err(ConfigError::Range(_))));
+        config.start_from = Some("e".into());
+        let err = config.repos().unwrap_err().to_string();
+        assert_eq!(err, "--start-from e: no repo by that name");
+    }
+
+    #[test]
+    fn test_warnings_as_errors() {
+        use crate::logging::{self, LogFormat};
+        use tracing_subscriber::EnvFilter;
+
+        let (dir, _) = fixture(
+            r#"
+                biuld = ["make"]
+                clean = [{ run = "make clean", cdw = "web" }]
+                env = { CC = "${REMA_TEST_UNSET}" }
+            "#,
+        );
+        let file = dir.path().join("config.toml");
+        fs::write(
+            &file,
+            format!(
+                "version = 1\nbase_dir = {:?}\nskip_misssing = true\n",
+                dir.path()
+            ),
+        )
+        .unwrap();
+        let _serial = warnings::SERIAL.lock().unwrap();
+        // as with REMA_LOG=error, the warnings still count
+        let filter = EnvFilter::new("error");
+        let subscriber = logging::subscriber(LogFormat::Text, false, filter, io::sink);
+        tracing::subscriber::with_default(subscriber, || {
+            let from = warnings::count();
+            assert!(RemaConfig::load(dir.path().to_path_buf(), false).is_ok());
+            let err = warnings::check_since(from, "-W error")
+                .unwrap_err()
+                .to_string();
+            assert!(err.starts_with("3 warnings with -W error:"), "{}", err);
+            assert!(
+                err.contains(r#"undefined variable "REMA_TEST_UNSET""#),
+                "{}",
+                err
+            );
+            assert!(err.contains(r#"unknown key "biuld""#), "{}", err);
+            assert!(
+                err.contains(r#"unknown key "cdw" in command "make clean""#),
+                "{}",
+                err
+            );
+
+            let from = warnings::count();
+            assert!(Config::load(&file, None).is_ok());
+            let err = warnings::check_since(from, "-W error").unwrap_err();
+            assert!(err.to_string().contains("skip_misssing"), "{}", err);
+
+            // a clean config passes
+            fs::write(&file, format!("base_dir = {:?}\n", dir.path())).unwrap();
+            let from = warnings::count();
+            assert!(Config::load(&file, None).is_ok());
+            assert!(warnings::check_since(from, "-W error").is_ok());
+        });
+    }
+
+    #[test]
+    fn test_set_overrides() {
+        let (dir, _) = fixture(
+            r#"
+                build = ["make"]
+                autoclean = false
+                pull_retries = 1
+                timeout = "1h"
+            "#,
+        );
+        fs::write(dir.path().join("rema.local.toml"), "autoclean = false\n").unwrap();
+        let overrides = [
+            "autoclean=true",
+            "pull_retries = 4",
+            "resource=gpu",
+            "timeout=1s",
+        ]
+        .iter()
+        .map(|s| parse_override(s).unwrap())
+        .collect::<toml::value::Table>();
+        let conf = RemaConfig::load_with(dir.path().to_path_buf(), false, &overrides).unwrap();
+        assert!(conf.autoclean);
+        assert_eq!(conf.pull_retries, 4);
+        assert_eq!(conf.resource.as_deref(), Some("gpu"));
+        assert_eq!(conf.timeout, Some(Duration::from_secs(1)));
+
+        let wrong = std::iter::once(parse_override("pull_retries=lots").unwrap()).collect();
+        let err = RemaConfig::load_with(dir.path().to_path_buf(), false, &wrong).unwrap_err();
+        assert!(err.to_string().contains("invalid type"), "{}", err);
+        assert!(parse_override("build=make")
+            .unwrap_err()
+            .contains("can't --set"));
+        assert!(parse_override("autoclean")
+            .unwrap_err()
+            .contains("key=value"));
+    }
+
+    #[test]
+    fn test_clean_expect_absent() {
+        let (dir, conf) = fixture(
+            r#"
+                clean = ["rm -r target/debug"]
+                clean_expect_absent = ["target", "out.log"]
+            "#,
+        );
+        fs::create_dir_all(dir.path().join("target/debug")).unwrap();
+        fs::write(dir.path().join("target/debug/app"), "").unwrap();
+        // an empty target/ is fine
+        conf.clean().unwrap();
+
+        fs::create_dir_all(dir.path().join("target/debug")).unwrap();
+        fs::write(dir.path().join("target/keep"), "").unwrap();
+        fs::write(dir.path().join("out.log"), "").unwrap();
+        match conf.clean() {
+            Err(CommandError::NotCleaned(left)) => {
+                assert_eq!(left, vec![PathBuf::from("target"), "out.log".into()]);
+            }
+            r => panic!("expected NotCleaned, got {:?}", r),
+        }
+    }
+
+    #[test]
+    fn test_allowed_remote_url() {
+        let (_dir, conf) = fixture(r"allowed_remote_url = '^https://github\.com/me/'");
+        track(&conf, "https://example.com/someone-else/app.git");
+
+        let err = conf.pull(None).unwrap_err();
+        assert!(
+            err.message().contains("does not match allowed_remote_url"),
+            "{}",
+            err
+        );
+        assert!(toml::from_str::<RemaConfig>("allowed_remote_url = '('").is_err());
+    }
+
+    #[test]
+    fn test_parallel_discovery() {
+        let base = tempfile::tempdir().unwrap();
+        for i in 0..40 {
+            let dir = base.path().join(format!("repo{i:02}"));
+            Repository::init(&dir).unwrap();
+            // every third dir isn't managed by rema
+            if i % 3 != 0 {
+                fs::write(dir.join("rema.toml"), format!(r#"tags = ["t{i}"]"#)).unwrap();
+            }
+        }
+        let mut config: Config =
+            toml::from_str(&format!("base_dir = {:?}", base.path().to_str().unwrap())).unwrap();
+        let names = |c: &Config| {
+            c.repos()
+                .unwrap()
+                .iter()
+                .map(|r| r.name().to_string())
+                .collect::<Vec<_>>()
+        };
+
+        let sequential = names(&config);
+        assert_eq!(sequential.len(), 26);
+        assert!(sequential.windows(2).all(|w| w[0] < w[1]));
+        config.parallel_discovery = true;
+        assert_eq!(names(&config), sequential);
+
+        // broken repos are all collected
+        config.strict = true;
+        for i in &[1, 2] {
+            let p = base.path().join(format!("repo{i:02}/rema.toml"));
+            fs::write(p, r#"tags = ["${REMA_TEST_UNSET}"]"#).unwrap();
+        }
+        match config.repos() {
+            Err(ConfigError::Repos(errors)) => assert_eq!(errors.len(), 2),
+            r => panic!("expected 2 errors, got {:?}", r.map(|r| r.len())),
+        }
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_isolate_env() {
+        std::env::set_var("REMA_TEST_PARENT", "leaked");
+        let (dir, mut conf) = fixture(
+            r#"
+                build = ["sh -c env>envdump"]
+                env = { CC = "clang" }
+            "#,
+        );
+        conf.isolate_env = true;
+        conf.build(None).unwrap();
+        let env = fs::read_to_string(dir.path().join("envdump")).unwrap();
+        let vars = env
+            .lines()
+            .filter_map(|l| l.split_once('='))
+            .collect::<Vec<_>>();
+        assert!(vars.iter().any(|(k, _)| *k == "PATH"), "{}", env);
+        assert!(vars.contains(&("CC", "clang")), "{}", env);
+        assert!(
+            !vars.iter().any(|(k, _)| *k == "REMA_TEST_PARENT"),
+            "{}",
+            env
+        );
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_nice() {
+        let (dir, conf) = fixture(
+            r#"
+                build = ["sh niceness.sh"]
+                nice = 5
+            "#,
+        );
+        fs::write(dir.path().join("niceness.sh"), "nice > niceness").unwrap();
+        conf.build(None).unwrap();
+
+        let current = unsafe { libc::getpriority(libc::PRIO_PROCESS, 0) };
+        let got = fs::read_to_string(dir.path().join("niceness")).unwrap();
+        assert_eq!(got.trim().parse::<i32>().unwrap(), (current + 5).min(19));
+    }
+
+    #[test]
+    fn test_stdin_string() {
+        let (dir, conf) = fixture(
+            r#"
+                build = [
+                    { run = "sh copy.sh fed", stdin = { string = "hello\n" } },
+                    { run = "sh copy.sh closed", stdin = "null" },
+                ]
+            "#,
+        );
+        fs::write(dir.path().join("copy.sh"), r#"cat > "$1""#).unwrap();
+        conf.build(None).unwrap();
+        assert_eq!(
+            fs::read_to_string(dir.path().join("fed")).unwrap(),
+            "hello\n"
+        );
+        assert_eq!(fs::read_to_string(dir.path().join("closed")).unwrap(), "");
+
+        // closed when it's left out and can't be shared
+        let (dir, mut conf) = fixture(r#"build = ["sh copy.sh default"]"#);
+        fs::write(dir.path().join("copy.sh"), r#"cat > "$1""#).unwrap();
+        conf.close_stdin = true;
+        conf.build(None).unwrap();
+        assert_eq!(fs::read_to_string(dir.path().join("default")).unwrap(), "");
+    }
+
+    #[test]
+    fn test_group_output() {
+        let (dir, mut conf) = fixture(r#"build = ["sh both.sh"]"#);
+        conf.output = OutputMode::Group;
+        fs::write(
+            dir.path().join("both.sh"),
+            "echo out; echo err >&2; echo done",
+        )
+        .unwrap();
+        conf.build(None).unwrap();
+        // both pipes end up in the one block, their interleaving isn't fixed
+        let held = String::from_utf8(conf.output_buf.lock().unwrap().clone()).unwrap();
+        let mut lines = held.lines().collect::<Vec<_>>();
+        lines.sort_unstable();
+        assert_eq!(lines, vec!["done", "err", "out"]);
+        conf.flush_output();
+        assert!(conf.output_buf.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_missing_clones() {
+        let base = tempfile::tempdir().unwrap();
+        fs::create_dir(base.path().join("have")).unwrap();
+        let config: Config = toml::from_str(&format!(
+            r#"
+                base_dir = {:?}
+                [clone]
+                have = "https://example.com/have.git"
+                want = "https://example.com/want.git"
+            "#,
+            base.path().to_str().unwrap()
+        ))
+        .unwrap();
+        assert_eq!(
+            config.missing_clones(),
+            vec![(
+                "want",
+                "https://example.com/want.git",
+                base.path().join("want")
+            )]
+        );
+    }
+
+    #[test]
+    fn test_skip_missing() {
+        let base = tempfile::tempdir().unwrap();
+        // rema.toml is still there but the repo has gone
+        fs::create_dir(base.path().join("app")).unwrap();
+        fs::write(base.path().join("app/rema.toml"), "build = []").unwrap();
+        let config = |extra: &str| -> Config {
+            toml::from_str(&format!(
+                "base_dir = {:?}\n{extra}",
+                base.path().to_str().unwrap()
+            ))
+            .unwrap()
+        };
+        match config("").repos() {
+            Err(ConfigError::Repos(errors)) => {
+                assert!(matches!(errors[..], [ConfigError::Missing(_, None)]));
+            }
+            _ => panic!("expected the missing repo to fail the run"),
+        }
+        assert!(config("skip_missing = true").repos().unwrap().is_empty());
+
+        fs::write(base.path().join("app/rema.toml"), "skip_missing = true").unwrap();
+        assert!(config("").repos().unwrap().is_empty());
+        fs::write(base.path().join("app/rema.toml"), "skip_missing = false").unwrap();
+        assert!(config("skip_missing = true").repos().is_err());
+    }
+
+    #[test]
+    fn test_profile() {
+        let work = tempfile::tempdir().unwrap();
+        let home = tempfile::tempdir().unwrap();
+        let file = work.path().join("config.toml");
+        fs::write(
+            &file,
+            format!(
+                r#"
+                    base_dir = {:?}
+                    timeout = "1m"
+                    [profile.home]
+                    base_dir = {:?}
+                "#,
+                work.path().to_str().unwrap(),
+                home.path().to_str().unwrap()
+            ),
+        )
+        .unwrap();
+
+        let c = Config::load(&file, None).unwrap();
+        assert_eq!(c.base_dir, work.path());
+        let c = Config::load(&file, Some("home")).unwrap();
+        assert_eq!(c.base_dir, home.path());
+        assert_eq!(c.timeout, Some(Duration::from_mins(1)));
+        match Config::load(&file, Some("office")) {
+            Err(ConfigError::UnknownProfile(name, known)) => {
+                assert_eq!(name, "office");
+                assert_eq!(known, ["home"]);
+            }
+            _ => panic!("expected an unknown profile error"),
+        }
+    }
+
+    #[test]
+    fn test_reattach() {
+        let cloned = cloned_fixture("");
+        let (up, upstream, dir) = (&cloned.up, &cloned.upstream, &cloned.dir);
+        fs::write(dir.join("rema.local.toml"), "reattach = true\n").unwrap();
+        let conf = cloned.load();
+        git::checkout_detached(conf.repo(), "HEAD").unwrap();
+        fs::write(up.path().join("new"), "").unwrap();
+        let new = commit_all(upstream.repo(), "new");
+
+        // not with uncommitted changes to lose
+        fs::write(dir.join("rema.toml"), "changed").unwrap();
+        assert_eq!(conf.pull(None), Ok(false));
+        assert!(conf.take_pull_skipped());
+        assert!(conf.repo().head_detached().unwrap());
+
+        // the default branch comes from origin, not whichever remote sorts
+        // first
+        conf.repo().remote("aaa", "/nonexistent").unwrap();
+
+        fs::write(dir.join("rema.toml"), "").unwrap();
+        assert!(conf.pull(None).unwrap());
+        assert!(!conf.repo().head_detached().unwrap());
+        assert_eq!(conf.head(), Some(new));
+        assert!(dir.join("new").exists());
+    }
+
+    #[test]
+    fn test_skip_build() {
+        let (dir, mut conf) = fixture("build = []");
+        assert!(!conf.skip_build());
+        fs::write(dir.path().join(".rema-skip"), "").unwrap();
+        assert!(conf.skip_build());
+
+        conf.skip_file = Some("NOBUILD".into());
+        assert!(!conf.skip_build());
+        fs::write(dir.path().join("NOBUILD"), "").unwrap();
+        assert!(conf.skip_build());
+
+        let (dir, conf) = fixture(r#"skip_if = "test -f hold""#);
+        assert!(!conf.skip_build());
+        fs::write(dir.path().join("hold"), "").unwrap();
+        assert!(conf.skip_build());
+        let (_dir, conf) = fixture(r#"skip_if = "no-such-command-rema""#);
+        assert!(!conf.skip_build());
+    }
+
+    #[test]
+    fn test_build_at() {
+        let (dir, conf) = fixture(r#"build = ["cp v built"]"#);
+        fs::write(dir.path().join(".gitignore"), "built\n").unwrap();
+        fs::write(dir.path().join("v"), "1").unwrap();
+        let one = commit_all(conf.repo(), "one");
+        fs::write(dir.path().join("v"), "2").unwrap();
+        commit_all(conf.repo(), "two");
+
+        assert_eq!(conf.build_at(&one.to_string()).unwrap(), one);
+        assert_eq!(fs::read_to_string(dir.path().join("built")).unwrap(), "1");
+        assert!(!conf.repo().head_detached().unwrap());
+        assert_eq!(fs::read_to_string(dir.path().join("v")).unwrap(), "2");
+
+        fs::write(dir.path().join("v"), "dirty").unwrap();
+        assert!(matches!(
+            conf.build_at(&one.to_string()),
+            Err(CommandError::Dirty(_))
+        ));
+    }
+
+    #[test]
+    fn test_ensure_clean() {
+        let (dir, conf) = fixture(r#"build = ["touch built"]"#);
+        conf.ensure_clean().unwrap();
+        fs::write(dir.path().join("rema.toml"), "build = []").unwrap();
+        match conf.ensure_clean() {
+            Err(CommandError::Dirty(files)) => assert_eq!(files, vec![PathBuf::from("rema.toml")]),
+            r => panic!("expected Dirty, got {:?}", r),
+        }
+    }
+
+    #[test]
+    fn test_remote_url_updated() {
+        let new = tempfile::tempdir().unwrap();
+        let new_url = new.path().to_str().unwrap();
+        let (_dir, mut conf) = fixture(&format!("remote_url = {new_url:?}"));
+        track(&conf, "/srv/old-host/app.git");
+        let url = |c: &RemaConfig| {
+            c.repo()
+                .find_remote("origin")
+                .unwrap()
+                .url()
+                .map(String::from)
+        };
+
+        conf.no_mutate_remotes = true;
+        assert!(conf.pull(None).is_err());
+        assert_eq!(url(&conf).as_deref(), Some("/srv/old-host/app.git"));
+
+        conf.no_mutate_remotes = false;
+        // the fetch itself fails, new is an empty dir
+        let _ = conf.pull(None);
+        assert_eq!(url(&conf).as_deref(), Some(new_url));
+    }
+
+    #[test]
+    fn test_local_only() {
+        let (dir, mut conf) = fixture(
+            r#"
+                autoupdate = true
+                build = ["touch built"]
+            "#,
+        );
+        // fetching this would fail
+        track(&conf, dir.path().join("missing").to_str().unwrap());
+        assert!(conf.pull(None).is_err());
+
+        conf.local_only = true;
+        assert_eq!(conf.pull(None), Ok(false));
+        conf.autoupdate_build(conf.head()).unwrap();
+        assert!(dir.path().join("built").exists());
+    }
+
+    #[test]
+    fn test_write_built_commit() {
+        let (dir, mut conf) = fixture("built_commit_file = \".rema-built-commit\"");
+        let head = conf.head().unwrap();
+        conf.write_built_commit().unwrap();
+        let read = |p: &Path| fs::read_to_string(p).unwrap();
+        assert_eq!(
+            read(&dir.path().join(".rema-built-commit")),
+            format!("{head}\n")
+        );
+
+        let central = tempfile::tempdir().unwrap();
+        conf.built_commit_file = Some(central.path().join("built/{repo}"));
+        conf.write_built_commit().unwrap();
+        let file = central.path().join("built").join(conf.name());
+        assert_eq!(read(&file), format!("{head}\n"));
+        assert!(!central
+            .path()
+            .join("built")
+            .join(format!("{}.part", conf.name()))
+            .exists());
+    }
+
+    #[test]
+    fn test_run_env() {
+        let (dir, mut conf) = fixture(r#"build = ["sh dump.sh"]"#);
+        fs::write(dir.path().join("dump.sh"), "env > env.out").unwrap();
+        let old = commit_all(conf.repo(), "dump");
+        fs::write(dir.path().join("v"), "2").unwrap();
+        let new = commit_all(conf.repo(), "two");
+        let branch = conf.repo().head().unwrap().shorthand().unwrap().to_string();
+        let vars = || {
+            fs::read_to_string(dir.path().join("env.out"))
+                .unwrap()
+                .lines()
+                .filter(|l| l.starts_with("REMA_") || l.starts_with("CI_"))
+                .map(String::from)
+                .collect::<BTreeSet<_>>()
+        };
+
+        conf.build(Some(old)).unwrap();
+        let expected = [
+            format!("REMA_REPO_NAME={}", conf.name()),
+            format!("REMA_REPO_PATH={}", conf.workdir().display()),
+            format!("REMA_BRANCH={branch}"),
+            "REMA_UPDATE_STATUS=updated".into(),
+            format!("REMA_OLD_SHA={old}"),
+            format!("REMA_NEW_SHA={new}"),
+        ];
+        let got = vars();
+        for v in &expected {
+            assert!(got.contains(v), "{} not in {:?}", v, got);
+        }
+
+        conf.env_prefix = Some("CI_".into());
+        conf.build(None).unwrap();
+        let got = vars();
+        assert!(got.contains("CI_UPDATE_STATUS=none"), "{:?}", got);
+        assert!(got.contains("CI_OLD_SHA="), "{:?}", got);
+        assert!(!got.iter().any(|v| v.starts_with("REMA_REPO_NAME")));
+    }
+
+    #[test]
+    fn test_renamed_deleted_env() {
+        let (dir, mut conf) = fixture(r#"build = ["sh dump.sh"]"#);
+        fs::write(
+            dir.path().join("dump.sh"),
+            r#"printf "$REMA_RENAMED" > renamed; printf "$REMA_DELETED" > deleted"#,
+        )
+        .unwrap();
+        fs::write(
+            dir.path().join("bashrc"),
+            "alias ll='ls -l'\nexport EDITOR=vim\n",
+        )
+        .unwrap();
+        fs::write(dir.path().join("old.conf"), "x").unwrap();
+        let old = commit_all(conf.repo(), "dotfiles");
+
+        fs::create_dir(dir.path().join("bash")).unwrap();
+        fs::rename(dir.path().join("bashrc"), dir.path().join("bash/bashrc")).unwrap();
+        fs::remove_file(dir.path().join("old.conf")).unwrap();
+        let mut index = conf.repo().index().unwrap();
+        index.remove_path(Path::new("bashrc")).unwrap();
+        index.remove_path(Path::new("old.conf")).unwrap();
+        index.write().unwrap();
+        commit_all(conf.repo(), "restructure");
+
+        conf.build(Some(old)).unwrap();
+        let read = |f: &str| fs::read_to_string(dir.path().join(f)).unwrap();
+        assert_eq!(read("renamed"), "bashrc\tbash/bashrc\n");
+        assert_eq!(read("deleted"), "old.conf\n");
+
+        // a later build without a pull doesn't see the old diff
+        conf.build(None).unwrap();
+        assert_eq!(read("renamed"), "");
+
+        conf.env_prefix = Some("CI_".into());
+        fs::write(
+            dir.path().join("dump.sh"),
+            r#"printf "$CI_DELETED" > deleted"#,
+        )
+        .unwrap();
+        conf.build(Some(old)).unwrap();
+        assert_eq!(read("deleted"), "old.conf\n");
     }
 }
@@ -0,0 +1,123 @@
+use crate::config::Config;
+use crate::duration;
+use crate::errors::pretty_error;
+use crate::run::{self, Ctx};
+
+use std::thread;
+
+use clap::ArgMatches;
+use tracing::{info, warn};
+
+// `rema daemon`, pulls every interval. A config that fails to load, say
+// because it's half written, is logged and the last good one used instead
+// unless `strict` is set.
+pub(crate) struct Daemon<F> {
+    load: F,
+    strict: bool,
+    last_good: Option<Config>,
+}
+
+impl<F> Daemon<F>
+where
+    F: Fn() -> Result<Config, failure::Error>,
+{
+    pub(crate) fn new(load: F, strict: bool) -> Self {
+        Self {
+            load,
+            strict,
+            last_good: None,
+        }
+    }
+
+    // None when no config has loaded yet
+    pub(crate) fn reload(&mut self) -> Result<Option<&Config>, failure::Error> {
+        match (self.load)() {
+            Ok(c) => self.last_good = Some(c),
+            Err(e) if !self.strict => {
+                warn!(
+                    event = "config_reload_failed",
+                    "could not load config, {}: {}",
+                    if self.last_good.is_some() {
+                        "keeping the last good one"
+                    } else {
+                        "retrying next cycle"
+                    },
+                    pretty_error(&e)
+                );
+            }
+            Err(e) => return Err(e),
+        }
+        Ok(self.last_good.as_ref())
+    }
+
+    fn cycle(&mut self, ctx: &Ctx, m: &ArgMatches<'_>) -> Result<(), failure::Error> {
+        let strict = self.strict;
+        let Some(config) = self.reload()? else {
+            return Ok(());
+        };
+        match run::pull(config, ctx, m) {
+            Err(e) if !strict => {
+                warn!(event = "cycle_failed", "pull failed: {}", pretty_error(&e));
+                Ok(())
+            }
+            r => r,
+        }
+    }
+
+    pub(crate) fn run(mut self, ctx: &Ctx, m: &ArgMatches<'_>) -> Result<(), failure::Error> {
+        let interval =
+            duration::parse(m.value_of("INTERVAL").unwrap_or("15m")).map_err(failure::err_msg)?;
+        loop {
+            self.cycle(ctx, m)?;
+            info!(event = "sleep", "next pull in {:?}", interval);
+            thread::sleep(interval);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::fs;
+    use std::path::Path;
+
+    fn loader(p: &Path) -> impl Fn() -> Result<Config, failure::Error> + '_ {
+        move || Ok(Config::load(p, None)?)
+    }
+
+    #[test]
+    fn test_survives_config_error() {
+        let dir = tempfile::tempdir().unwrap();
+        let file = dir.path().join("config.toml");
+        let good = format!(
+            "base_dir = {:?}\ntimeout = \"1m\"",
+            dir.path().to_str().unwrap()
+        );
+        fs::write(&file, &good).unwrap();
+
+        let mut daemon = Daemon::new(loader(&file), false);
+        assert!(daemon.reload().unwrap().is_some());
+
+        // mid edit
+        fs::write(&file, "base_dir = ").unwrap();
+        let config = daemon.reload().unwrap().unwrap();
+        assert_eq!(config.timeout, Some(std::time::Duration::from_mins(1)));
+
+        let mut strict = Daemon::new(loader(&file), true);
+        assert!(strict.reload().is_err());
+
+        // picks the file back up once it's fixed
+        fs::write(&file, good.replace("1m", "2m")).unwrap();
+        let config = daemon.reload().unwrap().unwrap();
+        assert_eq!(config.timeout, Some(std::time::Duration::from_mins(2)));
+    }
+
+    #[test]
+    fn test_no_config_yet() {
+        let dir = tempfile::tempdir().unwrap();
+        let file = dir.path().join("missing.toml");
+        let mut daemon = Daemon::new(loader(&file), false);
+        assert!(daemon.reload().unwrap().is_none());
+    }
+}
@@ -0,0 +1,117 @@
+use std::time::Duration;
+
+use serde::{Deserialize, Deserializer};
+
+// Parse durations like "30s", "5m", "2h", "1d" or combinations such as
+// "1h30m" and "1d 12h". A bare number is rejected since its unit is ambiguous.
+pub(crate) fn parse(s: &str) -> Result<Duration, String> {
+    let err = |why: &str| format!("invalid duration {s:?}: {why}");
+    let mut rest = s.trim();
+    if rest.is_empty() {
+        return Err(err("empty"));
+    }
+
+    let mut total = Duration::from_secs(0);
+    while !rest.is_empty() {
+        let digits = rest
+            .find(|c: char| !c.is_ascii_digit())
+            .unwrap_or(rest.len());
+        if digits == 0 {
+            return Err(err("expected a number"));
+        }
+        let n = rest[..digits]
+            .parse::<u64>()
+            .map_err(|_| err("number too large"))?;
+        rest = &rest[digits..];
+
+        let unit_len = rest
+            .find(|c: char| !c.is_ascii_alphabetic())
+            .unwrap_or(rest.len());
+        let secs = match &rest[..unit_len] {
+            "s" => 1,
+            "m" => 60,
+            "h" => 60 * 60,
+            "d" => 24 * 60 * 60,
+            "" => return Err(err("missing unit, use s, m, h or d")),
+            u => return Err(err(&format!("unknown unit {u:?}"))),
+        };
+        rest = rest[unit_len..].trim_start();
+
+        let part = n
+            .checked_mul(secs)
+            .map(Duration::from_secs)
+            .ok_or_else(|| err("duration too large"))?;
+        total = total
+            .checked_add(part)
+            .ok_or_else(|| err("duration too large"))?;
+    }
+    Ok(total)
+}
+
+// clap validator
+#[allow(clippy::needless_pass_by_value)]
+pub(crate) fn validate(s: String) -> Result<(), String> {
+    parse(&s).map(|_| ())
+}
+
+// for #[serde(default, deserialize_with = "duration::deserialize_opt")]
+pub(crate) fn deserialize_opt<'de, D>(d: D) -> Result<Option<Duration>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let s = String::deserialize(d)?;
+    parse(&s).map(Some).map_err(serde::de::Error::custom)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_units() {
+        assert_eq!(parse("30s"), Ok(Duration::from_secs(30)));
+        assert_eq!(parse("5m"), Ok(Duration::from_mins(5)));
+        assert_eq!(parse("2h"), Ok(Duration::from_hours(2)));
+        assert_eq!(parse("1d"), Ok(Duration::from_hours(24)));
+    }
+
+    #[test]
+    fn test_combinations() {
+        assert_eq!(parse("1h30m"), Ok(Duration::from_mins(90)));
+        assert_eq!(parse("1d 12h"), Ok(Duration::from_hours(36)));
+        assert_eq!(parse(" 1m 1s "), Ok(Duration::from_secs(61)));
+        assert_eq!(parse("0s"), Ok(Duration::from_secs(0)));
+    }
+
+    #[test]
+    fn test_invalid() {
+        for s in &[
+            "",
+            "   ",
+            "30",
+            "s",
+            "5x",
+            "1h30",
+            "-5m",
+            "1.5h",
+            "99999999999999999999s",
+        ] {
+            assert!(parse(s).is_err(), "{}", s);
+        }
+        assert!(validate("10q".into()).unwrap_err().contains("unknown unit"));
+    }
+
+    #[test]
+    fn test_deserialize() {
+        #[derive(Deserialize)]
+        struct T {
+            #[serde(default, deserialize_with = "deserialize_opt")]
+            timeout: Option<Duration>,
+        }
+        let t: T = toml::from_str(r#"timeout = "1m30s""#).unwrap();
+        assert_eq!(t.timeout, Some(Duration::from_secs(90)));
+        let t: T = toml::from_str("").unwrap();
+        assert_eq!(t.timeout, None);
+        assert!(toml::from_str::<T>(r#"timeout = "soon""#).is_err());
+    }
+}
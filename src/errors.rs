@@ -1,6 +1,9 @@
 use std::error::Error;
 use std::fmt;
+use std::io;
 use std::path::PathBuf;
+use std::process::ExitStatus;
+use std::time::Duration;
 
 // https://github.com/BurntSushi/imdb-rename/blob/master/src/main.rs
 // Return a prettily formatted error, including its entire causal chain.
@@ -21,6 +24,18 @@ pub(crate) enum ConfigError {
     BaseDirNotDir(PathBuf),
     File(failure::Error),
     Toml(failure::Error),
+    Interpolate(PathBuf, String),
+    Version(PathBuf, String),
+    // the profile asked for and the ones the config has
+    UnknownProfile(String, Vec<String>),
+    NotARepo(PathBuf, git2::Error),
+    // the repo's skip_missing, if its rema.toml could still be read
+    Missing(PathBuf, Option<bool>),
+    Repos(Vec<ConfigError>),
+    // -c with a URL that couldn't be fetched and has no cached copy
+    Fetch(String, String),
+    // --start-from or --stop-after that can't be satisfied
+    Range(String),
 }
 
 impl fmt::Display for ConfigError {
@@ -34,13 +49,37 @@ impl fmt::Display for ConfigError {
             }
             Self::File(e) => write!(f, "could not read config file: {}", pretty_error(e)),
             Self::Toml(e) => write!(f, "error in config file: {}", pretty_error(e)),
+            Self::Interpolate(p, e) | Self::Version(p, e) => {
+                write!(f, "in {:?}: {}", p.to_str(), e)
+            }
+            Self::UnknownProfile(name, known) if known.is_empty() => {
+                write!(f, "no profile {name:?}, the config defines none")
+            }
+            Self::UnknownProfile(name, known) => {
+                write!(
+                    f,
+                    "no profile {name:?}, expected one of {}",
+                    known.join(", ")
+                )
+            }
+            Self::NotARepo(p, e) => write!(f, "{} is not a git repo: {e}", p.display()),
+            Self::Missing(p, _) => write!(f, "{} is missing", p.display()),
+            Self::Range(e) => f.write_str(e),
+            Self::Fetch(url, e) => write!(f, "could not fetch config from {url}: {e}"),
+            Self::Repos(errors) => {
+                write!(f, "{} repos could not be loaded", errors.len())?;
+                for e in errors {
+                    write!(f, "\n  {e}")?;
+                }
+                Ok(())
+            }
         }
     }
 }
 impl Error for ConfigError {}
 
-impl From<std::io::Error> for ConfigError {
-    fn from(e: std::io::Error) -> Self {
+impl From<io::Error> for ConfigError {
+    fn from(e: io::Error) -> Self {
         ConfigError::File(e.into())
     }
 }
@@ -50,3 +89,80 @@ impl From<toml::de::Error> for ConfigError {
         ConfigError::Toml(e.into())
     }
 }
+
+// "exited with 2", or on unix "killed by signal 9 (SIGKILL)" for a command
+// that never got to exit, e.g. at the hands of the OOM killer
+pub(crate) fn exit_reason(status: ExitStatus) -> String {
+    #[cfg(unix)]
+    {
+        use std::os::unix::process::ExitStatusExt;
+
+        if let Some(sig) = status.signal() {
+            let name = match sig {
+                libc::SIGHUP => " (SIGHUP)",
+                libc::SIGINT => " (SIGINT)",
+                libc::SIGQUIT => " (SIGQUIT)",
+                libc::SIGABRT => " (SIGABRT)",
+                libc::SIGKILL => " (SIGKILL)",
+                libc::SIGSEGV => " (SIGSEGV)",
+                libc::SIGPIPE => " (SIGPIPE)",
+                libc::SIGTERM => " (SIGTERM)",
+                _ => "",
+            };
+            let core = if status.core_dumped() {
+                ", core dumped"
+            } else {
+                ""
+            };
+            return format!("killed by signal {sig}{name}{core}");
+        }
+    }
+    match status.code() {
+        Some(code) => format!("exited with {code}"),
+        None => format!("failed with {status}"),
+    }
+}
+
+#[derive(Debug)]
+pub(crate) enum CommandError {
+    Empty,
+    BuildArg(String),
+    RunAs(String),
+    Signature(String),
+    NoCwd(PathBuf),
+    Spawn(String, io::Error),
+    Failed(String, ExitStatus),
+    TimedOut(String, Duration),
+    RepoTimedOut(Duration),
+    NotCleaned(Vec<PathBuf>),
+    Dirty(Vec<PathBuf>),
+    Git(git2::Error),
+    // the failed matrix entries of how many
+    Variants(Vec<(String, CommandError)>, usize),
+}
+
+impl fmt::Display for CommandError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Empty => write!(f, "empty command"),
+            Self::BuildArg(e) | Self::RunAs(e) => write!(f, "{e}"),
+            Self::Signature(e) => write!(f, "signature check failed, {e}"),
+            Self::NoCwd(p) => write!(f, "cwd {} is not a directory", p.display()),
+            Self::Spawn(cmd, e) => write!(f, "could not run {cmd:?}: {e}"),
+            Self::Failed(cmd, status) => write!(f, "{cmd:?} {}", exit_reason(*status)),
+            Self::TimedOut(cmd, t) => write!(f, "{cmd:?} timed out after {t:?}"),
+            Self::RepoTimedOut(t) => write!(f, "repo timed out after {t:?}"),
+            Self::NotCleaned(paths) => write!(f, "clean left behind {paths:?}"),
+            Self::Dirty(paths) => write!(f, "uncommitted changes in {paths:?}"),
+            Self::Git(e) => write!(f, "{e}"),
+            Self::Variants(failed, of) => {
+                write!(f, "{} of {of} matrix variants failed", failed.len())?;
+                for (variant, e) in failed {
+                    write!(f, "\n  {variant}: {e}")?;
+                }
+                Ok(())
+            }
+        }
+    }
+}
+impl Error for CommandError {}
@@ -0,0 +1,164 @@
+use crate::config::Phase;
+use crate::git::FileChange;
+use crate::report::Outcome;
+
+use std::io::{self, Write};
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use serde::Serialize;
+
+// Things that happen during a run, streamed as one JSON object per line
+#[derive(Debug, Serialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub(crate) enum Event<'a> {
+    RepoStart {
+        repo: &'a str,
+        action: &'a str,
+    },
+    CommandStart {
+        repo: &'a str,
+        phase: Phase,
+        command: &'a str,
+    },
+    CommandEnd {
+        repo: &'a str,
+        phase: Phase,
+        command: &'a str,
+        // None when killed by a signal or timeout, or it never started
+        exit_code: Option<i32>,
+        duration_ms: u128,
+    },
+    ChangedFiles {
+        repo: &'a str,
+        files: &'a [FileChange],
+        // how many more were left out to keep the line bounded
+        truncated: usize,
+    },
+    // one entry of a repo's build matrix
+    VariantEnd {
+        repo: &'a str,
+        variant: &'a str,
+        ok: bool,
+    },
+    RepoEnd {
+        repo: &'a str,
+        outcome: Outcome,
+    },
+}
+
+#[derive(Serialize)]
+struct Line<'a> {
+    ts_ms: u128,
+    #[serde(flatten)]
+    event: &'a Event<'a>,
+}
+
+static SINK: Mutex<Option<Box<dyn Write + Send>>> = Mutex::new(None);
+
+pub(crate) fn enable(w: Box<dyn Write + Send>) {
+    *SINK.lock().unwrap() = Some(w);
+}
+
+pub(crate) fn enabled() -> bool {
+    SINK.lock().unwrap().is_some()
+}
+
+// write the event and flush straight away so consumers see it as it happens
+pub(crate) fn emit(event: &Event<'_>) {
+    let mut sink = SINK.lock().unwrap();
+    if let Some(w) = sink.as_mut() {
+        let line = Line {
+            ts_ms: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or(Duration::from_secs(0))
+                .as_millis(),
+            event,
+        };
+        let res = serde_json::to_writer(&mut *w, &line)
+            .map_err(io::Error::from)
+            .and_then(|()| writeln!(w))
+            .and_then(|()| w.flush());
+        if let Err(e) = res {
+            tracing::warn!("could not write event: {}", e);
+        }
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use crate::config::tests::fixture;
+
+    use std::sync::Arc;
+
+    #[derive(Clone, Default)]
+    pub(crate) struct Buf(pub(crate) Arc<Mutex<Vec<u8>>>);
+
+    impl Write for Buf {
+        fn write(&mut self, b: &[u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().extend_from_slice(b);
+            Ok(b.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    // the sink turned on until dropped, so a failing test doesn't leave it
+    // on for the rest
+    struct Enabled;
+
+    impl Enabled {
+        fn with(w: Box<dyn Write + Send>) -> Self {
+            enable(w);
+            Self
+        }
+    }
+
+    impl Drop for Enabled {
+        fn drop(&mut self) {
+            if let Ok(mut sink) = SINK.lock() {
+                *sink = None;
+            }
+        }
+    }
+
+    #[test]
+    fn test_command_events() {
+        let (_dir, conf) = fixture(r#"build = ["true", "false"]"#);
+        let buf = Buf::default();
+        let sink = Enabled::with(Box::new(buf.clone()));
+        assert!(conf.build(None).is_err());
+        drop(sink);
+        assert!(!enabled());
+
+        // other tests may run commands while the sink is on
+        let out = String::from_utf8(buf.0.lock().unwrap().clone()).unwrap();
+        let events = out
+            .lines()
+            .map(|l| serde_json::from_str::<serde_json::Value>(l).unwrap())
+            .filter(|e| e["repo"] == conf.name())
+            .collect::<Vec<_>>();
+        let kinds = events
+            .iter()
+            .map(|e| e["event"].as_str().unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(
+            kinds,
+            vec![
+                "command_start",
+                "command_end",
+                "command_start",
+                "command_end"
+            ]
+        );
+        assert_eq!(events[1]["exit_code"], 0);
+        assert_eq!(events[3]["exit_code"], 1);
+        assert_eq!(events[3]["command"], "false");
+        assert_eq!(events[3]["phase"], "build");
+        assert!(events[3]["duration_ms"].is_u64());
+        assert!(events[0]["ts_ms"].is_u64());
+    }
+}
@@ -0,0 +1,189 @@
+use crate::config::RemaConfig;
+use crate::git;
+use crate::status::RepoStatus;
+
+use std::fmt::Write;
+
+// Everything `rema explain` knows about one repo: its resolved settings, git
+// state and the commands a pull followed by an update would run. Read-only,
+// ahead/behind is as of the last fetch.
+pub(crate) fn render(conf: &RemaConfig, status: &RepoStatus) -> String {
+    let mut out = String::new();
+    let _ = writeln!(out, "{}", conf.name());
+    let _ = writeln!(out, "  path: {}", conf.workdir().display());
+    if !conf.tags().is_empty() {
+        let _ = writeln!(out, "  tags: {}", conf.tags().join(", "));
+    }
+
+    out.push_str("\nsettings:\n");
+    let settings = conf.settings();
+    let w = settings.iter().map(|(k, _)| k.len()).max().unwrap_or(0);
+    for (k, v) in &settings {
+        let _ = writeln!(out, "  {k:<w$}  {v}");
+    }
+
+    out.push_str("\ngit:\n");
+    let branch = status.branch.as_deref().unwrap_or("(detached)");
+    let _ = writeln!(out, "  branch: {branch}");
+    let head = conf
+        .head()
+        .map_or_else(|| "(no commit)".into(), |o| o.to_string());
+    let _ = writeln!(out, "  head: {head}");
+    let upstream = git::upstream(conf.repo());
+    match (&upstream, status.ahead_behind) {
+        (Some((_, tracking)), Some((ahead, behind))) => {
+            let _ = writeln!(
+                out,
+                "  upstream: {tracking}, {behind} behind, {ahead} ahead"
+            );
+        }
+        _ => out.push_str("  upstream: none\n"),
+    }
+    let tree = if status.dirty {
+        "uncommitted changes"
+    } else {
+        "clean"
+    };
+    let _ = writeln!(out, "  working tree: {tree}");
+
+    out.push_str("\npull:\n");
+    match &upstream {
+        Some((remote, tracking)) => {
+            let _ = writeln!(out, "  fetch {remote}, fast-forward only to {tracking}");
+            if conf.autoupdate() {
+                out.push_str("  builds right after a pull that moved HEAD (autoupdate)\n");
+            } else {
+                out.push_str("  builds on the next `rema update`\n");
+            }
+        }
+        None => out.push_str("  nothing to pull, the branch has no upstream\n"),
+    }
+
+    render_plan(conf, &mut out);
+    out
+}
+
+// `rema inspect`, a repo cloned from `url` only to read its rema.toml
+pub(crate) fn render_remote(url: &str, conf: &RemaConfig) -> String {
+    let mut out = String::new();
+    let _ = writeln!(out, "{} ({url})", conf.name());
+    if !conf.tags().is_empty() {
+        let _ = writeln!(out, "  tags: {}", conf.tags().join(", "));
+    }
+    render_plan(conf, &mut out);
+    out
+}
+
+fn render_plan(conf: &RemaConfig, out: &mut String) {
+    out.push_str("\nplan:\n");
+    let mut empty = true;
+    for spec in conf.pre_pull_commands().chain(conf.commands()) {
+        empty = false;
+        let _ = write!(out, "  {:<11}  {}", spec.phase, spec.line);
+        if !spec.if_changed.is_empty() {
+            let _ = write!(out, "  (if {:?} changed)", spec.if_changed);
+        }
+        out.push('\n');
+    }
+    if empty {
+        out.push_str("  no commands\n");
+    }
+}
+
+// names that look like they hold a secret, their values are never printed
+const SECRET_WORDS: [&str; 6] = ["TOKEN", "SECRET", "PASSWORD", "PASSWD", "KEY", "CREDENTIAL"];
+
+// explain --dump-env, the environment each command of the plan would get
+// and where every variable comes from
+pub(crate) fn render_env(conf: &RemaConfig) -> String {
+    let mut out = String::from("\nenv:\n");
+    if conf.has_matrix() {
+        out.push_str("  builds also get their matrix entry's variables, see settings\n");
+    }
+    for spec in conf.pre_pull_commands().chain(conf.commands()) {
+        let _ = writeln!(out, "  {}  {}", spec.phase, spec.line);
+        for (k, (v, source)) in conf.resolved_env(&spec) {
+            let v = if is_secret(conf, &k) {
+                "***"
+            } else {
+                v.as_str()
+            };
+            let _ = writeln!(out, "    {k}={v}  ({source})");
+        }
+    }
+    out
+}
+
+fn is_secret(conf: &RemaConfig, name: &str) -> bool {
+    let upper = name.to_uppercase();
+    conf.token_env() == Some(name) || SECRET_WORDS.iter().any(|w| upper.contains(w))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::tests::fixture;
+
+    #[test]
+    fn test_render() {
+        let (_dir, conf) = fixture(
+            r#"
+                tags = ["web"]
+                pre_pull = ["systemctl stop app"]
+                build = [{ run = "npm ci", if_changed = ["package.json"] }, "make"]
+                post_update = ["systemctl start app"]
+                env = { CC = "clang" }
+            "#,
+        );
+        let status = RepoStatus::of(&conf).unwrap();
+        let out = render(&conf, &status);
+        let lines = out.lines().map(str::trim).collect::<Vec<_>>();
+
+        assert!(lines.contains(&"tags: web"), "{}", out);
+        assert!(
+            lines
+                .iter()
+                .any(|l| l.starts_with("env.CC ") && l.ends_with(" clang")),
+            "{}",
+            out
+        );
+        assert!(lines.contains(&"upstream: none"), "{}", out);
+        assert!(lines.contains(&"nothing to pull, the branch has no upstream"));
+        let plan = lines.iter().position(|l| *l == "plan:").unwrap();
+        assert_eq!(
+            lines[plan + 1..],
+            [
+                "pre_pull     systemctl stop app",
+                r#"build        npm ci  (if ["package.json"] changed)"#,
+                "build        make",
+                "post_update  systemctl start app",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_render_env() {
+        let (dir, _) = fixture(
+            r#"
+                build = ["make"]
+                env = { PATH = "/opt/bin", CC = "gcc", API_TOKEN = "hunter2" }
+            "#,
+        );
+        std::fs::write(
+            dir.path().join("rema.local.toml"),
+            r#"env = { CC = "clang" }"#,
+        )
+        .unwrap();
+        let conf = RemaConfig::load(dir.path().to_path_buf(), false).unwrap();
+        let out = render_env(&conf);
+        let lines = out.lines().map(str::trim).collect::<Vec<_>>();
+
+        assert_eq!(lines[..3], ["", "env:", "build  make"]);
+        // the repo's env wins over the inherited one, the local file's over both
+        assert!(lines.contains(&"PATH=/opt/bin  (env)"), "{}", out);
+        assert!(lines.contains(&"CC=clang  (env)"), "{}", out);
+        assert!(lines.contains(&"API_TOKEN=***  (env)"), "{}", out);
+        assert!(!out.contains("hunter2"));
+        assert!(lines.iter().any(|l| l.ends_with("(inherited)")), "{}", out);
+    }
+}
@@ -0,0 +1,98 @@
+use crate::config::{Phase, RemaConfig};
+use crate::git;
+
+use std::fmt::Write;
+
+// A shell script doing roughly what `rema pull` followed by `rema update`
+// would, for auditing or running rema's steps by hand. Each repo runs in a
+// subshell so its directory and env don't leak into the next one.
+pub(crate) fn script(repos: &[RemaConfig]) -> String {
+    let mut out = String::from("#!/bin/sh\nset -e\n");
+    for r in repos {
+        out.push('\n');
+        out.push_str(&repo_script(r));
+    }
+    out
+}
+
+fn repo_script(conf: &RemaConfig) -> String {
+    let mut out = String::new();
+    let _ = writeln!(out, "# {}", conf.name());
+    out.push_str("(\n");
+    let _ = writeln!(out, "    cd {}", quote(&conf.workdir().to_string_lossy()));
+
+    let mut cmds = conf.commands().peekable();
+    if let Some(first) = cmds.peek() {
+        for (k, v) in first.env {
+            let _ = writeln!(out, "    export {k}={}", quote(v));
+        }
+    }
+
+    match git::upstream(conf.repo()) {
+        Some((remote, branch)) => {
+            let _ = writeln!(out, "    git fetch {}", quote(&remote));
+            let _ = writeln!(out, "    git merge --ff-only {}", quote(&branch));
+        }
+        None => out.push_str("    # no upstream, nothing to pull\n"),
+    }
+
+    for spec in cmds {
+        if spec.phase == Phase::Clean {
+            out.push_str("    # autoclean\n");
+        }
+        if !spec.if_changed.is_empty() {
+            let _ = writeln!(
+                out,
+                "    # rema only runs this if {:?} changed",
+                spec.if_changed
+            );
+        }
+        if spec.cwd == conf.workdir() {
+            let _ = writeln!(out, "    {}", spec.line);
+        } else {
+            let cwd = quote(&spec.cwd.to_string_lossy());
+            let _ = writeln!(out, "    (cd {cwd} && {})", spec.line);
+        }
+    }
+    out.push_str(")\n");
+    out
+}
+
+// single quoted for sh, a quote inside becomes '\''
+fn quote(s: &str) -> String {
+    format!("'{}'", s.replace('\'', r"'\''"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::tests::fixture;
+
+    #[test]
+    fn test_script() {
+        let (_dir, conf) = fixture(
+            r#"
+                build = ["make", "make install"]
+                post_update = ["systemctl restart app"]
+                env = { CC = "clang" }
+            "#,
+        );
+        let out = script(std::slice::from_ref(&conf));
+
+        assert!(out.starts_with("#!/bin/sh\nset -e\n"));
+        let lines = out.lines().map(str::trim).collect::<Vec<_>>();
+        let cd = format!("cd '{}'", conf.workdir().display());
+        assert!(lines.contains(&cd.as_str()), "{}", out);
+        let make = lines.iter().position(|l| *l == "make").unwrap();
+        assert_eq!(lines[make + 1], "make install");
+        assert_eq!(lines[make + 2], "systemctl restart app");
+        assert!(lines.contains(&"export CC='clang'"));
+        assert!(lines.contains(&"# no upstream, nothing to pull"));
+    }
+
+    #[test]
+    fn test_quote() {
+        assert_eq!(quote("/srv/my repo"), "'/srv/my repo'");
+        assert_eq!(quote("it's"), r"'it'\''s'");
+    }
+}
@@ -0,0 +1,1005 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::env;
+use std::fmt::{self, Write};
+use std::path::{Path, PathBuf};
+use std::sync::mpsc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use git2::{Delta, ErrorClass, ErrorCode, Repository};
+use regex::Regex;
+use serde::Serialize;
+use tracing::{info, warn};
+
+// Whether a failed git operation is worth trying again
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) enum Retry {
+    Transient,
+    Permanent,
+}
+
+impl fmt::Display for Retry {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Transient => write!(f, "transient"),
+            Self::Permanent => write!(f, "permanent"),
+        }
+    }
+}
+
+// Network hiccups and timeouts are transient, anything that will fail the
+// same way next time (bad credentials, missing refs, invalid specs) is not
+pub(crate) fn classify(e: &git2::Error) -> Retry {
+    match e.code() {
+        ErrorCode::Auth
+        | ErrorCode::Certificate
+        | ErrorCode::NotFound
+        | ErrorCode::InvalidSpec
+        | ErrorCode::NotFastForward
+        | ErrorCode::BareRepo
+        | ErrorCode::UnbornBranch => return Retry::Permanent,
+        ErrorCode::Eof | ErrorCode::Locked => return Retry::Transient,
+        _ => {}
+    }
+
+    match e.class() {
+        ErrorClass::Net | ErrorClass::Http | ErrorClass::Ssh => Retry::Transient,
+        _ if e.message().contains("timed out") => Retry::Transient,
+        _ => Retry::Permanent,
+    }
+}
+
+// Env var holding a token for every https remote
+pub(crate) const TOKEN_VAR: &str = "REMA_GIT_TOKEN";
+// Username sent along with a token, accepted by GitHub, GitLab and Gitea
+const TOKEN_USER: &str = "x-access-token";
+
+// How to fetch a repo, borrowed from its RemaConfig
+pub(crate) struct FetchOpts<'a> {
+    pub(crate) name: &'a str,
+    pub(crate) retries: u32,
+    // overrides TOKEN_VAR for this repo
+    pub(crate) token_env: Option<&'a str>,
+    // fetch from this url for one run instead of the remote's configured one
+    pub(crate) url: Option<&'a str>,
+    // abort the transfer once this passes
+    pub(crate) deadline: Option<Instant>,
+    // refuse to fetch from a url that doesn't match
+    pub(crate) allowed_url: Option<&'a Regex>,
+    // every tag of the remote
+    pub(crate) tags: bool,
+    // with tags, delete local tags the remote doesn't have
+    pub(crate) prune_tags: bool,
+    // fetch these refs instead of the remote's configured refspecs
+    pub(crate) refspec: Option<&'a str>,
+}
+
+// Whether `url` looks like something git can fetch from: a URL with a
+// known scheme, scp-like user@host:path, or an absolute local path
+pub(crate) fn valid_url(url: &str) -> bool {
+    const SCHEMES: &[&str] = &["https://", "http://", "ssh://", "git://", "file://"];
+    if let Some(scheme) = SCHEMES.iter().find(|s| url.starts_with(*s)) {
+        return url.len() > scheme.len() && !url.contains(char::is_whitespace);
+    }
+    if url.starts_with('/') {
+        return true;
+    }
+    match url.split_once(':') {
+        Some((host, path)) => {
+            !host.is_empty()
+                && !path.is_empty()
+                && !host.contains('/')
+                && !url.contains(char::is_whitespace)
+        }
+        None => false,
+    }
+}
+
+// Token for an https url, a per-host REMA_TOKEN_<HOST> wins over the repo's
+// token_env (or REMA_GIT_TOKEN). Never for plain http, where it would be
+// sent in the clear
+pub(crate) fn token_for(
+    url: &str,
+    token_env: Option<&str>,
+    lookup: impl Fn(&str) -> Option<String>,
+) -> Option<String> {
+    let rest = url.strip_prefix("https://")?;
+    let authority = rest.split('/').next()?;
+    let host = authority.rsplit('@').next()?.split(':').next()?;
+    let host_var = host
+        .chars()
+        .map(|c| {
+            if c.is_ascii_alphanumeric() {
+                c.to_ascii_uppercase()
+            } else {
+                '_'
+            }
+        })
+        .collect::<String>();
+
+    lookup(&format!("REMA_TOKEN_{host_var}"))
+        .or_else(|| lookup(token_env.unwrap_or(TOKEN_VAR)))
+        .filter(|t| !t.is_empty())
+}
+
+// Credentials for remotes, tried in order:
+// - ssh urls use ssh-agent
+// - https urls use a token from the environment, see token_for
+// - otherwise the git credential helper
+// libgit2 asks again for as long as the server rejects what it got, so a
+// rejected token fails the fetch rather than being sent over and over
+fn callbacks<'a>(repo: Option<&'a Repository>, opts: &FetchOpts<'a>) -> git2::RemoteCallbacks<'a> {
+    let token_env = opts.token_env;
+    let deadline = opts.deadline;
+    let mut token_sent = false;
+    let mut cb = git2::RemoteCallbacks::new();
+    cb.transfer_progress(move |_| deadline.is_none_or(|d| Instant::now() < d));
+    cb.credentials(move |url, username, allowed| {
+        if allowed.contains(git2::CredentialType::SSH_KEY) {
+            return git2::Cred::ssh_key_from_agent(username.unwrap_or("git"));
+        }
+        if allowed.contains(git2::CredentialType::USER_PASS_PLAINTEXT) {
+            if let Some(token) = token_for(url, token_env, |v| env::var(v).ok()) {
+                if token_sent {
+                    return Err(git2::Error::new(
+                        ErrorCode::Auth,
+                        ErrorClass::Http,
+                        format!("the token for {url} was rejected"),
+                    ));
+                }
+                token_sent = true;
+                return git2::Cred::userpass_plaintext(TOKEN_USER, &token);
+            }
+        }
+        let config = match repo {
+            Some(r) => r.config()?,
+            None => git2::Config::open_default()?,
+        };
+        git2::Cred::credential_helper(&config, url, username)
+    });
+    cb
+}
+
+// the remote the current branch pulls from
+pub(crate) fn upstream_remote(repo: &Repository) -> Result<String, git2::Error> {
+    let head = repo.head()?;
+    let refname = head.name().unwrap_or_default();
+    let remote_name = repo.branch_upstream_remote(refname)?;
+    Ok(remote_name.as_str().unwrap_or("origin").to_string())
+}
+
+// the remote and tracking branch the current branch pulls from
+pub(crate) fn upstream(repo: &Repository) -> Option<(String, String)> {
+    let head = repo.head().ok()?;
+    let refname = head.name()?;
+    let remote = repo.branch_upstream_remote(refname).ok()?;
+    let branch = repo.branch_upstream_name(refname).ok()?;
+    let branch = branch.as_str()?;
+    let branch = branch.strip_prefix("refs/remotes/").unwrap_or(branch);
+    Some((remote.as_str()?.to_string(), branch.to_string()))
+}
+
+// Point the upstream remote at `url`, returns whether it had to change. With
+// `mutate` unset a differing url is an error instead.
+pub(crate) fn ensure_remote_url(
+    repo: &Repository,
+    name: &str,
+    url: &str,
+    mutate: bool,
+) -> Result<bool, git2::Error> {
+    let remote_name = upstream_remote(repo)?;
+    let remote = repo.find_remote(&remote_name)?;
+    let current = remote.url().unwrap_or_default();
+    if current == url {
+        return Ok(false);
+    }
+    if !mutate {
+        return Err(git2::Error::from_str(&format!(
+            "remote {remote_name:?} is {current:?} but remote_url is {url:?}, not changing it with --no-mutate-remotes"
+        )));
+    }
+    info!(
+        repo = name,
+        event = "remote_set_url",
+        "changing remote {} from {} to {}",
+        remote_name,
+        current,
+        url
+    );
+    repo.remote_set_url(&remote_name, url)?;
+    Ok(true)
+}
+
+// Fetch the upstream of the current branch, retrying transient failures
+pub(crate) fn fetch(repo: &Repository, opts: &FetchOpts<'_>) -> Result<(), git2::Error> {
+    let name = opts.name;
+    let retries = opts.retries;
+    let mut remote = repo.find_remote(&upstream_remote(repo)?)?;
+
+    // an override fetches through an anonymous remote into the same
+    // tracking refs, leaving the configured url untouched
+    let mut refspecs = Vec::new();
+    if let Some(spec) = opts.refspec {
+        refspecs.push(spec.to_string());
+    } else if opts.url.is_some() || opts.tags {
+        refspecs = remote
+            .fetch_refspecs()?
+            .iter()
+            .flatten()
+            .map(String::from)
+            .collect();
+    }
+    if opts.tags {
+        refspecs.push(TAG_REFSPEC.into());
+    }
+    if let Some(url) = opts.url {
+        info!(repo = name, event = "url_override", "fetching from {}", url);
+        remote = repo.remote_anonymous(url)?;
+    }
+    if let Some(allowed) = opts.allowed_url {
+        let url = remote.url().unwrap_or_default();
+        if !allowed.is_match(url) {
+            return Err(git2::Error::from_str(&format!(
+                "remote url {url:?} does not match allowed_remote_url {:?}",
+                allowed.as_str()
+            )));
+        }
+    }
+
+    let mut attempt = 0;
+    loop {
+        let mut fo = git2::FetchOptions::new();
+        fo.remote_callbacks(callbacks(Some(repo), opts));
+        if opts.tags {
+            fo.download_tags(git2::AutotagOption::All);
+        }
+        match remote.fetch(&refspecs, Some(&mut fo), None) {
+            Ok(()) if opts.tags && opts.prune_tags => return prune_tags(repo, &remote),
+            Ok(()) => return Ok(()),
+            Err(e) if e.class() == ErrorClass::Invalid && opts.refspec.is_some() => {
+                return Err(git2::Error::new(
+                    ErrorCode::InvalidSpec,
+                    ErrorClass::Invalid,
+                    format!("refspec rejected, {}", e.message()),
+                ));
+            }
+            Err(e) => {
+                let class = classify(&e);
+                let expired = opts.deadline.is_some_and(|d| Instant::now() >= d);
+                if class == Retry::Permanent || attempt >= retries || expired {
+                    warn!(
+                        repo = name,
+                        event = "fetch_failed",
+                        class = %class,
+                        attempt,
+                        "fetch failed, not retrying ({} error): {}",
+                        class,
+                        e
+                    );
+                    return Err(e);
+                }
+                attempt += 1;
+                warn!(
+                    repo = name,
+                    event = "fetch_retry",
+                    class = %class,
+                    attempt,
+                    "fetch failed, retrying ({} error): {}",
+                    class,
+                    e
+                );
+                thread::sleep(Duration::from_secs(u64::from(attempt)));
+            }
+        }
+    }
+}
+
+// Whether a remote answers, like `git ls-remote` but only connecting. The
+// probe runs on its own thread since connecting has no timeout of its own, a
+// probe that is given up on is left to finish in the background.
+pub(crate) fn probe_remote(
+    url: &str,
+    token_env: Option<&str>,
+    timeout: Duration,
+) -> Result<(), String> {
+    let url = url.to_string();
+    let token_env = token_env.map(String::from);
+    let (tx, rx) = mpsc::channel();
+    thread::spawn(move || {
+        let opts = FetchOpts {
+            name: "",
+            retries: 0,
+            token_env: token_env.as_deref(),
+            url: None,
+            deadline: None,
+            allowed_url: None,
+            tags: false,
+            prune_tags: false,
+            refspec: None,
+        };
+        let res = git2::Remote::create_detached(&url).and_then(|mut remote| {
+            remote
+                .connect_auth(git2::Direction::Fetch, Some(callbacks(None, &opts)), None)
+                .map(|_| ())
+        });
+        let _ = tx.send(res.map_err(|e| e.message().to_string()));
+    });
+    rx.recv_timeout(timeout)
+        .unwrap_or_else(|_| Err(format!("no answer after {timeout:?}")))
+}
+
+pub(crate) fn clone(
+    url: &str,
+    into: &Path,
+    token_env: Option<&str>,
+) -> Result<Repository, git2::Error> {
+    let opts = FetchOpts {
+        name: "",
+        retries: 0,
+        token_env,
+        url: None,
+        deadline: None,
+        allowed_url: None,
+        tags: false,
+        prune_tags: false,
+        refspec: None,
+    };
+    let mut fo = git2::FetchOptions::new();
+    fo.remote_callbacks(callbacks(None, &opts));
+    git2::build::RepoBuilder::new()
+        .fetch_options(fo)
+        .clone(url, into)
+}
+
+// Clone `url` through the shared object cache at `cache`, a bare repo
+// holding the objects of every repo cloned through it. Objects are fetched
+// into the cache (only what it's missing) and the clone gets an alternates
+// file pointing at it, plus refs and a checkout, so two clones of the same
+// repo store its history once. The catch: the clone now depends on the
+// cache. Deleting it, moving it or running `git gc --prune` in it breaks
+// every clone made through it; `git repack -a -d` in a clone copies what it
+// uses and cuts it loose.
+pub(crate) fn clone_cached(
+    url: &str,
+    into: &Path,
+    cache: &Path,
+    token_env: Option<&str>,
+) -> Result<Repository, git2::Error> {
+    let shared = Repository::open_bare(cache).or_else(|_| Repository::init_bare(cache))?;
+    let name = url
+        .chars()
+        .map(|c| if c.is_ascii_alphanumeric() { c } else { '_' })
+        .collect::<String>();
+    let mut remote = match shared.find_remote(&name) {
+        Ok(r) => r,
+        Err(_) => {
+            shared.remote_with_fetch(&name, url, &format!("+refs/heads/*:refs/remotes/{name}/*"))?
+        }
+    };
+    let opts = FetchOpts {
+        name: "",
+        retries: 0,
+        token_env,
+        url: None,
+        deadline: None,
+        allowed_url: None,
+        tags: false,
+        prune_tags: false,
+        refspec: None,
+    };
+    let mut fo = git2::FetchOptions::new();
+    fo.remote_callbacks(callbacks(None, &opts));
+    remote.fetch(&[] as &[&str], Some(&mut fo), None)?;
+    let prefix = format!("refs/remotes/{name}/");
+    let default = remote.default_branch().ok().and_then(|b| {
+        b.as_str()
+            .map(|b| b.trim_start_matches("refs/heads/").to_string())
+    });
+
+    let repo = Repository::init(into)?;
+    let alternates = repo.path().join("objects/info/alternates");
+    let objects = shared.path().join("objects");
+    std::fs::write(&alternates, format!("{}\n", objects.display()))
+        .map_err(|e| git2::Error::from_str(&format!("{}: {}", alternates.display(), e)))?;
+    // reopened so the object database sees the alternates
+    let repo = Repository::open(into)?;
+    repo.remote("origin", url)?;
+    let mut branches = Vec::new();
+    for r in shared.references_glob(&format!("{prefix}*"))? {
+        let r = r?;
+        if let (Some(full), Some(oid)) = (r.name(), r.target()) {
+            let branch = full.trim_start_matches(&prefix).to_string();
+            repo.reference(
+                &format!("refs/remotes/origin/{branch}"),
+                oid,
+                true,
+                "rema: clone from cache",
+            )?;
+            branches.push(branch);
+        }
+    }
+    let branch = default
+        .filter(|b| branches.contains(b))
+        .or_else(|| branches.first().cloned())
+        .ok_or_else(|| git2::Error::from_str(&format!("{url} has no branches")))?;
+    repo.reference_symbolic(
+        "refs/remotes/origin/HEAD",
+        &format!("refs/remotes/origin/{branch}"),
+        true,
+        "rema: clone from cache",
+    )?;
+    {
+        let commit = repo
+            .find_reference(&format!("refs/remotes/origin/{branch}"))?
+            .peel_to_commit()?;
+        repo.branch(&branch, &commit, false)?
+            .set_upstream(Some(&format!("origin/{branch}")))?;
+    }
+    repo.set_head(&format!("refs/heads/{branch}"))?;
+    repo.checkout_head(Some(git2::build::CheckoutBuilder::default().force()))?;
+    Ok(repo)
+}
+
+// Fast-forward the current branch to its upstream, returns whether HEAD moved
+pub(crate) fn fast_forward(repo: &Repository) -> Result<bool, git2::Error> {
+    let head = repo.head()?;
+    let refname = head.name().unwrap_or_default().to_string();
+    let upstream = repo.branch_upstream_name(&refname)?;
+    let target = repo.refname_to_id(upstream.as_str().unwrap_or_default())?;
+    let annotated = repo.find_annotated_commit(target)?;
+
+    let (analysis, _) = repo.merge_analysis(&[&annotated])?;
+    if analysis.is_up_to_date() {
+        return Ok(false);
+    }
+    if !analysis.is_fast_forward() {
+        return Err(git2::Error::new(
+            ErrorCode::NotFastForward,
+            ErrorClass::Merge,
+            format!("{refname} cannot be fast-forwarded to its upstream"),
+        ));
+    }
+
+    // a safe checkout keeps local edits to files upstream didn't touch and
+    // fails before moving the branch on any it did, like git pull
+    let tree = repo.find_commit(target)?.tree()?;
+    repo.checkout_tree(
+        tree.as_object(),
+        Some(git2::build::CheckoutBuilder::default().safe()),
+    )?;
+    repo.find_reference(&refname)?
+        .set_target(target, "rema: fast-forward")?;
+    Ok(true)
+}
+
+// Where HEAD was before checkout_detached
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) enum Head {
+    Branch(String),
+    Detached(git2::Oid),
+}
+
+// Check out `rev` with a detached HEAD, returns where HEAD was and the commit
+// now checked out
+pub(crate) fn checkout_detached(
+    repo: &Repository,
+    rev: &str,
+) -> Result<(Head, git2::Oid), git2::Error> {
+    let head = repo.head()?;
+    let prev = match head.name() {
+        Some(name) if head.is_branch() => Head::Branch(name.to_string()),
+        _ => Head::Detached(head.peel_to_commit()?.id()),
+    };
+    let commit = repo.revparse_single(rev)?.peel_to_commit()?;
+    repo.checkout_tree(
+        commit.as_object(),
+        Some(git2::build::CheckoutBuilder::default().safe()),
+    )?;
+    repo.set_head_detached(commit.id())?;
+    Ok((prev, commit.id()))
+}
+
+// Check a detached HEAD back out on `branch`, or the remote's default
+// branch, creating it from the remote's if there's no local one; returns the
+// branch
+pub(crate) fn reattach(repo: &Repository, branch: Option<&str>) -> Result<String, git2::Error> {
+    let remote = reattach_remote(repo, branch)?;
+    let branch = match branch {
+        Some(b) => b.to_string(),
+        None => default_branch(repo, &remote)?,
+    };
+    let local = match repo.find_branch(&branch, git2::BranchType::Local) {
+        Ok(b) => b,
+        Err(e) if e.code() == ErrorCode::NotFound => {
+            let tracking = format!("{remote}/{branch}");
+            let commit = repo
+                .find_branch(&tracking, git2::BranchType::Remote)?
+                .get()
+                .peel_to_commit()?;
+            let mut b = repo.branch(&branch, &commit, false)?;
+            b.set_upstream(Some(&tracking))?;
+            b
+        }
+        Err(e) => return Err(e),
+    };
+    let refname = local
+        .get()
+        .name()
+        .ok_or_else(|| git2::Error::from_str("branch name is not utf-8"))?
+        .to_string();
+    repo.checkout_tree(
+        &local.get().peel(git2::ObjectType::Commit)?,
+        Some(git2::build::CheckoutBuilder::default().safe()),
+    )?;
+    repo.set_head(&refname)?;
+    Ok(branch)
+}
+
+// the remote `branch` is configured to pull from, else origin or the only
+// remote there is
+fn reattach_remote(repo: &Repository, branch: Option<&str>) -> Result<String, git2::Error> {
+    let config = repo.config()?;
+    if let Some(r) = branch.and_then(|b| config.get_string(&format!("branch.{b}.remote")).ok()) {
+        return Ok(r);
+    }
+    let remotes = repo.remotes()?;
+    let names = remotes.iter().flatten().collect::<Vec<_>>();
+    match names.as_slice() {
+        [only] => Ok((*only).to_string()),
+        _ if names.contains(&"origin") => Ok("origin".into()),
+        _ => Err(git2::Error::from_str(
+            "no remote to find the default branch from, set expected_branch",
+        )),
+    }
+}
+
+// what refs/remotes/<remote>/HEAD points at, set by the clone
+fn default_branch(repo: &Repository, remote: &str) -> Result<String, git2::Error> {
+    let head = repo.find_reference(&format!("refs/remotes/{remote}/HEAD"))?;
+    let target = head.symbolic_target().unwrap_or_default();
+    target
+        .strip_prefix(&format!("refs/remotes/{remote}/"))
+        .map(String::from)
+        .ok_or_else(|| git2::Error::from_str(&format!("{remote} has no default branch")))
+}
+
+// undo checkout_detached, changes a build made to tracked files are lost
+pub(crate) fn restore_head(repo: &Repository, prev: &Head) -> Result<(), git2::Error> {
+    match prev {
+        Head::Branch(name) => repo.set_head(name)?,
+        Head::Detached(oid) => repo.set_head_detached(*oid)?,
+    }
+    repo.checkout_head(Some(git2::build::CheckoutBuilder::default().force()))
+}
+
+// Paths touched by `changes`, both sides of renames are included
+pub(crate) fn changed_paths(changes: &[FileChange]) -> Vec<PathBuf> {
+    let mut paths = Vec::new();
+    for c in changes {
+        let from = c.old_path.as_ref().filter(|_| c.status == 'R');
+        for p in from.into_iter().chain(std::iter::once(&c.path)) {
+            if !paths.contains(p) {
+                paths.push(p.clone());
+            }
+        }
+    }
+    paths
+}
+
+// Paths in the index, relative to the working tree
+pub(crate) fn tracked_files(repo: &Repository) -> Result<Vec<PathBuf>, git2::Error> {
+    Ok(repo
+        .index()?
+        .iter()
+        .map(|e| PathBuf::from(String::from_utf8_lossy(&e.path).into_owned()))
+        .collect())
+}
+
+// Where each submodule of `repo` is checked out, with symlinks resolved;
+// ones not checked out are left out
+pub(crate) fn submodule_dirs(repo: &Repository) -> Result<Vec<PathBuf>, git2::Error> {
+    let Some(workdir) = repo.workdir() else {
+        return Ok(Vec::new());
+    };
+    Ok(repo
+        .submodules()?
+        .iter()
+        .filter_map(|s| workdir.join(s.path()).canonicalize().ok())
+        .collect())
+}
+
+// One hash over the contents of `paths` under `root` as they are on disk,
+// using git's blob hashing so nothing has to be staged; a missing file still
+// counts, as missing
+pub(crate) fn hash_files(root: &Path, paths: &[PathBuf]) -> Result<git2::Oid, git2::Error> {
+    let mut listing = String::new();
+    for p in paths {
+        let oid = git2::Oid::hash_file(git2::ObjectType::Blob, root.join(p))
+            .map_or_else(|_| "-".into(), |o| o.to_string());
+        let _ = writeln!(listing, "{}\0{}", p.display(), oid);
+    }
+    git2::Oid::hash_object(git2::ObjectType::Blob, listing.as_bytes())
+}
+
+const TAG_REFSPEC: &str = "+refs/tags/*:refs/tags/*";
+
+// Delete the tags `remote` didn't advertise on its last fetch. Only tags,
+// a FetchPrune would take the remote-tracking branches along.
+fn prune_tags(repo: &Repository, remote: &git2::Remote<'_>) -> Result<(), git2::Error> {
+    let advertised = remote
+        .list()?
+        .iter()
+        .map(|h| h.name().trim_end_matches("^{}").to_string())
+        .collect::<BTreeSet<_>>();
+    let gone = repo
+        .references_glob("refs/tags/*")?
+        .filter_map(|r| r.ok()?.name().map(String::from))
+        .filter(|name| !advertised.contains(name))
+        .collect::<Vec<_>>();
+    for name in gone {
+        repo.find_reference(&name)?.delete()?;
+    }
+    Ok(())
+}
+
+// tag name -> the object it points at
+pub(crate) fn tags(repo: &Repository) -> Result<BTreeMap<String, git2::Oid>, git2::Error> {
+    let mut out = BTreeMap::new();
+    for r in repo.references_glob("refs/tags/*")? {
+        let r = r?;
+        if let (Some(name), Some(oid)) = (r.shorthand(), r.target()) {
+            out.insert(name.to_string(), oid);
+        }
+    }
+    Ok(out)
+}
+
+// What a fetch did to a repo's tags, by name
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
+pub(crate) struct TagUpdates {
+    pub(crate) created: Vec<String>,
+    // moved to another object
+    pub(crate) updated: Vec<String>,
+    pub(crate) deleted: Vec<String>,
+}
+
+impl TagUpdates {
+    pub(crate) fn between(
+        before: &BTreeMap<String, git2::Oid>,
+        after: &BTreeMap<String, git2::Oid>,
+    ) -> Self {
+        let mut u = Self::default();
+        for (name, oid) in after {
+            match before.get(name) {
+                None => u.created.push(name.clone()),
+                Some(old) if old != oid => u.updated.push(name.clone()),
+                Some(_) => {}
+            }
+        }
+        u.deleted = before
+            .keys()
+            .filter(|name| !after.contains_key(*name))
+            .cloned()
+            .collect();
+        u
+    }
+
+    pub(crate) fn is_empty(&self) -> bool {
+        self.created.is_empty() && self.updated.is_empty() && self.deleted.is_empty()
+    }
+
+    // the first created or moved tag matching `pattern`
+    pub(crate) fn matching(&self, pattern: &glob::Pattern) -> Option<&str> {
+        self.created
+            .iter()
+            .chain(&self.updated)
+            .map(String::as_str)
+            .find(|t| pattern.matches(t))
+    }
+}
+
+// "created v1.2, v1.3; deleted v1.2-rc1"
+impl fmt::Display for TagUpdates {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let parts = [
+            ("created", &self.created),
+            ("updated", &self.updated),
+            ("deleted", &self.deleted),
+        ];
+        let mut sep = "";
+        for (what, names) in parts {
+            if !names.is_empty() {
+                write!(f, "{sep}{what} {}", names.join(", "))?;
+                sep = "; ";
+            }
+        }
+        Ok(())
+    }
+}
+
+// A path touched between two commits with its status letter, as in
+// `git diff --name-status`
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub(crate) struct FileChange {
+    pub(crate) status: char,
+    pub(crate) path: PathBuf,
+    // where a renamed or copied file came from
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) old_path: Option<PathBuf>,
+}
+
+pub(crate) fn file_changes(
+    repo: &Repository,
+    old: git2::Oid,
+    new: git2::Oid,
+) -> Result<Vec<FileChange>, git2::Error> {
+    let old_tree = repo.find_commit(old)?.tree()?;
+    let new_tree = repo.find_commit(new)?.tree()?;
+    let mut diff = repo.diff_tree_to_tree(Some(&old_tree), Some(&new_tree), None)?;
+    diff.find_similar(None)?;
+
+    Ok(diff
+        .deltas()
+        .filter_map(|delta| {
+            let status = match delta.status() {
+                Delta::Added => 'A',
+                Delta::Deleted => 'D',
+                Delta::Modified => 'M',
+                Delta::Renamed => 'R',
+                Delta::Copied => 'C',
+                Delta::Typechange => 'T',
+                _ => '?',
+            };
+            let file = if status == 'D' {
+                delta.old_file()
+            } else {
+                delta.new_file()
+            };
+            let old_path = match status {
+                'R' | 'C' => delta.old_file().path().map(Path::to_path_buf),
+                _ => None,
+            };
+            Some(FileChange {
+                status,
+                path: file.path()?.to_path_buf(),
+                old_path,
+            })
+        })
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::tests::commit_all;
+
+    use std::fs;
+
+    fn vars(pairs: &[(&str, &str)]) -> impl Fn(&str) -> Option<String> {
+        let pairs = pairs
+            .iter()
+            .map(|(k, v)| ((*k).to_string(), (*v).to_string()))
+            .collect::<Vec<_>>();
+        move |k| pairs.iter().find(|(n, _)| n == k).map(|(_, v)| v.clone())
+    }
+
+    #[test]
+    fn test_token_for_https() {
+        let env = vars(&[(TOKEN_VAR, "global")]);
+        let url = "https://github.com/0jdxt/rema.git";
+        assert_eq!(token_for(url, None, &env).as_deref(), Some("global"));
+        // ssh remotes never use the token
+        assert_eq!(token_for("git@github.com:0jdxt/rema.git", None, &env), None);
+        // nor plain http
+        assert_eq!(
+            token_for("http://github.com/0jdxt/rema.git", None, &env),
+            None
+        );
+    }
+
+    #[test]
+    fn test_token_for_precedence() {
+        let env = vars(&[
+            (TOKEN_VAR, "global"),
+            ("CI_TOKEN", "repo"),
+            ("REMA_TOKEN_GIT_EXAMPLE_COM", "host"),
+        ]);
+        let url = "https://user@git.example.com:8443/team/repo.git";
+        assert_eq!(
+            token_for(url, Some("CI_TOKEN"), &env).as_deref(),
+            Some("host")
+        );
+
+        let other = "https://github.com/team/repo.git";
+        assert_eq!(
+            token_for(other, Some("CI_TOKEN"), &env).as_deref(),
+            Some("repo")
+        );
+        assert_eq!(token_for(other, Some("UNSET"), &env), None);
+    }
+
+    #[test]
+    fn test_valid_url() {
+        for url in &[
+            "https://github.com/someone/rema.git",
+            "ssh://git@host:22/repo",
+            "git@github.com:someone/rema.git",
+            "file:///srv/git/rema",
+            "/srv/git/rema",
+        ] {
+            assert!(valid_url(url), "{}", url);
+        }
+        for url in &["", "https://", "rema", "./relative/path", "https://a b"] {
+            assert!(!valid_url(url), "{}", url);
+        }
+    }
+
+    #[test]
+    fn test_classify_permanent() {
+        let cases = [
+            (ErrorCode::Auth, ErrorClass::Http),
+            (ErrorCode::NotFound, ErrorClass::Reference),
+            (ErrorCode::Certificate, ErrorClass::Ssl),
+            (ErrorCode::InvalidSpec, ErrorClass::Reference),
+            (ErrorCode::GenericError, ErrorClass::Config),
+            (ErrorCode::GenericError, ErrorClass::Os),
+        ];
+        for (code, class) in &cases {
+            let e = git2::Error::new(*code, *class, "boom");
+            assert_eq!(classify(&e), Retry::Permanent, "{code:?} {class:?}");
+        }
+    }
+
+    #[test]
+    fn test_classify_transient() {
+        let cases = [
+            (
+                ErrorCode::GenericError,
+                ErrorClass::Net,
+                "connection refused",
+            ),
+            (ErrorCode::GenericError, ErrorClass::Http, "502 bad gateway"),
+            (ErrorCode::GenericError, ErrorClass::Ssh, "kex failed"),
+            (ErrorCode::Eof, ErrorClass::Net, "early eof"),
+            (
+                ErrorCode::GenericError,
+                ErrorClass::Callback,
+                "operation timed out",
+            ),
+        ];
+        for (code, class, msg) in &cases {
+            let e = git2::Error::new(*code, *class, msg);
+            assert_eq!(classify(&e), Retry::Transient, "{code:?} {class:?}");
+        }
+    }
+
+    #[test]
+    fn test_file_changes() {
+        let dir = tempfile::tempdir().unwrap();
+        let repo = Repository::init(dir.path()).unwrap();
+        fs::write(dir.path().join("a.txt"), "a").unwrap();
+        fs::write(dir.path().join("b.txt"), "b").unwrap();
+        let old = commit_all(&repo, "one");
+
+        fs::write(dir.path().join("a.txt"), "a2").unwrap();
+        fs::remove_file(dir.path().join("b.txt")).unwrap();
+        fs::write(dir.path().join("c.txt"), "c").unwrap();
+        let mut index = repo.index().unwrap();
+        index.remove_path(Path::new("b.txt")).unwrap();
+        index.write().unwrap();
+        let new = commit_all(&repo, "two");
+
+        let changes = file_changes(&repo, old, new).unwrap();
+        let changes = changes
+            .iter()
+            .map(|c| (c.status, c.path.to_str().unwrap()))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            changes,
+            vec![('M', "a.txt"), ('D', "b.txt"), ('A', "c.txt")]
+        );
+    }
+
+    #[test]
+    fn test_checkout_detached() {
+        let dir = tempfile::tempdir().unwrap();
+        let repo = Repository::init(dir.path()).unwrap();
+        fs::write(dir.path().join("v"), "1").unwrap();
+        let one = commit_all(&repo, "one");
+        fs::write(dir.path().join("v"), "2").unwrap();
+        commit_all(&repo, "two");
+        let branch = repo.head().unwrap().name().unwrap().to_string();
+
+        let (prev, at) = checkout_detached(&repo, &one.to_string()).unwrap();
+        assert_eq!((prev.clone(), at), (Head::Branch(branch.clone()), one));
+        assert!(repo.head_detached().unwrap());
+        assert_eq!(fs::read_to_string(dir.path().join("v")).unwrap(), "1");
+
+        restore_head(&repo, &prev).unwrap();
+        assert_eq!(repo.head().unwrap().name(), Some(branch.as_str()));
+        assert_eq!(fs::read_to_string(dir.path().join("v")).unwrap(), "2");
+    }
+
+    #[test]
+    fn test_file_changes_rename() {
+        let dir = tempfile::tempdir().unwrap();
+        let repo = Repository::init(dir.path()).unwrap();
+        fs::write(dir.path().join("vimrc"), "set number\nset hidden\n").unwrap();
+        let old = commit_all(&repo, "one");
+
+        fs::create_dir(dir.path().join("vim")).unwrap();
+        fs::rename(dir.path().join("vimrc"), dir.path().join("vim/vimrc")).unwrap();
+        let mut index = repo.index().unwrap();
+        index.remove_path(Path::new("vimrc")).unwrap();
+        index.write().unwrap();
+        let new = commit_all(&repo, "move");
+
+        assert_eq!(
+            file_changes(&repo, old, new).unwrap(),
+            vec![FileChange {
+                status: 'R',
+                path: "vim/vimrc".into(),
+                old_path: Some("vimrc".into()),
+            }]
+        );
+        assert_eq!(
+            changed_paths(&file_changes(&repo, old, new).unwrap()),
+            vec![PathBuf::from("vimrc"), PathBuf::from("vim/vimrc")]
+        );
+    }
+
+    #[test]
+    fn test_clone_cached_shares_objects() {
+        let upstream = tempfile::tempdir().unwrap();
+        let repo = Repository::init(upstream.path()).unwrap();
+        fs::write(upstream.path().join("v"), "1").unwrap();
+        let head = commit_all(&repo, "one");
+        let url = upstream.path().to_str().unwrap();
+
+        let dir = tempfile::tempdir().unwrap();
+        let cache = dir.path().join("cache");
+        let mut clones = Vec::new();
+        for name in ["a", "b"] {
+            let clone = clone_cached(url, &dir.path().join(name), &cache, None).unwrap();
+            assert_eq!(clone.head().unwrap().target(), Some(head));
+            assert_eq!(
+                fs::read_to_string(dir.path().join(name).join("v")).unwrap(),
+                "1"
+            );
+            clones.push(clone);
+        }
+        for clone in &clones {
+            let objects = clone.path().join("objects");
+            assert_eq!(
+                fs::read_to_string(objects.join("info/alternates"))
+                    .unwrap()
+                    .trim(),
+                cache.join("objects").display().to_string()
+            );
+            // nothing of their own besides info/ and pack/, both empty
+            let own = fs::read_dir(&objects)
+                .unwrap()
+                .map(|e| e.unwrap().file_name().into_string().unwrap())
+                .filter(|n| n != "info" && n != "pack")
+                .collect::<Vec<_>>();
+            assert!(own.is_empty(), "{:?}", own);
+            assert_eq!(fs::read_dir(objects.join("pack")).unwrap().count(), 0);
+        }
+        let upstream_branch = repo.head().unwrap().shorthand().unwrap().to_string();
+        assert_eq!(
+            clones[0].head().unwrap().shorthand(),
+            Some(upstream_branch.as_str())
+        );
+    }
+
+    #[test]
+    fn test_probe_remote() {
+        let dir = tempfile::tempdir().unwrap();
+        Repository::init(dir.path()).unwrap();
+        let url = dir.path().to_str().unwrap();
+        assert_eq!(probe_remote(url, None, Duration::from_secs(5)), Ok(()));
+
+        let missing = dir.path().join("missing");
+        assert!(probe_remote(missing.to_str().unwrap(), None, Duration::from_secs(5)).is_err());
+    }
+}
@@ -0,0 +1,155 @@
+use crate::report::{self, Outcome, Summary};
+
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use serde::{Deserialize, Serialize};
+
+// runs kept per repo when the config doesn't set history_len
+pub(crate) const DEFAULT_LEN: usize = 20;
+
+// One repo's outcome in one run, kept in the state for `rema history`
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub(crate) struct Run {
+    // seconds since the epoch
+    pub(crate) at: u64,
+    // the subcommand, pull, update or rebuild
+    pub(crate) action: String,
+    pub(crate) outcome: Outcome,
+    // in seconds
+    pub(crate) duration: f64,
+}
+
+// repo name -> its runs, oldest first
+pub(crate) type History = BTreeMap<String, Vec<Run>>;
+
+pub(crate) fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+// add every repo of a run, dropping the oldest runs past `len`
+pub(crate) fn record(history: &mut History, summary: &Summary, action: &str, at: u64, len: usize) {
+    for (name, outcome, duration) in summary.outcomes() {
+        let runs = history.entry(name.into()).or_default();
+        runs.push(Run {
+            at,
+            action: action.into(),
+            outcome,
+            duration: duration.as_secs_f64(),
+        });
+        let over = runs.len().saturating_sub(len);
+        runs.drain(..over);
+    }
+    history.retain(|_, runs| !runs.is_empty());
+}
+
+// the last `last` runs of `repo`, or of every repo
+pub(crate) fn select(history: &History, repo: Option<&str>, last: Option<usize>) -> History {
+    history
+        .iter()
+        .filter(|(name, _)| repo.is_none_or(|r| r == name.as_str()))
+        .map(|(name, runs)| {
+            let skip = last.map_or(0, |n| runs.len().saturating_sub(n));
+            (name.clone(), runs[skip..].to_vec())
+        })
+        .collect()
+}
+
+// app
+//   2026-10-14 09:12:03  pull     updated         12.4s
+pub(crate) fn render(history: &History) -> String {
+    if history.is_empty() {
+        return "no runs recorded yet\n".into();
+    }
+    let mut out = String::new();
+    for (name, runs) in history {
+        let _ = writeln!(out, "{name}");
+        for r in runs {
+            let _ = writeln!(
+                out,
+                "  {}  {:<8} {:<15} {:.1}s",
+                utc(r.at),
+                r.action,
+                report::outcome_name(r.outcome),
+                r.duration
+            );
+        }
+    }
+    out
+}
+
+// "YYYY-MM-DD HH:MM:SS" from seconds since the epoch, days to a civil date
+// as in http://howardhinnant.github.io/date_algorithms.html
+fn utc(at: u64) -> String {
+    let (days, secs) = (at / 86400, at % 86400);
+    let z = days + 719_468;
+    let era = z / 146_097;
+    let doe = z % 146_097;
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + u64::from(month <= 2);
+    format!(
+        "{year:04}-{month:02}-{day:02} {:02}:{:02}:{:02}",
+        secs / 3600,
+        secs / 60 % 60,
+        secs % 60
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn run(at: u64) -> Run {
+        Run {
+            at,
+            action: "pull".into(),
+            outcome: Outcome::Updated,
+            duration: 1.5,
+        }
+    }
+
+    #[test]
+    fn test_record_caps_length() {
+        let mut summary = Summary::default();
+        summary.record_missing("app");
+        let mut history = History::new();
+        for at in 0..4 {
+            record(&mut history, &summary, "pull", at, 3);
+        }
+        let kept = history["app"].iter().map(|r| r.at).collect::<Vec<_>>();
+        assert_eq!(kept, [1, 2, 3]);
+        assert_eq!(history["app"][0].outcome, Outcome::Missing);
+    }
+
+    #[test]
+    fn test_select_last() {
+        let mut history = History::new();
+        history.insert("app".into(), (0..5).map(run).collect());
+        history.insert("lib".into(), vec![run(9)]);
+
+        let picked = select(&history, Some("app"), Some(2));
+        assert_eq!(picked.len(), 1);
+        assert_eq!(picked["app"], [run(3), run(4)]);
+        assert_eq!(select(&history, None, None), history);
+        assert!(select(&history, Some("gone"), None).is_empty());
+    }
+
+    #[test]
+    fn test_render() {
+        let mut history = History::new();
+        history.insert("app".into(), vec![run(1_791_969_123)]);
+        assert_eq!(
+            render(&history),
+            "app\n  2026-10-14 09:12:03  pull     updated         1.5s\n"
+        );
+        assert_eq!(render(&History::new()), "no runs recorded yet\n");
+    }
+}
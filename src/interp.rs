@@ -0,0 +1,162 @@
+use tracing::debug;
+
+// Expand `${VAR}` in every string of a parsed config, `$$` is a literal `$`.
+// A `$` followed by anything else is left alone so shell syntax like `$HOME`
+// reaches the command untouched.
+pub(crate) fn expand_value<F>(v: &mut toml::Value, strict: bool, lookup: &F) -> Result<(), String>
+where
+    F: Fn(&str) -> Option<String>,
+{
+    match v {
+        toml::Value::String(s) => *s = expand(s, strict, lookup)?,
+        toml::Value::Array(a) => {
+            for v in a {
+                expand_value(v, strict, lookup)?;
+            }
+        }
+        toml::Value::Table(t) => {
+            for (_, v) in t.iter_mut() {
+                expand_value(v, strict, lookup)?;
+            }
+        }
+        _ => {}
+    }
+    Ok(())
+}
+
+// undefined variables are an error when strict and empty otherwise
+pub(crate) fn expand<F>(s: &str, strict: bool, lookup: &F) -> Result<String, String>
+where
+    F: Fn(&str) -> Option<String>,
+{
+    let mut out = String::with_capacity(s.len());
+    let mut rest = s;
+    while let Some(i) = rest.find('$') {
+        out.push_str(&rest[..i]);
+        rest = &rest[i..];
+        if let Some(r) = rest.strip_prefix("$$") {
+            out.push('$');
+            rest = r;
+        } else if let Some(r) = rest.strip_prefix("${") {
+            let end = r
+                .find('}')
+                .ok_or_else(|| format!("unclosed ${{ in {s:?}"))?;
+            let name = &r[..end];
+            match lookup(name) {
+                Some(val) => out.push_str(&val),
+                None if strict => return Err(format!("undefined variable {name:?} in {s:?}")),
+                None => debug!("undefined variable {:?}, expanding to empty", name),
+            }
+            rest = &r[end + 1..];
+        } else {
+            out.push('$');
+            rest = &rest[1..];
+        }
+    }
+    out.push_str(rest);
+    Ok(out)
+}
+
+// Fill `{name}` placeholders in a command line for --build-arg, an undefined
+// one is an error. `{{` and `}}` are literal braces, braces around anything
+// that isn't a name, like find's `{}`, are left alone.
+pub(crate) fn fill<F>(s: &str, lookup: &F) -> Result<String, String>
+where
+    F: Fn(&str) -> Option<String>,
+{
+    let mut out = String::with_capacity(s.len());
+    let mut rest = s;
+    while let Some(i) = rest.find(['{', '}']) {
+        out.push_str(&rest[..i]);
+        rest = &rest[i..];
+        if let Some(r) = rest.strip_prefix("{{") {
+            out.push('{');
+            rest = r;
+        } else if let Some(r) = rest.strip_prefix("}}") {
+            out.push('}');
+            rest = r;
+        } else if let Some((name, r)) = placeholder(rest) {
+            let val =
+                lookup(name).ok_or_else(|| format!("undefined build arg {name:?} in {s:?}"))?;
+            out.push_str(&val);
+            rest = r;
+        } else {
+            out.push_str(&rest[..1]);
+            rest = &rest[1..];
+        }
+    }
+    out.push_str(rest);
+    Ok(out)
+}
+
+// a `{name}` starting `s`, and what's after it
+fn placeholder(s: &str) -> Option<(&str, &str)> {
+    let r = s.strip_prefix('{')?;
+    let end = r.find('}')?;
+    let name = &r[..end];
+    let valid = name.starts_with(|c: char| c.is_ascii_alphabetic() || c == '_')
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-');
+    valid.then(|| (name, &r[end + 1..]))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn lookup(name: &str) -> Option<String> {
+        match name {
+            "HOME" => Some("/home/me".into()),
+            "CI_COMMIT_SHA" => Some("abc123".into()),
+            _ => None,
+        }
+    }
+
+    #[test]
+    fn test_expand() {
+        assert_eq!(
+            expand("make DEST=${HOME}/bin", false, &lookup),
+            Ok("make DEST=/home/me/bin".into())
+        );
+        assert_eq!(expand("cost $$5", false, &lookup), Ok("cost $5".into()));
+        assert_eq!(
+            expand("echo $HOME", false, &lookup),
+            Ok("echo $HOME".into())
+        );
+        assert_eq!(expand("a${NOPE}b", false, &lookup), Ok("ab".into()));
+        assert!(expand("a${NOPE}b", true, &lookup).is_err());
+        assert!(expand("a${HOME", false, &lookup).is_err());
+    }
+
+    #[test]
+    fn test_fill() {
+        let args = |name: &str| (name == "profile").then(|| "--release".to_string());
+        assert_eq!(
+            fill("cargo build {profile}", &args),
+            Ok("cargo build --release".into())
+        );
+        assert_eq!(
+            fill("find . -exec rm {} ;", &args),
+            Ok("find . -exec rm {} ;".into())
+        );
+        assert_eq!(fill("echo {{profile}}", &args), Ok("echo {profile}".into()));
+        assert!(fill("make {target}", &args).is_err());
+    }
+
+    #[test]
+    fn test_expand_value() {
+        let mut v: toml::Value = toml::from_str(
+            r#"
+                tags = ["build-${CI_COMMIT_SHA}"]
+                build = [{ run = "cp out ${HOME}" }]
+                pull_retries = 2
+            "#,
+        )
+        .unwrap();
+        expand_value(&mut v, true, &lookup).unwrap();
+        assert_eq!(v["tags"][0].as_str(), Some("build-abc123"));
+        assert_eq!(v["build"][0]["run"].as_str(), Some("cp out /home/me"));
+        assert_eq!(v["pull_retries"].as_integer(), Some(2));
+    }
+}
@@ -0,0 +1,77 @@
+use std::thread;
+
+// --concurrency-from-load, caps how many builds run at once by the system
+// load average. Up to `target` every job slot is used; above it the slots
+// shrink in proportion, jobs * target / load, but never below one so a busy
+// machine still gets through its repos. Re-read before each build starts.
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct LoadCap {
+    pub(crate) target: f64,
+    // the one minute load average, None when it can't be read
+    pub(crate) source: fn() -> Option<f64>,
+}
+
+impl LoadCap {
+    // the target defaults to the number of cores, a fully busy machine
+    pub(crate) fn new(target: Option<f64>) -> Self {
+        #[allow(clippy::cast_precision_loss)]
+        let cores = thread::available_parallelism().map_or(1, usize::from) as f64;
+        Self {
+            target: target.unwrap_or(cores),
+            source: system_load,
+        }
+    }
+
+    pub(crate) fn allowed(&self, jobs: usize) -> usize {
+        match (self.source)() {
+            Some(load) if load > self.target => {
+                #[allow(
+                    clippy::cast_precision_loss,
+                    clippy::cast_possible_truncation,
+                    clippy::cast_sign_loss
+                )]
+                let n = (jobs as f64 * self.target / load) as usize;
+                n.clamp(1, jobs)
+            }
+            _ => jobs,
+        }
+    }
+}
+
+// clap validator
+#[allow(clippy::needless_pass_by_value)]
+pub(crate) fn validate(s: String) -> Result<(), String> {
+    match s.parse::<f64>() {
+        Ok(n) if n > 0.0 => Ok(()),
+        _ => Err(format!("expected a positive load average, got {s:?}")),
+    }
+}
+
+#[cfg(unix)]
+fn system_load() -> Option<f64> {
+    let mut avg = [0.0];
+    let n = unsafe { libc::getloadavg(avg.as_mut_ptr(), 1) };
+    (n == 1).then_some(avg[0])
+}
+
+#[cfg(not(unix))]
+fn system_load() -> Option<f64> {
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_allowed() {
+        let cap = |source| LoadCap {
+            target: 4.0,
+            source,
+        };
+        assert_eq!(cap(|| Some(2.0)).allowed(8), 8);
+        assert_eq!(cap(|| Some(8.0)).allowed(8), 4);
+        assert_eq!(cap(|| Some(100.0)).allowed(8), 1);
+        assert_eq!(cap(|| None).allowed(8), 8);
+    }
+}
@@ -0,0 +1,149 @@
+use std::convert::TryFrom;
+use std::fs::{self, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+
+use tracing::warn;
+
+const FILE_NAME: &str = "rema.lock";
+
+// Held in the state dir while a pull, update, rebuild, build or clean runs so
+// two remas don't touch the same repos at once or race on the state file. The lockfile has
+// the holder's pid and is removed on drop, unless another rema has taken it
+// over since.
+#[derive(Debug)]
+pub(crate) struct Lock {
+    path: PathBuf,
+}
+
+impl Lock {
+    // a lock left by a pid that isn't running is taken over, `force` for
+    // --ignore-lockfile takes it over whoever holds it
+    pub(crate) fn acquire(dir: &Path, force: bool) -> Result<Self, failure::Error> {
+        fs::create_dir_all(dir)?;
+        let path = dir.join(FILE_NAME);
+        match create(&path) {
+            Ok(()) => {}
+            Err(e) if e.kind() == io::ErrorKind::AlreadyExists => {
+                let holder = fs::read_to_string(&path)?.trim().parse::<u32>().ok();
+                match holder {
+                    _ if force => warn!(
+                        event = "lock_overridden",
+                        "IGNORING LOCKFILE {} held by pid {}, another rema may still be running",
+                        path.display(),
+                        holder.map_or_else(|| "unknown".into(), |p| p.to_string())
+                    ),
+                    Some(p) if is_running(p) => failure::bail!(
+                        "another rema (pid {}) holds {}, pass --ignore-lockfile if it isn't running",
+                        p,
+                        path.display()
+                    ),
+                    _ => warn!(
+                        event = "lock_stale",
+                        "taking over stale lockfile {}",
+                        path.display()
+                    ),
+                }
+                // removed and created again rather than overwritten, so of two
+                // remas taking it over at once only one gets it
+                match fs::remove_file(&path) {
+                    Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e.into()),
+                    _ => {}
+                }
+                if let Err(e) = create(&path) {
+                    if e.kind() == io::ErrorKind::AlreadyExists {
+                        failure::bail!("another rema took over {} first", path.display());
+                    }
+                    return Err(e.into());
+                }
+            }
+            Err(e) => return Err(e.into()),
+        }
+        Ok(Self { path })
+    }
+}
+
+// the lockfile with our pid, failing if it exists
+fn create(path: &Path) -> io::Result<()> {
+    let mut f = OpenOptions::new().write(true).create_new(true).open(path)?;
+    writeln!(f, "{}", std::process::id())
+}
+
+impl Drop for Lock {
+    fn drop(&mut self) {
+        let ours = std::process::id().to_string();
+        match fs::read_to_string(&self.path) {
+            Ok(held) if held.trim() == ours => {}
+            Ok(held) => {
+                warn!(
+                    "not removing {}, pid {} holds it now",
+                    self.path.display(),
+                    held.trim()
+                );
+                return;
+            }
+            // already gone, e.g. removed by whoever took it over
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return,
+            Err(e) => {
+                warn!("could not read {}: {}", self.path.display(), e);
+                return;
+            }
+        }
+        if let Err(e) = fs::remove_file(&self.path) {
+            warn!("could not remove {}: {}", self.path.display(), e);
+        }
+    }
+}
+
+// signal 0 only checks the pid exists, EPERM means it does but isn't ours
+#[cfg(unix)]
+fn is_running(pid: u32) -> bool {
+    let Ok(pid) = libc::pid_t::try_from(pid) else {
+        return false;
+    };
+    let alive = unsafe { libc::kill(pid, 0) } == 0;
+    alive || io::Error::last_os_error().raw_os_error() == Some(libc::EPERM)
+}
+
+// no cheap way to tell, so never treat a lock as stale
+#[cfg(not(unix))]
+fn is_running(_pid: u32) -> bool {
+    true
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_lock() {
+        let dir = tempfile::tempdir().unwrap();
+        let lock = Lock::acquire(dir.path(), false).unwrap();
+        assert!(Lock::acquire(dir.path(), false).is_err());
+        let forced = Lock::acquire(dir.path(), true).unwrap();
+        drop(forced);
+        assert!(!dir.path().join(FILE_NAME).exists());
+        drop(lock);
+    }
+
+    #[test]
+    fn test_stale_lock_taken_over() {
+        let dir = tempfile::tempdir().unwrap();
+        // pids are capped well below this on linux and macos
+        fs::write(dir.path().join(FILE_NAME), "2147483646\n").unwrap();
+        let _lock = Lock::acquire(dir.path(), false).unwrap();
+        let held = fs::read_to_string(dir.path().join(FILE_NAME)).unwrap();
+        assert_eq!(held.trim(), std::process::id().to_string());
+    }
+
+    #[test]
+    fn test_taken_over_lock_kept() {
+        let dir = tempfile::tempdir().unwrap();
+        let lock = Lock::acquire(dir.path(), false).unwrap();
+        // another rema's --ignore-lockfile
+        fs::write(dir.path().join(FILE_NAME), "2147483646\n").unwrap();
+        drop(lock);
+        let held = fs::read_to_string(dir.path().join(FILE_NAME)).unwrap();
+        assert_eq!(held, "2147483646\n");
+    }
+}
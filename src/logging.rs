@@ -0,0 +1,134 @@
+use std::env;
+use std::io;
+
+use crate::warnings;
+
+use tracing::Subscriber;
+use tracing_subscriber::fmt::MakeWriter;
+use tracing_subscriber::layer::{Layer, SubscriberExt};
+use tracing_subscriber::util::SubscriberInitExt;
+use tracing_subscriber::{EnvFilter, Registry};
+
+// Env var selecting the log format when --log-json isn't passed
+const FORMAT_VAR: &str = "REMA_LOG_FORMAT";
+// Env var holding an optional filter directive, e.g. "debug" or "rema=trace"
+const FILTER_VAR: &str = "REMA_LOG";
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) enum LogFormat {
+    Text,
+    Json,
+}
+
+impl LogFormat {
+    // --log-json wins, otherwise fall back to REMA_LOG_FORMAT
+    pub(crate) fn resolve(log_json: bool) -> Self {
+        if log_json {
+            return Self::Json;
+        }
+        match env::var(FORMAT_VAR) {
+            Ok(v) if v.eq_ignore_ascii_case("json") => Self::Json,
+            _ => Self::Text,
+        }
+    }
+}
+
+// Install the global subscriber, logs always go to stderr; `ansi` colors the
+// text format's levels
+pub(crate) fn init(format: LogFormat, ansi: bool) {
+    let filter = EnvFilter::try_from_env(FILTER_VAR).unwrap_or_else(|_| EnvFilter::new("info"));
+    subscriber(format, ansi, filter, io::stderr).init();
+}
+
+// REMA_LOG filters just what's written, warnings::Sink sees every warning so
+// the log level can't get a run past --strict. The JSON format is one object
+// per line with the event's fields flattened to the top level, giving
+// {"timestamp", "level", "message", "repo", "event", ...}
+pub(crate) fn subscriber<W>(
+    format: LogFormat,
+    ansi: bool,
+    filter: EnvFilter,
+    writer: W,
+) -> impl Subscriber + Send + Sync
+where
+    W: for<'w> MakeWriter<'w> + Send + Sync + 'static,
+{
+    let fmt = tracing_subscriber::fmt::layer()
+        .with_target(false)
+        .with_writer(writer);
+    let fmt = match format {
+        LogFormat::Text => fmt.with_ansi(ansi).with_filter(filter).boxed(),
+        LogFormat::Json => fmt
+            .json()
+            .flatten_event(true)
+            .with_current_span(false)
+            .with_span_list(false)
+            .with_filter(filter)
+            .boxed(),
+    };
+    Registry::default().with(fmt).with(warnings::Sink)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::sync::{Arc, Mutex};
+
+    #[derive(Clone, Default)]
+    struct Buf(Arc<Mutex<Vec<u8>>>);
+
+    impl io::Write for Buf {
+        fn write(&mut self, b: &[u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().extend_from_slice(b);
+            Ok(b.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_json_log_line_fields() {
+        let buf = Buf::default();
+        let writer = buf.clone();
+        let subscriber = subscriber(LogFormat::Json, false, EnvFilter::new("info"), move || {
+            writer.clone()
+        });
+
+        tracing::subscriber::with_default(subscriber, || {
+            tracing::info!(repo = "dotfiles", event = "exec", "running make");
+        });
+
+        let out = String::from_utf8(buf.0.lock().unwrap().clone()).unwrap();
+        let line: serde_json::Value = serde_json::from_str(out.trim()).unwrap();
+        assert!(line["timestamp"].is_string());
+        assert_eq!(line["level"], "INFO");
+        assert_eq!(line["repo"], "dotfiles");
+        assert_eq!(line["event"], "exec");
+        assert_eq!(line["message"], "running make");
+    }
+
+    #[test]
+    fn test_filtered_warnings_still_counted() {
+        let _serial = warnings::SERIAL.lock().unwrap();
+        let buf = Buf::default();
+        let writer = buf.clone();
+        let subscriber = subscriber(LogFormat::Text, false, EnvFilter::new("error"), move || {
+            writer.clone()
+        });
+
+        tracing::subscriber::with_default(subscriber, || {
+            let from = warnings::count();
+            tracing::warn!("hidden by the filter");
+            assert!(warnings::check_since(from, "--strict").is_err());
+        });
+        assert!(buf.0.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_log_json_flag_wins() {
+        assert_eq!(LogFormat::resolve(true), LogFormat::Json);
+    }
+}
@@ -1,38 +1,555 @@
 #![warn(clippy::all, clippy::pedantic, rust_2018_idioms)]
 
+pub(crate) mod bench;
+pub(crate) mod color;
+pub(crate) mod command;
 pub(crate) mod config;
+pub(crate) mod daemon;
+pub(crate) mod duration;
 pub(crate) mod errors;
+pub(crate) mod events;
+pub(crate) mod explain;
+pub(crate) mod export;
+pub(crate) mod git;
+pub(crate) mod history;
+pub(crate) mod interp;
+pub(crate) mod load;
+pub(crate) mod lock;
+pub(crate) mod logging;
+pub(crate) mod migrate;
+pub(crate) mod notify;
+pub(crate) mod output;
+pub(crate) mod plan;
+pub(crate) mod prompt;
+pub(crate) mod remote_config;
+pub(crate) mod repo_list;
+pub(crate) mod report;
+pub(crate) mod resource;
+pub(crate) mod run;
+pub(crate) mod signature;
+pub(crate) mod state;
+pub(crate) mod status;
+pub(crate) mod timing;
+#[cfg(feature = "tui")]
+pub(crate) mod tui;
+pub(crate) mod user;
+pub(crate) mod verify;
+pub(crate) mod warnings;
 
 use crate::errors::pretty_error;
 
-use std::fs;
+use std::env;
+use std::ffi::OsString;
+use std::io::{self, IsTerminal};
 use std::path::PathBuf;
 
-use clap::clap_app;
-use config::RemaConfig;
+use clap::{clap_app, App, Arg, ArgMatches, SubCommand};
+use color::ColorChoice;
+use config::Config;
+use daemon::Daemon;
+use load::LoadCap;
+use logging::LogFormat;
+use notify::Notify;
+use output::OutputMode;
+use report::{GroupBy, ReportFormat};
+use run::Ctx;
+use state::StateFormat;
+use status::StateFilter;
 
 fn main() {
-    let matches = clap_app!(rema =>
+    let matches = app().get_matches();
+
+    logging::init(
+        LogFormat::resolve(matches.is_present("LOG_JSON")),
+        color(&matches).enabled(io::stderr().is_terminal()),
+    );
+    if matches.is_present("JSON_LINES") {
+        events::enable(Box::new(std::io::stdout()));
+    }
+
+    let res = run(&matches).and_then(|()| warnings::check(matches.is_present("STRICT")));
+    if let Err(e) = res {
+        eprintln!("error: {}", pretty_error(&e));
+        std::process::exit(1);
+    }
+}
+
+// one flat list of every flag and subcommand
+#[allow(clippy::too_many_lines)]
+fn app() -> App<'static, 'static> {
+    clap_app!(rema =>
         (version: clap::crate_version!())
         (author: clap::crate_authors!())
         (about: clap::crate_description!())
-        (@arg CONFIG: -c --config +takes_value "Sets custom config file")
-        (@subcommand pull => (about: "fetch repos updates"))
-        (@subcommand update => (about: "build updated repos"))
-        (@subcommand clean => (about: "clean updated repos"))
+        (@arg CONFIG: -c --config +takes_value
+            "Sets custom config file, or an https URL to fetch it from with the http-config feature")
+        (@arg NO_DEFAULT: --("no-default") "Don't run the config's default actions when no command is given")
+        (@arg PROFILE: --profile +takes_value +global env("REMA_PROFILE")
+            "Use the [profile.<name>] settings of the config file")
+        (@arg LOG_JSON: --("log-json") "Emit logs as JSON objects (or set REMA_LOG_FORMAT=json)")
+        (@arg TIMEOUT: --timeout +takes_value +global {duration::validate}
+            "Kill commands running longer than this, e.g. 30s, 5m, 1h30m")
+        (@arg REPO_TIMEOUT: --("repo-timeout") +takes_value +global {duration::validate}
+            "Give up on a repo whose pull, build and clean together take longer than this")
+        (@arg JSON_LINES: --("json-lines") +global
+            "Stream run events to stdout as one JSON object per line")
+        (@arg STRICT: --strict +global
+            "Fail on undefined ${VAR} in a rema.toml instead of expanding to empty; also fails the run when anything warned, e.g. a skipped missing repo or an unknown key")
+        (@arg PARALLEL_DISCOVERY: --("parallel-discovery") +global
+            "Open repos in parallel, faster for base dirs with thousands of them")
+        (@arg STATE_FORMAT: --("state-format") +takes_value +global possible_values(&["toml", "json"])
+            default_value("toml") "File format rema keeps its state between runs in")
+        (@arg PREFIX_OUTPUT: --("prefix-output") +global conflicts_with[GROUP_OUTPUT]
+            "Tag every line of command output with its repo")
+        (@arg TIMESTAMPS: --timestamps +global conflicts_with[PREFIX_OUTPUT] conflicts_with[GROUP_OUTPUT]
+            "Merge command output into one stream, every line with the time, repo and command")
+        (@arg GROUP_OUTPUT: --("group-output") +global
+            "Hold back each repo's command output and print it in one block when it finishes")
+        (@arg MAX_REPO_OUTPUT: --("max-repo-output") +takes_value +global {positive}
+            "Stop showing a repo's streamed command output after this many lines")
+        (@arg ONLY_DIRTY: --("only-dirty") +global "Only act on repos with uncommitted changes")
+        (@arg ONLY_BEHIND: --("only-behind") +global
+            "Only act on repos behind their upstream, with --only-dirty either will do")
+        (@arg FAIL_ON_DIRTY: --("fail-on-dirty") +global
+            "Refuse to run if any repo has uncommitted changes, update fails just those repos")
+        (@arg NO_MUTATE_REMOTES: --("no-mutate-remotes") +global
+            "Fail repos whose remote differs from remote_url instead of changing it")
+        (@arg ISOLATE_ENV: --("isolate-env") +global
+            "Run commands with only PATH, HOME and LANG from rema's environment plus the repo's env")
+        (@arg BUILD_ARG: --("build-arg") +takes_value +multiple +global number_of_values(1)
+            {build_arg} "Fill {name} in commands, as name=value, over the repo's build_args")
+        (@arg AUTO_CLONE: --("auto-clone") +global
+            "Clone repos under [clone] that have gone missing instead of reporting them")
+        (@arg CACHE_DIR: --("cache-dir") +takes_value +global
+            "Clone through a shared object cache here, deduplicating objects between clones; the clones need it to stay")
+        (@arg ENV_PREFIX: --("env-prefix") +takes_value +global {env_prefix}
+            "Start the names of REMA_REPO_NAME, REMA_BRANCH and the rest every command gets with this instead of REMA_")
+        (@arg START_FROM: --("start-from") +takes_value +global
+            "Skip the repos before this one in the order they run, e.g. to resume a run that failed partway")
+        (@arg STOP_AFTER: --("stop-after") +takes_value +global
+            "Skip the repos after this one, with --start-from just the repos from one to the other")
+        (@arg COLOR: --color +takes_value +global possible_values(&["always", "auto", "never"])
+            default_value("auto")
+            "Color log lines and status, auto only on a terminal and without NO_COLOR")
+        (@arg HASH_CACHE: --("hash-cache") +global
+            "Skip builds whose inputs hash the same as the last good build, see hash_inputs")
+        (@arg LOCAL_ONLY: --("local-only") +global
+            "Skip every fetch, pull builds the current checkouts and status only looks at local state")
+        (@arg BUILD_ARGS: --("build-args") +takes_value +multiple +global number_of_values(1)
+            allow_hyphen_values(true)
+            "Add these to the end of every build command, split on whitespace like the commands are")
+        (@arg SINCE_TAG: --("since-tag") +takes_value +global {tag_glob}
+            "Only build repos a pull brought a new tag matching this glob to, e.g. v*")
+        (@arg TREAT_WARNINGS_AS_ERRORS: --("treat-warnings-as-errors") +global
+            "Fail loading configs with unknown keys, undefined ${VAR} or missing repos instead of warning")
+        (@arg WARNINGS: -W +takes_value +global possible_values(&["error"])
+            "-W error is --treat-warnings-as-errors")
+        (@arg SET: --set alias("repo-config-override") +takes_value +multiple +global
+            number_of_values(1) {setting}
+            "Set a repo field for this run only, over every rema.toml, e.g. autoclean=true")
+        (@arg YES: -y --yes alias("assume-yes") +global
+            "Answer yes to every confirmation prompt, e.g. upgrade-config --write on a terminal")
+        (@arg TRACE_TIMING: --("trace-timing") +global
+            "Break each repo's time down into pre_pull, fetch, merge, build, clean and post_update")
+        (@arg BENCHMARK: --benchmark +global
+            "End with min, max, mean and median build times and the wall time against the summed time")
+        (@arg JOBS: -j --jobs +takes_value +global {positive}
+            "Repos to work on at once, the default for --build-jobs and half of --fetch-jobs")
+        (@arg FETCH_JOBS: --("fetch-jobs") +takes_value +global {positive}
+            "Repos to fetch at once, defaults to twice --jobs")
+        (@arg BUILD_JOBS: --("build-jobs") +takes_value +global {positive}
+            "Repos to build at once, defaults to --jobs")
+        (@arg CONCURRENCY_FROM_LOAD: --("concurrency-from-load") +global
+            "Start fewer builds at once while the load average is over --target-load")
+        (@arg TARGET_LOAD: --("target-load") +takes_value +global {load::validate}
+            requires[CONCURRENCY_FROM_LOAD] "Load average to keep builds under, defaults to the number of cores")
+        (@arg REPOS_FROM: --("repos-from") +takes_value +global
+            "Only act on the repos named in this file, one per line")
+        (@arg DUMP_FAILURES: --("dump-failures") +takes_value +global
+            "Write the repos that failed to this file, for a later --repos-from")
+        (@arg IGNORE_LOCKFILE: --("ignore-lockfile") +global
+            "Run even if another rema's lockfile is there, for one left by a crash")
+        (@arg NOTIFY: --notify +takes_value +global
+            "Run this once a pull, update or rebuild is over, with REMA_SUMMARY and REMA_FAILED set")
+        (@arg NOTIFY_THRESHOLD: --("notify-threshold") +takes_value +global {duration::validate}
+            requires[NOTIFY] "Only notify for runs taking at least this long, or that failed")
+        (@arg REPORT: --report +takes_value +global "Also write the run summary to this file")
+        (@arg REPORT_FORMAT: --("report-format") +takes_value +global possible_values(&["junit", "json"])
+            default_value("junit") "Format of --report, junit has a test case per repo")
+        (@arg GROUP_BY: --("group-by") +takes_value +global possible_values(&["tag", "none"])
+            default_value("none") "Group the run summary by repo tag")
+        (@subcommand pull =>
+            (about: "fetch repos updates")
+            (@arg REPO_URL: --("repo-url") alias("remote-url") +takes_value +multiple
+                number_of_values(1) "Fetch repo from another url for this run, as name=url")
+            (@arg APPLY_ONCE: --("apply-once") "Skip post_update hooks already run for this commit")
+            (@arg CHANGED_FILES: --("changed-files") "List the files each pull changed, with their status")
+            (@arg COUNT: --count "Print only the totals, on one line")
+            (@arg PRINT0: --print0 conflicts_with[COUNT CHANGED_FILES]
+                "Print only the paths of updated repos, each followed by a NUL, for xargs -0")
+            (@arg PREFETCH_THEN_PROMPT: --("prefetch-then-prompt")
+                "Fetch every repo first, list what would update and ask once before merging")
+        )
+        (@subcommand daemon =>
+            (about: "pull repos over and over, reloading the config each time")
+            (@arg INTERVAL: --interval +takes_value {duration::validate} default_value("15m")
+                "Time between pulls")
+            (@arg STRICT_DAEMON: --("strict-daemon")
+                "Exit on a config error instead of carrying on with the last good config")
+        )
+        (@subcommand update =>
+            (about: "build updated repos")
+            (@arg APPLY_ONCE: --("apply-once") "Skip post_update hooks already run for this commit")
+            (@arg ONLY_FAILED: --("only-failed") "Rebuild only the repos whose last build failed")
+            (@arg REF: --ref +takes_value conflicts_with[ONLY_FAILED]
+                "Fetch every repo and build it at this tag or commit, leaving HEAD detached there")
+        )
+        (@subcommand clean =>
+            (about: "clean repos")
+            (@arg PARALLEL_CLEAN: --("parallel-clean")
+                "Clean repos concurrently, as many at once as builds (--build-jobs or -j)")
+        )
+        (@subcommand build =>
+            (about: "build one repo at a given commit, checked out detached then restored")
+            (@arg ONLY: --only +takes_value +required "Name of the repo")
+            (@arg AT: --at alias("checkout-detached") +takes_value
+                "Commit to build, anything git can resolve, defaults to HEAD")
+        )
+        (@subcommand rebuild => (about: "clean then build every repo, whether updated or not"))
+        (@subcommand status =>
+            (about: "show branch, upstream and working tree state of repos")
+            (@arg COMPACT: --compact conflicts_with[COUNT] "One cell per repo in a grid with a legend")
+            (@arg COUNT: --count "Print only the totals, e.g. 42 repos, 5 behind, 2 dirty")
+            (@arg CHECK_REMOTES: --("check-remotes") "Also check every remote answers, this is slower")
+        )
+        (@subcommand clone =>
+            (about: "clone repos listed under [clone] that aren't in base_dir yet")
+            (@arg DRY_RUN: --("dry-run") "List what would be cloned without cloning")
+        )
+        (@subcommand tui =>
+            (about: "browse repos and their status, pulling or updating the ones picked")
+        )
+        (@subcommand plan =>
+            (about: "print every repo's commands as json, or check them against a saved plan")
+            (@arg COMPARE_PLAN: --("compare-plan") +takes_value
+                "Print what changed since this saved plan and fail if anything did")
+        )
+        (@subcommand export =>
+            (about: "print a shell script of the git and build commands rema would run")
+        )
+        (@subcommand explain =>
+            (about: "describe one repo's settings, git state and what a pull and update would run")
+            (@arg REPO: +required "Name of the repo")
+            (@arg DUMP_ENV: --("dump-env") alias("print-env")
+                "Also list the environment each command would run with and where each variable comes from, secrets hidden")
+        )
+        (@subcommand inspect =>
+            (about: "clone a repo to a temporary dir and print its plan, without adding it anywhere")
+            (@arg URL: +required "Where to clone it from, anything git can fetch")
+        )
+        (@subcommand pending =>
+            (about: "list repos a pull would update, going by the last fetch, without fetching")
+        )
+        (@subcommand history =>
+            (about: "show the last runs of each repo and how they went")
+            (@arg REPO: "Only this repo")
+            (@arg LAST: --last +takes_value {positive} "Show at most this many runs per repo")
+            (@arg FORMAT: --format +takes_value possible_values(&["human", "json"])
+                default_value("human") "Print a table per repo or json")
+        )
+        (@subcommand verify =>
+            (about: "check repos are still on their pinned_commit and expected_branch")
+        )
     )
-    .get_matches();
+    .subcommands(hyphenated_subcommands())
+}
 
-    // TODO: maybe tmp or idk
-    let updates_file = PathBuf::new();
+fn run(matches: &ArgMatches<'_>) -> Result<(), failure::Error> {
+    let default_jobs = jobs(matches, "JOBS").unwrap_or(1);
+    let ctx = Ctx {
+        // TODO: maybe tmp or idk
+        state_dir: state::state_dir(),
+        state_format: matches
+            .value_of("STATE_FORMAT")
+            .unwrap_or("toml")
+            .parse::<StateFormat>()
+            .map_err(failure::err_msg)?,
+        group_by: matches
+            .value_of("GROUP_BY")
+            .unwrap_or("none")
+            .parse::<GroupBy>()
+            .map_err(failure::err_msg)?,
+        fetch_jobs: jobs(matches, "FETCH_JOBS").unwrap_or(2 * default_jobs),
+        build_jobs: jobs(matches, "BUILD_JOBS").unwrap_or(default_jobs),
+        load_cap: matches
+            .is_present("CONCURRENCY_FROM_LOAD")
+            .then(|| LoadCap::new(matches.value_of("TARGET_LOAD").and_then(|v| v.parse().ok()))),
+        dump_failures: matches.value_of("DUMP_FAILURES").map(PathBuf::from),
+        ignore_lockfile: matches.is_present("IGNORE_LOCKFILE"),
+        hash_cache: matches.is_present("HASH_CACHE"),
+        auto_clone: matches.is_present("AUTO_CLONE"),
+        assume_yes: matches.is_present("YES"),
+        benchmark: matches.is_present("BENCHMARK"),
+        color: color(matches).enabled(io::stdout().is_terminal()),
+        notify: matches.value_of("NOTIFY").map(|c| Notify {
+            command: c.into(),
+            threshold: matches
+                .value_of("NOTIFY_THRESHOLD")
+                .and_then(|t| duration::parse(t).ok()),
+        }),
+        report: match matches.value_of("REPORT") {
+            Some(p) => Some((
+                matches
+                    .value_of("REPORT_FORMAT")
+                    .unwrap_or("junit")
+                    .parse::<ReportFormat>()
+                    .map_err(failure::err_msg)?,
+                PathBuf::from(p),
+            )),
+            None => None,
+        },
+    };
+
+    // the daemon reloads the config itself every cycle
+    if let ("daemon", Some(m)) = matches.subcommand() {
+        return Daemon::new(|| load_config(matches, &ctx), m.is_present("STRICT_DAEMON"))
+            .run(&ctx, m);
+    }
+    // a repo that isn't managed yet, no config needed
+    if let ("inspect", Some(m)) = matches.subcommand() {
+        return run::inspect(m);
+    }
+    // an old config may not load until it has been upgraded
+    if let ("upgrade-config", Some(m)) = matches.subcommand() {
+        if matches
+            .value_of("CONFIG")
+            .is_some_and(remote_config::is_url)
+        {
+            failure::bail!(
+                "upgrade-config can't write back to a URL, upgrade the file where it's served from"
+            );
+        }
+        return run::upgrade_config(&config_file(matches), &ctx, m);
+    }
+    let config = load_config(matches, &ctx)?;
+
+    if matches.is_present("FAIL_ON_DIRTY") && matches.subcommand_name() != Some("update") {
+        run::ensure_clean(&config)?;
+    }
 
     match matches.subcommand() {
-        ("pull", _) => todo!("pull repos"),
-        ("update", _) => todo!("run build cmds on updated repos"),
-        ("clean", _) => todo!("clean repos"),
-        ("", None) => eprintln!("No command given"),
+        ("pull", Some(m)) => run::pull(&config, &ctx, m),
+        ("update", Some(m)) => run::update(&config, &ctx, m),
+        ("clean", Some(m)) => run::clean(&config, &ctx, m),
+        ("rebuild", _) => run::rebuild(&config, &ctx),
+        ("build", Some(m)) => run::build(&config, &ctx, m),
+        ("status", Some(m)) => run::status(&config, &ctx, m),
+        ("pending", _) => run::pending(&config),
+        ("verify", _) => run::verify(&config),
+        ("history", Some(m)) => run::history(&ctx, m),
+        ("explain", Some(m)) => run::explain(&config, m),
+        ("export", _) => run::export(&config),
+        ("plan", Some(m)) => run::plan(&config, m),
+        ("clone", Some(m)) => run::clone(&config, m),
+        ("list-tags", Some(m)) => run::list_tags(&config, m),
+        #[cfg(feature = "tui")]
+        ("tui", Some(m)) => tui::run(config, &ctx, m),
+        #[cfg(not(feature = "tui"))]
+        ("tui", _) => Err(failure::err_msg("rema was built without the tui feature")),
+        ("", None) if !config.default.is_empty() && !matches.is_present("NO_DEFAULT") => {
+            run_default(&config.default)
+        }
+        ("", None) => {
+            eprintln!("No command given");
+            Ok(())
+        }
         (s, _) => {
             unreachable!("got subcommand: {}", s);
         }
     }
 }
+
+// clap_app! only takes identifiers as subcommand names
+fn hyphenated_subcommands() -> [App<'static, 'static>; 2] {
+    [
+        SubCommand::with_name("list-tags")
+            .about("show the tags in use and how many repos have each")
+            .arg(
+                Arg::with_name("SORT")
+                    .long("sort")
+                    .takes_value(true)
+                    .possible_values(&["name", "count"])
+                    .default_value("name")
+                    .help("Order tags by name or by repo count"),
+            ),
+        SubCommand::with_name("upgrade-config")
+            .about("migrate a config to the current schema version, printing it unless --write")
+            .arg(Arg::with_name("FILE").help("A repo's rema.toml, the global config when left out"))
+            .arg(
+                Arg::with_name("WRITE")
+                    .long("write")
+                    .help("Replace the file instead of printing, comments are not kept"),
+            ),
+    ]
+}
+
+// each of the config's `default` actions as if it had been given after
+// rema's own arguments; one naming no command would run the defaults again
+fn run_default(actions: &[String]) -> Result<(), failure::Error> {
+    for action in actions {
+        let args = env::args_os().chain(action.split_whitespace().map(OsString::from));
+        let matches = app().get_matches_from_safe(args)?;
+        if matches.subcommand_name().is_none() {
+            failure::bail!("default action {:?} doesn't name a command", action);
+        }
+        run(&matches)?;
+    }
+    Ok(())
+}
+
+// the config file with the command line's overrides applied
+fn load_config(matches: &ArgMatches<'_>, ctx: &Ctx) -> Result<Config, failure::Error> {
+    let file = match matches.value_of("CONFIG") {
+        Some(url) if remote_config::is_url(url) => {
+            let cache = dirs::cache_dir().unwrap_or_default().join("rema");
+            remote_config::fetch(url, &cache)?
+        }
+        _ => config_file(matches),
+    };
+    let warned = warnings::count();
+    let mut config = Config::load(&file, matches.value_of("PROFILE"))?;
+    config.strict = matches.is_present("STRICT");
+    config.parallel_discovery = matches.is_present("PARALLEL_DISCOVERY");
+    config.no_mutate_remotes = matches.is_present("NO_MUTATE_REMOTES");
+    config.isolate_env = matches.is_present("ISOLATE_ENV");
+    config.local_only = matches.is_present("LOCAL_ONLY");
+    config.close_stdin = ctx.build_jobs > 1 || matches.subcommand_name() == Some("daemon");
+    config.trace_timing = matches.is_present("TRACE_TIMING");
+    config.env_prefix = matches.value_of("ENV_PREFIX").map(String::from);
+    config.start_from = matches.value_of("START_FROM").map(String::from);
+    config.stop_after = matches.value_of("STOP_AFTER").map(String::from);
+    if let Some(dir) = matches.value_of("CACHE_DIR") {
+        config.cache_dir = Some(PathBuf::from(dir));
+    }
+    config.extra_build_args = matches
+        .values_of("BUILD_ARGS")
+        .into_iter()
+        .flatten()
+        .flat_map(str::split_whitespace)
+        .map(String::from)
+        .collect();
+    for s in matches.values_of("SET").into_iter().flatten() {
+        let (key, value) = config::parse_override(s).map_err(failure::err_msg)?;
+        config.overrides.insert(key, value);
+    }
+    // over every repo's own, where the config file's are only a default
+    for (arg, key) in [("TIMEOUT", "timeout"), ("REPO_TIMEOUT", "repo_timeout")] {
+        if let Some(t) = matches.value_of(arg) {
+            config
+                .overrides
+                .insert(key.into(), toml::Value::String(t.into()));
+        }
+    }
+    if let Some(glob) = matches.value_of("SINCE_TAG") {
+        config.since_tag = Some(glob::Pattern::new(glob)?);
+    }
+    config.build_args = matches
+        .values_of("BUILD_ARG")
+        .into_iter()
+        .flatten()
+        .filter_map(|v| v.split_once('='))
+        .map(|(k, v)| (k.to_string(), v.to_string()))
+        .collect();
+    config.filter = StateFilter {
+        dirty: matches.is_present("ONLY_DIRTY"),
+        behind: matches.is_present("ONLY_BEHIND"),
+    };
+    if matches.is_present("PREFIX_OUTPUT") {
+        config.output = OutputMode::Prefix;
+    } else if matches.is_present("GROUP_OUTPUT") {
+        config.output = OutputMode::Group;
+    } else if matches.is_present("TIMESTAMPS") {
+        config.output = OutputMode::Timestamped;
+    }
+    if let Some(p) = matches.value_of("REPOS_FROM") {
+        config.names = Some(repo_list::read(p.as_ref())?);
+    }
+    if let Some(n) = matches.value_of("MAX_REPO_OUTPUT") {
+        config.max_repo_output = n.parse().ok();
+    }
+    if matches.is_present("TREAT_WARNINGS_AS_ERRORS")
+        || matches.value_of("WARNINGS") == Some("error")
+    {
+        // repos warn as they're loaded, missing ones among others, so load
+        // them once up front
+        config.repos()?;
+        warnings::check_since(warned, "-W error")?;
+    }
+    Ok(config)
+}
+
+// clap validator
+#[allow(clippy::needless_pass_by_value)]
+fn positive(s: String) -> Result<(), String> {
+    match s.parse::<usize>() {
+        Ok(n) if n > 0 => Ok(()),
+        _ => Err(format!("expected a positive number, got {s:?}")),
+    }
+}
+
+#[allow(clippy::needless_pass_by_value)]
+fn build_arg(s: String) -> Result<(), String> {
+    match s.split_once('=') {
+        Some((name, _)) if !name.is_empty() => Ok(()),
+        _ => Err(format!("expected name=value, got {s:?}")),
+    }
+}
+
+#[allow(clippy::needless_pass_by_value)]
+fn tag_glob(s: String) -> Result<(), String> {
+    glob::Pattern::new(&s)
+        .map(|_| ())
+        .map_err(|e| format!("bad glob {s:?}: {e}"))
+}
+
+#[allow(clippy::needless_pass_by_value)]
+fn env_prefix(s: String) -> Result<(), String> {
+    let starts_name = s
+        .chars()
+        .next()
+        .is_some_and(|c| c.is_ascii_alphabetic() || c == '_');
+    if starts_name && s.chars().all(|c| c.is_ascii_alphanumeric() || c == '_') {
+        Ok(())
+    } else {
+        Err(format!("{s:?} can't start environment variable names"))
+    }
+}
+
+#[allow(clippy::needless_pass_by_value)]
+fn setting(s: String) -> Result<(), String> {
+    config::parse_override(&s).map(|_| ())
+}
+
+fn color(matches: &ArgMatches<'_>) -> ColorChoice {
+    matches
+        .value_of("COLOR")
+        .and_then(|c| c.parse().ok())
+        .unwrap_or(ColorChoice::Auto)
+}
+
+fn jobs(matches: &ArgMatches<'_>, arg: &str) -> Option<usize> {
+    matches.value_of(arg).and_then(|v| v.parse().ok())
+}
+
+fn config_file(matches: &ArgMatches<'_>) -> PathBuf {
+    matches
+        .value_of("CONFIG")
+        .map_or_else(default_config_file, PathBuf::from)
+}
+
+fn default_config_file() -> PathBuf {
+    dirs::config_dir()
+        .unwrap_or_default()
+        .join("rema")
+        .join("config.toml")
+}
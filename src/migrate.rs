@@ -0,0 +1,117 @@
+use std::convert::TryFrom;
+
+use toml::value::Table;
+use toml::Value;
+
+// The config schema version written by upgrade-config, a file without a
+// `version` field is version 0
+pub(crate) const VERSION: u32 = 1;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) enum Kind {
+    // the global config pointing at base_dir
+    Root,
+    // a repo's rema.toml or rema.local.toml
+    Repo,
+}
+
+// one migration per version, REPO[n] takes a version n config to n + 1
+const ROOT: [fn(&mut Table); 1] = [|_| {}];
+const REPO: [fn(&mut Table); 1] = [structure_commands];
+
+const COMMAND_LISTS: [&str; 4] = ["pre_pull", "build", "clean", "post_update"];
+
+pub(crate) fn version(v: &Value) -> Result<u32, String> {
+    match v.get("version") {
+        None => Ok(0),
+        Some(Value::Integer(n)) => u32::try_from(*n).map_err(|_| format!("bad version {n}")),
+        Some(other) => Err(format!("version must be a number, found {other}")),
+    }
+}
+
+// refuse configs written for a newer rema rather than misreading them
+pub(crate) fn check(v: &Value) -> Result<(), String> {
+    match version(v)? {
+        n if n > VERSION => Err(format!(
+            "written for a newer rema (config version {n}, this one reads up to {VERSION})"
+        )),
+        _ => Ok(()),
+    }
+}
+
+// apply every migration the config hasn't had yet, returns the version it
+// started at
+pub(crate) fn upgrade(v: &mut Value, kind: Kind) -> Result<u32, String> {
+    check(v)?;
+    let from = version(v)?;
+    let t = v
+        .as_table_mut()
+        .ok_or_else(|| "config must be a table".to_string())?;
+    let migrations: &[fn(&mut Table)] = match kind {
+        Kind::Root => &ROOT,
+        Kind::Repo => &REPO,
+    };
+    for m in &migrations[from as usize..] {
+        m(t);
+    }
+    t.insert("version".into(), Value::Integer(VERSION.into()));
+    Ok(from)
+}
+
+// 0 -> 1: bare command lines become `{ run = "..." }` tables, the form that
+// takes options like if_changed and stdin
+fn structure_commands(t: &mut Table) {
+    for key in &COMMAND_LISTS {
+        if let Some(Value::Array(cmds)) = t.get_mut(*key) {
+            for c in cmds {
+                if let Value::String(run) = c {
+                    let mut table = Table::new();
+                    table.insert("run".into(), Value::String(std::mem::take(run)));
+                    *c = Value::Table(table);
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::command::Command;
+
+    #[test]
+    fn test_upgrade_v0_repo() {
+        let mut v: Value = toml::from_str(
+            r#"
+                tags = ["web"]
+                build = ["make", { run = "make test", if_changed = ["src/*"] }]
+                clean = ["make clean"]
+            "#,
+        )
+        .unwrap();
+        assert_eq!(upgrade(&mut v, Kind::Repo), Ok(0));
+        assert_eq!(version(&v), Ok(VERSION));
+        assert_eq!(v["build"][0]["run"].as_str(), Some("make"));
+        assert_eq!(v["build"][1]["if_changed"][0].as_str(), Some("src/*"));
+        assert_eq!(v["clean"][0]["run"].as_str(), Some("make clean"));
+        assert_eq!(v["tags"][0].as_str(), Some("web"));
+
+        // still reads the same once written out
+        let v: Value = toml::from_str(&toml::to_string(&v).unwrap()).unwrap();
+        let build: Vec<Command> = v["build"].clone().try_into().unwrap();
+        assert_eq!(build[0], Command::from("make"));
+        assert_eq!(build[1].if_changed, ["src/*"]);
+
+        // and upgrading again changes nothing
+        let mut again = v.clone();
+        assert_eq!(upgrade(&mut again, Kind::Repo), Ok(VERSION));
+        assert_eq!(again, v);
+    }
+
+    #[test]
+    fn test_newer_version_rejected() {
+        let mut v: Value = toml::from_str("version = 99").unwrap();
+        assert!(check(&v).is_err());
+        assert!(upgrade(&mut v, Kind::Root).is_err());
+    }
+}
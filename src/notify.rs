@@ -0,0 +1,81 @@
+use crate::errors;
+use crate::report::Summary;
+
+use std::process::Command;
+use std::time::Duration;
+
+use tracing::{info, warn};
+
+// --notify, a command run once a pull, update or rebuild is over, told how
+// it went through REMA_SUMMARY and REMA_FAILED (0 or 1). With a threshold
+// only runs that took at least that long notify, failures always do.
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct Notify {
+    pub(crate) command: String,
+    pub(crate) threshold: Option<Duration>,
+}
+
+impl Notify {
+    fn wanted(&self, elapsed: Duration, failed: bool) -> bool {
+        failed || self.threshold.is_none_or(|t| elapsed >= t)
+    }
+
+    // true if the command ran; a notify command failing doesn't fail the run
+    pub(crate) fn after(&self, summary: &Summary, elapsed: Duration) -> bool {
+        let failed = summary.failed().next().is_some();
+        if !self.wanted(elapsed, failed) {
+            info!(
+                event = "notify_skipped",
+                "run took {:?}, under --notify-threshold", elapsed
+            );
+            return false;
+        }
+        let parts = self.command.split_whitespace().collect::<Vec<_>>();
+        let Some((cmd, args)) = parts.split_first() else {
+            return false;
+        };
+        let res = Command::new(cmd)
+            .args(args)
+            .env("REMA_SUMMARY", summary.render_count().trim_end())
+            .env("REMA_FAILED", if failed { "1" } else { "0" })
+            .status();
+        match res {
+            Ok(s) if s.success() => {}
+            Ok(s) => warn!(
+                "notify command {:?} {}",
+                self.command,
+                errors::exit_reason(s)
+            ),
+            Err(e) => warn!("could not run notify command {:?}: {}", self.command, e),
+        }
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_threshold() {
+        let dir = tempfile::tempdir().unwrap();
+        let sent = dir.path().join("sent");
+        let notify = Notify {
+            command: format!("touch {}", sent.display()),
+            threshold: Some(Duration::from_mins(1)),
+        };
+        let summary = Summary::default();
+
+        assert!(!notify.after(&summary, Duration::from_secs(2)));
+        assert!(!sent.exists());
+        assert!(notify.after(&summary, Duration::from_mins(5)));
+        assert!(sent.exists());
+
+        assert!(notify.wanted(Duration::ZERO, true));
+        let always = Notify {
+            threshold: None,
+            ..notify
+        };
+        assert!(always.wanted(Duration::ZERO, false));
+    }
+}
@@ -0,0 +1,246 @@
+use crate::events;
+
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+// How the output of spawned commands reaches the terminal
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub(crate) enum OutputMode {
+    // straight through, commands inherit rema's stdout and stderr
+    #[default]
+    Stream,
+    // every line tagged with the repo it came from
+    Prefix,
+    // held back and printed as one block once the repo finishes
+    Group,
+    // every line, stdout and stderr alike, on stdout with the time and the
+    // repo and command it came from
+    Timestamped,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) enum Pipe {
+    Out,
+    Err,
+}
+
+// a repo's held back output for OutputMode::Group
+pub(crate) type Buffer = Arc<Mutex<Vec<u8>>>;
+
+const TRUNCATED: &[u8] = b"[output truncated]\n";
+
+// --max-repo-output, the lines of a repo's streamed output shown before the
+// rest is dropped; its commands keep running and all of them share the count
+#[derive(Debug, Default, Clone)]
+pub(crate) struct Cap {
+    max: Option<usize>,
+    seen: Arc<AtomicUsize>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Admit {
+    Show,
+    // the first line over the cap, replaced by the notice
+    Truncate,
+    Drop,
+}
+
+impl Cap {
+    pub(crate) fn new(max: Option<usize>) -> Self {
+        Self {
+            max,
+            seen: Arc::default(),
+        }
+    }
+
+    // output has to be piped to be counted
+    pub(crate) fn is_active(&self) -> bool {
+        self.max.is_some()
+    }
+
+    fn admit(&self) -> Admit {
+        let Some(max) = self.max else {
+            return Admit::Show;
+        };
+        match self.seen.fetch_add(1, Ordering::Relaxed) {
+            n if n < max => Admit::Show,
+            n if n == max => Admit::Truncate,
+            _ => Admit::Drop,
+        }
+    }
+}
+
+static RESERVED: AtomicBool = AtomicBool::new(false);
+
+// for output meant for another program, like `pull --print0`
+pub(crate) fn reserve_stdout() {
+    RESERVED.store(true, Ordering::Relaxed);
+}
+
+// when the event stream or reserve_stdout owns stdout
+pub(crate) fn stdout_reserved() -> bool {
+    RESERVED.load(Ordering::Relaxed) || events::enabled()
+}
+
+// child stdout goes to stderr while stdout is reserved
+fn write_to(pipe: Pipe, bytes: &[u8]) {
+    let res = if pipe == Pipe::Out && !stdout_reserved() {
+        io::stdout().lock().write_all(bytes)
+    } else {
+        io::stderr().lock().write_all(bytes)
+    };
+    if let Err(e) = res {
+        tracing::warn!("could not write command output: {}", e);
+    }
+}
+
+fn prefixed(repo: &str, line: &[u8]) -> Vec<u8> {
+    let mut out = format!("[{repo}] ").into_bytes();
+    out.extend_from_slice(line);
+    if !out.ends_with(b"\n") {
+        out.push(b'\n');
+    }
+    out
+}
+
+// e.g. "14:03:27.512 [app] (make install) line", the time is UTC
+fn timestamped(at: Duration, repo: &str, command: &str, line: &[u8]) -> Vec<u8> {
+    let secs = at.as_secs();
+    let mut out = format!(
+        "{:02}:{:02}:{:02}.{:03} [{repo}] ({command}) ",
+        secs / 3600 % 24,
+        secs / 60 % 60,
+        secs % 60,
+        at.subsec_millis()
+    )
+    .into_bytes();
+    out.extend_from_slice(line);
+    if !out.ends_with(b"\n") {
+        out.push(b'\n');
+    }
+    out
+}
+
+// copy a child's pipe line by line until it closes, the cap only applies to
+// streamed output
+pub(crate) fn forward<R>(
+    r: R,
+    pipe: Pipe,
+    mode: OutputMode,
+    repo: &str,
+    command: &str,
+    buf: &Buffer,
+    cap: &Cap,
+) -> JoinHandle<()>
+where
+    R: Read + Send + 'static,
+{
+    let repo = repo.to_string();
+    let command = command.to_string();
+    let buf = Arc::clone(buf);
+    let cap = cap.clone();
+    thread::spawn(move || {
+        let mut r = BufReader::new(r);
+        let mut line = Vec::new();
+        while matches!(r.read_until(b'\n', &mut line), Ok(n) if n > 0) {
+            if mode != OutputMode::Group {
+                match cap.admit() {
+                    Admit::Show => {}
+                    Admit::Truncate => line = TRUNCATED.to_vec(),
+                    Admit::Drop => {
+                        line.clear();
+                        continue;
+                    }
+                }
+            }
+            match mode {
+                OutputMode::Prefix => write_to(pipe, &prefixed(&repo, &line)),
+                OutputMode::Group => {
+                    let mut buf = buf.lock().unwrap();
+                    buf.extend_from_slice(&line);
+                    if !buf.ends_with(b"\n") {
+                        buf.push(b'\n');
+                    }
+                }
+                OutputMode::Stream => write_to(pipe, &line),
+                OutputMode::Timestamped => {
+                    let now = SystemTime::now()
+                        .duration_since(UNIX_EPOCH)
+                        .unwrap_or_default();
+                    write_to(Pipe::Out, &timestamped(now, &repo, &command, &line));
+                }
+            }
+            line.clear();
+        }
+    })
+}
+
+// print and empty a repo's held back output
+pub(crate) fn flush(buf: &Buffer) {
+    let out = std::mem::take(&mut *buf.lock().unwrap());
+    if !out.is_empty() {
+        write_to(Pipe::Out, &out);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_prefixed() {
+        assert_eq!(prefixed("app", b"built\n"), b"[app] built\n");
+        assert_eq!(prefixed("app", b"no newline"), b"[app] no newline\n");
+    }
+
+    #[test]
+    fn test_timestamped() {
+        // three days after the epoch at 14:03:27.512
+        let at = Duration::from_millis(((3 * 24 + 14) * 3600 + 3 * 60 + 27) * 1000 + 512);
+        assert_eq!(
+            timestamped(at, "app", "make install", b"done"),
+            b"14:03:27.512 [app] (make install) done\n"
+        );
+    }
+
+    #[test]
+    fn test_group_buffers() {
+        let buf = Buffer::default();
+        // the cap doesn't apply to held back output
+        let cap = Cap::new(Some(1));
+        forward(
+            &b"one\ntwo"[..],
+            Pipe::Err,
+            OutputMode::Group,
+            "app",
+            "make",
+            &buf,
+            &cap,
+        )
+        .join()
+        .unwrap();
+        assert_eq!(&*buf.lock().unwrap(), b"one\ntwo\n");
+    }
+
+    #[test]
+    fn test_cap_truncates() {
+        let cap = Cap::new(Some(2));
+        let admitted = (0..5).map(|_| cap.admit()).collect::<Vec<_>>();
+        assert_eq!(
+            admitted,
+            [
+                Admit::Show,
+                Admit::Show,
+                Admit::Truncate,
+                Admit::Drop,
+                Admit::Drop
+            ]
+        );
+        // clones count towards the same repo
+        assert_eq!(cap.clone().admit(), Admit::Drop);
+        assert_eq!(Cap::default().admit(), Admit::Show);
+    }
+}
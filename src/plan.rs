@@ -0,0 +1,165 @@
+use crate::config::{Phase, RemaConfig};
+
+use std::collections::BTreeMap;
+use std::fmt::Write;
+
+use serde::{Deserialize, Serialize};
+
+// What rema would run, per repo, saved by `rema plan` and checked against
+// with --compare-plan like a lockfile
+pub(crate) type Plan = BTreeMap<String, Vec<Step>>;
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub(crate) struct Step {
+    pub(crate) phase: Phase,
+    pub(crate) run: String,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub(crate) if_changed: Vec<String>,
+}
+
+pub(crate) fn of(repos: &[RemaConfig]) -> Plan {
+    repos
+        .iter()
+        .map(|r| {
+            let steps = r
+                .pre_pull_commands()
+                .chain(r.commands())
+                .map(|c| Step {
+                    phase: c.phase,
+                    run: c.line.to_string(),
+                    if_changed: c.if_changed.to_vec(),
+                })
+                .collect();
+            (r.name().to_string(), steps)
+        })
+        .collect()
+}
+
+// `+` for what's new since `old`, `-` for what's gone and `~` for repos
+// whose steps changed; empty when nothing drifted
+pub(crate) fn diff(old: &Plan, new: &Plan) -> String {
+    let mut out = String::new();
+    for (name, steps) in old {
+        if !new.contains_key(name) {
+            let _ = writeln!(out, "- {name}");
+            for s in steps {
+                let _ = writeln!(out, "    - {}", step(s));
+            }
+        }
+    }
+    for (name, steps) in new {
+        match old.get(name) {
+            None => {
+                let _ = writeln!(out, "+ {name}");
+                for s in steps {
+                    let _ = writeln!(out, "    + {}", step(s));
+                }
+            }
+            Some(was) if was != steps => {
+                let _ = writeln!(out, "~ {name}");
+                if same_steps(was, steps) {
+                    out.push_str("    (reordered)\n");
+                    continue;
+                }
+                let (kept_was, kept_new) = common(was, steps);
+                for (s, _) in was.iter().zip(kept_was).filter(|(_, k)| !k) {
+                    let _ = writeln!(out, "    - {}", step(s));
+                }
+                for (s, _) in steps.iter().zip(kept_new).filter(|(_, k)| !k) {
+                    let _ = writeln!(out, "    + {}", step(s));
+                }
+            }
+            Some(_) => {}
+        }
+    }
+    out
+}
+
+// the same steps as often each, in any order
+fn same_steps(a: &[Step], b: &[Step]) -> bool {
+    let count = |steps: &[Step], s: &Step| steps.iter().filter(|t| *t == s).count();
+    a.len() == b.len() && a.iter().all(|s| count(a, s) == count(b, s))
+}
+
+// which steps of each side are in their longest common subsequence, so a
+// step added next to a copy of itself is still reported once
+fn common(a: &[Step], b: &[Step]) -> (Vec<bool>, Vec<bool>) {
+    let mut len = vec![vec![0; b.len() + 1]; a.len() + 1];
+    for i in (0..a.len()).rev() {
+        for j in (0..b.len()).rev() {
+            len[i][j] = if a[i] == b[j] {
+                len[i + 1][j + 1] + 1
+            } else {
+                len[i + 1][j].max(len[i][j + 1])
+            };
+        }
+    }
+    let (mut kept_a, mut kept_b) = (vec![false; a.len()], vec![false; b.len()]);
+    let (mut i, mut j) = (0, 0);
+    while i < a.len() && j < b.len() {
+        if a[i] == b[j] {
+            kept_a[i] = true;
+            kept_b[j] = true;
+            i += 1;
+            j += 1;
+        } else if len[i + 1][j] >= len[i][j + 1] {
+            i += 1;
+        } else {
+            j += 1;
+        }
+    }
+    (kept_a, kept_b)
+}
+
+fn step(s: &Step) -> String {
+    if s.if_changed.is_empty() {
+        format!("{}: {}", s.phase, s.run)
+    } else {
+        format!("{}: {} (if {:?} changed)", s.phase, s.run, s.if_changed)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::tests::fixture;
+
+    #[test]
+    fn test_added_repo_drifts() {
+        let (_a, app) = fixture(r#"build = ["make"]"#);
+        let (_b, web) = fixture(r#"build = ["npm ci"]"#);
+        let old = of(std::slice::from_ref(&app));
+        let saved: Plan = serde_json::from_str(&serde_json::to_string(&old).unwrap()).unwrap();
+        assert_eq!(diff(&saved, &of(std::slice::from_ref(&app))), "");
+
+        let new = of(&[app, web]);
+        let web_name = new.keys().find(|k| !old.contains_key(*k)).unwrap();
+        assert_eq!(
+            diff(&saved, &new),
+            format!("+ {web_name}\n    + build: npm ci\n")
+        );
+    }
+
+    #[test]
+    fn test_changed_steps() {
+        let step = |run: &str| Step {
+            phase: Phase::Build,
+            run: run.into(),
+            if_changed: vec![],
+        };
+        let old = Plan::from([("app".into(), vec![step("make")])]);
+        let new = Plan::from([("app".into(), vec![step("make all")])]);
+        assert_eq!(
+            diff(&old, &new),
+            "~ app\n    - build: make\n    + build: make all\n"
+        );
+        assert_eq!(diff(&new, &Plan::new()), "- app\n    - build: make all\n");
+
+        // a repeated step is an added one, not a reorder
+        let twice = Plan::from([("app".into(), vec![step("make"), step("make")])]);
+        assert_eq!(diff(&old, &twice), "~ app\n    + build: make\n");
+        let swapped = Plan::from([("app".into(), vec![step("b"), step("a")])]);
+        let ordered = Plan::from([("app".into(), vec![step("a"), step("b")])]);
+        assert_eq!(diff(&ordered, &swapped), "~ app\n    (reordered)\n");
+    }
+}
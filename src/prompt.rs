@@ -0,0 +1,40 @@
+use std::io::{self, BufRead, IsTerminal, Write};
+
+use tracing::info;
+
+// Ask a yes/no question before doing something that can't be undone. Only
+// asked on a terminal, scripts get what they always got; --yes answers yes
+// without asking. The prompts so far:
+// - upgrade-config --write, before replacing the file
+pub(crate) fn confirm(question: &str, assume_yes: bool) -> io::Result<bool> {
+    if assume_yes {
+        info!(event = "assumed_yes", "{} yes (--yes)", question);
+        return Ok(true);
+    }
+    if !io::stdin().is_terminal() {
+        return Ok(true);
+    }
+    eprint!("{question} [y/N] ");
+    io::stderr().flush()?;
+    let mut answer = String::new();
+    io::stdin().lock().read_line(&mut answer)?;
+    Ok(is_yes(&answer))
+}
+
+fn is_yes(answer: &str) -> bool {
+    matches!(answer.trim().to_lowercase().as_str(), "y" | "yes")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_is_yes() {
+        assert!(is_yes("y\n"));
+        assert!(is_yes(" YES "));
+        assert!(!is_yes("\n"));
+        assert!(!is_yes("no"));
+        assert!(confirm("replace it?", true).unwrap());
+    }
+}
@@ -0,0 +1,128 @@
+use crate::errors::ConfigError;
+
+use std::path::{Path, PathBuf};
+
+// -c https://..., a team's shared config fetched on every run; repos are
+// still found under its base_dir on this machine. http:// counts so that
+// fetch can refuse it, the config's commands would run from whoever is on
+// the network path.
+pub(crate) fn is_url(s: &str) -> bool {
+    s.starts_with("https://") || s.starts_with("http://")
+}
+
+fn ensure_https(url: &str) -> Result<(), ConfigError> {
+    if url.starts_with("https://") {
+        return Ok(());
+    }
+    Err(ConfigError::Fetch(
+        url.into(),
+        "only https configs are fetched".into(),
+    ))
+}
+
+// where the last good fetch of `url` is kept, e.g.
+// ~/.cache/rema/configs/https___example_com_rema_toml
+#[cfg(any(feature = "http-config", test))]
+fn cache_path(cache_dir: &Path, url: &str) -> PathBuf {
+    let name = url
+        .chars()
+        .map(|c| if c.is_ascii_alphanumeric() { c } else { '_' })
+        .collect::<String>();
+    cache_dir.join("configs").join(name)
+}
+
+// fetch `url` into the cache and return the cached file; when the fetch
+// fails an earlier copy is used, with a warning
+#[cfg(feature = "http-config")]
+pub(crate) fn fetch(url: &str, cache_dir: &Path) -> Result<PathBuf, ConfigError> {
+    use std::fs;
+    use std::process::Command;
+    use tracing::warn;
+
+    ensure_https(url)?;
+    let cached = cache_path(cache_dir, url);
+    let part = cached.with_extension("part");
+    let fetched = fs::create_dir_all(cache_dir.join("configs"))
+        .map_err(|e| e.to_string())
+        .and_then(|()| {
+            let out = Command::new("curl")
+                .args(["--fail", "--silent", "--show-error", "--location"])
+                .args(["--proto", "=https", "--proto-redir", "=https"])
+                .args(["--max-time", "30", "--output"])
+                .arg(&part)
+                .arg(url)
+                .output()
+                .map_err(|e| format!("could not run curl: {e}"))?;
+            if out.status.success() {
+                fs::rename(&part, &cached).map_err(|e| e.to_string())
+            } else {
+                Err(String::from_utf8_lossy(&out.stderr).trim().to_string())
+            }
+        });
+    match fetched {
+        Ok(()) => Ok(cached),
+        Err(e) if cached.is_file() => {
+            warn!(
+                event = "config_cached",
+                "could not fetch {}, using the copy from {}: {}",
+                url,
+                cached.display(),
+                e
+            );
+            Ok(cached)
+        }
+        Err(e) => Err(ConfigError::Fetch(url.into(), e)),
+    }
+}
+
+#[cfg(not(feature = "http-config"))]
+pub(crate) fn fetch(url: &str, _cache_dir: &Path) -> Result<PathBuf, ConfigError> {
+    ensure_https(url)?;
+    Err(ConfigError::Fetch(
+        url.into(),
+        "rema was built without the http-config feature".into(),
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_cache_path() {
+        assert!(is_url("https://example.com/rema.toml"));
+        assert!(!is_url("/etc/rema/config.toml"));
+        assert_eq!(
+            cache_path(Path::new("/c"), "https://example.com/team/rema.toml"),
+            Path::new("/c/configs/https___example_com_team_rema_toml")
+        );
+    }
+
+    #[test]
+    fn test_http_refused() {
+        let err = fetch("http://example.com/rema.toml", Path::new("/nonexistent")).unwrap_err();
+        assert!(err.to_string().contains("only https"), "{}", err);
+    }
+
+    #[cfg(not(feature = "http-config"))]
+    #[test]
+    fn test_fetch_disabled() {
+        let err = fetch("https://example.com/rema.toml", Path::new("/nonexistent")).unwrap_err();
+        assert!(err.to_string().contains("http-config feature"), "{}", err);
+    }
+
+    #[cfg(feature = "http-config")]
+    #[test]
+    fn test_fetch_falls_back_to_cache() {
+        let dir = tempfile::tempdir().unwrap();
+        // nothing listens on the discard port
+        let url = "https://127.0.0.1:9/rema.toml";
+        assert!(matches!(
+            fetch(url, dir.path()),
+            Err(ConfigError::Fetch(..))
+        ));
+        let cached = cache_path(dir.path(), url);
+        std::fs::write(&cached, "base_dir = \"/srv\"\n").unwrap();
+        assert_eq!(fetch(url, dir.path()).unwrap(), cached);
+    }
+}
@@ -0,0 +1,40 @@
+use std::fs;
+use std::io;
+use std::path::Path;
+
+// The --repos-from format: one repo name per line, blank lines and lines
+// starting with # are ignored
+pub(crate) fn read(p: &Path) -> io::Result<Vec<String>> {
+    Ok(parse(&fs::read_to_string(p)?))
+}
+
+fn parse(s: &str) -> Vec<String> {
+    s.lines()
+        .map(str::trim)
+        .filter(|l| !l.is_empty() && !l.starts_with('#'))
+        .map(String::from)
+        .collect()
+}
+
+pub(crate) fn write<'a>(p: &Path, names: impl IntoIterator<Item = &'a str>) -> io::Result<()> {
+    let mut out = String::new();
+    for n in names {
+        out.push_str(n);
+        out.push('\n');
+    }
+    fs::write(p, out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_round_trip() {
+        let dir = tempfile::tempdir().unwrap();
+        let p = dir.path().join("failures.txt");
+        write(&p, ["app", "dotfiles"]).unwrap();
+        assert_eq!(read(&p).unwrap(), ["app", "dotfiles"]);
+        assert_eq!(parse("# retry\napp\n\n  web  \n"), ["app", "web"]);
+    }
+}
@@ -0,0 +1,634 @@
+use crate::config::RemaConfig;
+use crate::git::{FileChange, TagUpdates};
+use crate::timing::Timings;
+
+use std::cmp::Reverse;
+use std::collections::BTreeMap;
+use std::fmt::{self, Write};
+use std::str::FromStr;
+use std::time::Duration;
+
+use serde::{Deserialize, Serialize};
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum Outcome {
+    UpToDate,
+    Updated,
+    Failed,
+    TimedOut,
+    // a pre_pull hook failed so the repo wasn't fetched
+    PrePullFailed,
+    // not built because of its skip file, or no new since_tag tag
+    Skipped,
+    // rebuild's clean failed so the build never ran
+    CleanFailed,
+    // known from [clone] or the state but gone from disk
+    Missing,
+}
+
+impl Outcome {
+    pub(crate) fn is_failure(self) -> bool {
+        match self {
+            Self::Failed | Self::TimedOut | Self::PrePullFailed | Self::CleanFailed => true,
+            Self::UpToDate | Self::Updated | Self::Skipped | Self::Missing => false,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) enum GroupBy {
+    None,
+    Tag,
+}
+
+impl FromStr for GroupBy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "none" => Ok(Self::None),
+            "tag" => Ok(Self::Tag),
+            _ => Err(format!("unknown grouping {s:?}, expected tag or none")),
+        }
+    }
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub(crate) struct Counts {
+    pub(crate) updated: usize,
+    pub(crate) failed: usize,
+    pub(crate) up_to_date: usize,
+    pub(crate) timed_out: usize,
+    pub(crate) pre_pull_failed: usize,
+    pub(crate) skipped: usize,
+    pub(crate) clean_failed: usize,
+    pub(crate) missing: usize,
+}
+
+impl Counts {
+    fn add(&mut self, outcome: Outcome) {
+        match outcome {
+            Outcome::UpToDate => self.up_to_date += 1,
+            Outcome::Updated => self.updated += 1,
+            Outcome::Failed => self.failed += 1,
+            Outcome::TimedOut => self.timed_out += 1,
+            Outcome::PrePullFailed => self.pre_pull_failed += 1,
+            Outcome::Skipped => self.skipped += 1,
+            Outcome::CleanFailed => self.clean_failed += 1,
+            Outcome::Missing => self.missing += 1,
+        }
+    }
+}
+
+impl fmt::Display for Counts {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} updated, {} failed, {} up to date",
+            self.updated, self.failed, self.up_to_date
+        )?;
+        if self.timed_out > 0 {
+            write!(f, ", {} timed out", self.timed_out)?;
+        }
+        if self.pre_pull_failed > 0 {
+            write!(f, ", {} failed pre_pull", self.pre_pull_failed)?;
+        }
+        if self.skipped > 0 {
+            write!(f, ", {} skipped", self.skipped)?;
+        }
+        if self.clean_failed > 0 {
+            write!(f, ", {} failed clean", self.clean_failed)?;
+        }
+        if self.missing > 0 {
+            write!(f, ", {} missing", self.missing)?;
+        }
+        Ok(())
+    }
+}
+
+struct Entry {
+    name: String,
+    tags: Vec<String>,
+    outcome: Outcome,
+    duration: Duration,
+    // why it failed
+    error: Option<String>,
+    // time in build commands, zero when none ran
+    build: Duration,
+    // --trace-timing
+    timings: Option<Timings>,
+    // pull and build attempts, more than one with repo_retries
+    attempts: u32,
+}
+
+// Outcomes of a run, printed once every repo has been processed
+#[derive(Default)]
+pub(crate) struct Summary {
+    entries: Vec<Entry>,
+    // what each pull did to the repo's git tags, repos without changes left out
+    tag_updates: BTreeMap<String, TagUpdates>,
+}
+
+const UNTAGGED: &str = "(untagged)";
+
+impl Summary {
+    pub(crate) fn record(&mut self, repo: &RemaConfig, outcome: Outcome, error: Option<String>) {
+        self.entries.push(Entry {
+            name: repo.name().into(),
+            tags: repo.tags().to_vec(),
+            outcome,
+            duration: repo.elapsed(),
+            error,
+            build: repo.build_time(),
+            timings: repo.take_timings(),
+            attempts: repo.attempts(),
+        });
+    }
+
+    // a repo that couldn't be loaded because it's not on disk
+    pub(crate) fn record_missing(&mut self, name: &str) {
+        self.entries.push(Entry {
+            name: name.into(),
+            tags: vec![],
+            outcome: Outcome::Missing,
+            duration: Duration::ZERO,
+            error: None,
+            build: Duration::ZERO,
+            timings: None,
+            attempts: 0,
+        });
+    }
+
+    pub(crate) fn record_tags(&mut self, name: &str, updates: TagUpdates) {
+        if !updates.is_empty() {
+            self.tag_updates.insert(name.into(), updates);
+        }
+    }
+
+    pub(crate) fn totals(&self) -> Counts {
+        let mut c = Counts::default();
+        for e in &self.entries {
+            c.add(e.outcome);
+        }
+        c
+    }
+
+    // repos with several tags count towards each of them
+    pub(crate) fn by_tag(&self) -> BTreeMap<&str, Counts> {
+        let mut groups = BTreeMap::<&str, Counts>::new();
+        for e in &self.entries {
+            if e.tags.is_empty() {
+                groups.entry(UNTAGGED).or_default().add(e.outcome);
+            }
+            for t in &e.tags {
+                groups.entry(t).or_default().add(e.outcome);
+            }
+        }
+        groups
+    }
+
+    // each repo's name, outcome and how long it took, for the history
+    pub(crate) fn outcomes(&self) -> impl Iterator<Item = (&str, Outcome, Duration)> {
+        self.entries
+            .iter()
+            .map(|e| (e.name.as_str(), e.outcome, e.duration))
+    }
+
+    // how long each repo that ran a build spent on it
+    pub(crate) fn build_times(&self) -> Vec<Duration> {
+        self.entries
+            .iter()
+            .map(|e| e.build)
+            .filter(|d| !d.is_zero())
+            .collect()
+    }
+
+    // every repo's time added up, more than the run took when repos ran at
+    // the same time
+    pub(crate) fn summed(&self) -> Duration {
+        self.entries.iter().map(|e| e.duration).sum()
+    }
+
+    // repos whose run went wrong, for --dump-failures
+    pub(crate) fn failed(&self) -> impl Iterator<Item = &str> {
+        self.entries
+            .iter()
+            .filter(|e| e.outcome.is_failure())
+            .map(|e| e.name.as_str())
+    }
+
+    // --count, just the totals on one line
+    pub(crate) fn render_count(&self) -> String {
+        format!("{} repos, {}\n", self.entries.len(), self.totals())
+    }
+
+    // JUnit XML with a test case per repo, skipped repos are marked skipped
+    pub(crate) fn junit(&self) -> String {
+        let c = self.totals();
+        let failures = self
+            .entries
+            .iter()
+            .filter(|e| e.outcome.is_failure())
+            .count();
+        let total: f64 = self.entries.iter().map(|e| e.duration.as_secs_f64()).sum();
+        let mut out = String::from("<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n");
+        let _ = writeln!(
+            out,
+            "<testsuite name=\"rema\" tests=\"{}\" failures=\"{failures}\" skipped=\"{}\" time=\"{total:.3}\">",
+            self.entries.len(),
+            c.skipped
+        );
+        for e in &self.entries {
+            let _ = write!(
+                out,
+                "  <testcase name=\"{}\" classname=\"rema\" time=\"{:.3}\"",
+                xml_escape(&e.name),
+                e.duration.as_secs_f64()
+            );
+            if e.outcome.is_failure() {
+                let msg = e.error.as_deref().unwrap_or("failed");
+                let _ = writeln!(
+                    out,
+                    ">\n    <failure type=\"{}\" message=\"{}\"/>\n  </testcase>",
+                    outcome_name(e.outcome),
+                    xml_escape(msg)
+                );
+            } else if matches!(e.outcome, Outcome::Skipped | Outcome::Missing) {
+                out.push_str(">\n    <skipped/>\n  </testcase>\n");
+            } else {
+                out.push_str("/>\n");
+            }
+        }
+        out.push_str("</testsuite>\n");
+        out
+    }
+
+    // every repo and the tag updates pulled, with `tags` keyed by repo
+    pub(crate) fn json(&self) -> serde_json::Value {
+        let repos = self
+            .entries
+            .iter()
+            .map(|e| {
+                let mut repo = serde_json::json!({
+                    "name": e.name,
+                    "outcome": e.outcome,
+                    "duration_ms": e.duration.as_millis(),
+                    "error": e.error,
+                    "attempts": e.attempts,
+                });
+                if let Some(t) = &e.timings {
+                    let phases = t
+                        .steps()
+                        .map(|(name, d)| (name, d.as_millis()))
+                        .collect::<BTreeMap<_, _>>();
+                    repo["phases_ms"] = serde_json::json!(phases);
+                }
+                repo
+            })
+            .collect::<Vec<_>>();
+        serde_json::json!({
+            "repos": repos,
+            "totals": self.totals().to_string(),
+            "tags": self.tag_updates,
+        })
+    }
+
+    pub(crate) fn render(&self, group_by: GroupBy) -> String {
+        let mut out = String::new();
+        for (name, updates) in &self.tag_updates {
+            let _ = writeln!(out, "{name}: tags {updates}");
+        }
+        for e in self.entries.iter().filter(|e| e.attempts > 1) {
+            let _ = writeln!(out, "{}: {} attempts", e.name, e.attempts);
+        }
+        if group_by == GroupBy::Tag {
+            for (tag, counts) in self.by_tag() {
+                let _ = writeln!(out, "{tag}: {counts}");
+            }
+        }
+        let _ = writeln!(out, "total: {}", self.totals());
+        out.push_str(&self.render_timings());
+        out
+    }
+
+    // --trace-timing, each repo's steps under its name
+    fn render_timings(&self) -> String {
+        let mut out = String::new();
+        for e in &self.entries {
+            if let Some(t) = &e.timings {
+                if out.is_empty() {
+                    out.push_str("timing:\n");
+                }
+                let _ = writeln!(out, "  {}", e.name);
+                out.push_str(&t.render("    "));
+            }
+        }
+        out
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) enum ReportFormat {
+    Junit,
+    Json,
+}
+
+impl FromStr for ReportFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "junit" => Ok(Self::Junit),
+            "json" => Ok(Self::Json),
+            _ => Err(format!(
+                "unknown report format {s:?}, expected junit or json"
+            )),
+        }
+    }
+}
+
+// as in the json events
+pub(crate) fn outcome_name(o: Outcome) -> String {
+    serde_json::to_value(o)
+        .ok()
+        .and_then(|v| v.as_str().map(String::from))
+        .unwrap_or_default()
+}
+
+fn xml_escape(s: &str) -> String {
+    let mut out = String::with_capacity(s.len());
+    for c in s.chars() {
+        match c {
+            '&' => out.push_str("&amp;"),
+            '<' => out.push_str("&lt;"),
+            '>' => out.push_str("&gt;"),
+            '"' => out.push_str("&quot;"),
+            '\'' => out.push_str("&apos;"),
+            '\n' => out.push_str("&#10;"),
+            c if c.is_control() => {}
+            c => out.push(c),
+        }
+    }
+    out
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) enum TagSort {
+    Name,
+    // most used first, ties by name
+    Count,
+}
+
+impl FromStr for TagSort {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "name" => Ok(Self::Name),
+            "count" => Ok(Self::Count),
+            _ => Err(format!("unknown sort {s:?}, expected name or count")),
+        }
+    }
+}
+
+// every tag in use with the number of repos carrying it
+pub(crate) fn render_tags<'a>(
+    tags: impl IntoIterator<Item = &'a [String]>,
+    sort: TagSort,
+) -> String {
+    let mut counts = BTreeMap::<&str, usize>::new();
+    for t in tags.into_iter().flatten() {
+        *counts.entry(t).or_default() += 1;
+    }
+    let mut counts = counts.into_iter().collect::<Vec<_>>();
+    if sort == TagSort::Count {
+        counts.sort_by_key(|(_, n)| Reverse(*n));
+    }
+
+    let w = counts.iter().map(|(t, _)| t.len()).max().unwrap_or(0);
+    let mut out = String::new();
+    for (tag, n) in counts {
+        let _ = writeln!(out, "{tag:<w$}  {n}");
+    }
+    out
+}
+
+// most files listed per repo by --changed-files
+pub(crate) const MAX_CHANGED_FILES: usize = 200;
+
+pub(crate) fn render_changes(repo: &str, changes: &[FileChange], max: usize) -> String {
+    let mut out = String::new();
+    let _ = writeln!(out, "{repo}: {} files changed", changes.len());
+    for c in changes.iter().take(max) {
+        match &c.old_path {
+            Some(old) => {
+                let _ = writeln!(
+                    out,
+                    "  {} {} -> {}",
+                    c.status,
+                    old.display(),
+                    c.path.display()
+                );
+            }
+            None => {
+                let _ = writeln!(out, "  {} {}", c.status, c.path.display());
+            }
+        }
+    }
+    if changes.len() > max {
+        let _ = writeln!(out, "  ... and {} more", changes.len() - max);
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn summary(entries: &[(&[&str], Outcome)]) -> Summary {
+        Summary {
+            entries: entries
+                .iter()
+                .enumerate()
+                .map(|(i, (tags, outcome))| Entry {
+                    name: format!("r{i}"),
+                    tags: tags.iter().map(|t| (*t).to_string()).collect(),
+                    outcome: *outcome,
+                    duration: Duration::from_millis(1500),
+                    error: outcome
+                        .is_failure()
+                        .then(|| "build failed: \"make\" exited <1>".into()),
+                    build: Duration::ZERO,
+                    timings: None,
+                    attempts: 1,
+                })
+                .collect(),
+            ..Summary::default()
+        }
+    }
+
+    #[test]
+    fn test_grouped_counts() {
+        let s = summary(&[
+            (&["web"], Outcome::Updated),
+            (&["web", "tools"], Outcome::Failed),
+            (&["tools"], Outcome::UpToDate),
+            (&[], Outcome::Updated),
+        ]);
+        let groups = s.by_tag();
+        assert_eq!(
+            groups["web"],
+            Counts {
+                updated: 1,
+                failed: 1,
+                up_to_date: 0,
+                timed_out: 0,
+                pre_pull_failed: 0,
+                skipped: 0,
+                clean_failed: 0,
+                missing: 0
+            }
+        );
+        assert_eq!(
+            groups["tools"],
+            Counts {
+                updated: 0,
+                failed: 1,
+                up_to_date: 1,
+                timed_out: 0,
+                pre_pull_failed: 0,
+                skipped: 0,
+                clean_failed: 0,
+                missing: 0
+            }
+        );
+        assert_eq!(groups[UNTAGGED].updated, 1);
+        // the grand total counts each repo once
+        assert_eq!(
+            s.totals(),
+            Counts {
+                updated: 2,
+                failed: 1,
+                up_to_date: 1,
+                timed_out: 0,
+                pre_pull_failed: 0,
+                skipped: 0,
+                clean_failed: 0,
+                missing: 0
+            }
+        );
+    }
+
+    #[test]
+    fn test_render_grouped() {
+        let s = summary(&[(&["web"], Outcome::Updated), (&[], Outcome::UpToDate)]);
+        assert_eq!(
+            s.render(GroupBy::Tag),
+            "(untagged): 0 updated, 0 failed, 1 up to date\n\
+             web: 1 updated, 0 failed, 0 up to date\n\
+             total: 1 updated, 0 failed, 1 up to date\n"
+        );
+        assert_eq!(
+            s.render(GroupBy::None),
+            "total: 1 updated, 0 failed, 1 up to date\n"
+        );
+    }
+
+    #[test]
+    fn test_failed() {
+        let s = summary(&[
+            (&[], Outcome::Updated),
+            (&[], Outcome::Failed),
+            (&[], Outcome::Skipped),
+            (&[], Outcome::TimedOut),
+        ]);
+        assert_eq!(s.failed().collect::<Vec<_>>(), ["r1", "r3"]);
+    }
+
+    #[test]
+    fn test_junit() {
+        let s = summary(&[
+            (&[], Outcome::Updated),
+            (&[], Outcome::Failed),
+            (&[], Outcome::Skipped),
+            (&[], Outcome::UpToDate),
+        ]);
+        let xml = s.junit();
+        let doc = roxmltree::Document::parse(&xml).unwrap();
+        let suite = doc.root_element();
+        assert_eq!(suite.attribute("tests"), Some("4"));
+        assert_eq!(suite.attribute("failures"), Some("1"));
+        let cases = suite
+            .children()
+            .filter(|n| n.has_tag_name("testcase"))
+            .collect::<Vec<_>>();
+        assert_eq!(cases.len(), 4);
+        assert_eq!(cases[0].attribute("time"), Some("1.500"));
+        let failure = cases[1]
+            .children()
+            .find(|n| n.has_tag_name("failure"))
+            .unwrap();
+        assert_eq!(
+            failure.attribute("message"),
+            Some("build failed: \"make\" exited <1>")
+        );
+        assert!(cases[2].children().any(|n| n.has_tag_name("skipped")));
+    }
+
+    #[test]
+    fn test_render_count() {
+        let s = summary(&[(&["web"], Outcome::Updated), (&[], Outcome::UpToDate)]);
+        assert_eq!(
+            s.render_count(),
+            "2 repos, 1 updated, 0 failed, 1 up to date\n"
+        );
+    }
+
+    #[test]
+    fn test_render_pre_pull_failed() {
+        let s = summary(&[(&[], Outcome::Failed), (&[], Outcome::PrePullFailed)]);
+        assert_eq!(
+            s.render(GroupBy::None),
+            "total: 0 updated, 1 failed, 0 up to date, 1 failed pre_pull\n"
+        );
+    }
+
+    #[test]
+    fn test_render_tags() {
+        let repos = [
+            vec!["web".to_string(), "tools".into()],
+            vec!["tools".into()],
+            vec![],
+            vec!["db".into()],
+        ];
+        let tags = || repos.iter().map(Vec::as_slice);
+        assert_eq!(
+            render_tags(tags(), TagSort::Name),
+            "db     1\ntools  2\nweb    1\n"
+        );
+        assert_eq!(
+            render_tags(tags(), TagSort::Count),
+            "tools  2\ndb     1\nweb    1\n"
+        );
+        assert_eq!("count".parse(), Ok(TagSort::Count));
+        assert!("size".parse::<TagSort>().is_err());
+    }
+
+    #[test]
+    fn test_render_changes_truncated() {
+        let changes = ["a", "b", "c"]
+            .iter()
+            .map(|p| FileChange {
+                status: 'M',
+                path: (*p).into(),
+                old_path: None,
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(
+            render_changes("r", &changes, 2),
+            "r: 3 files changed\n  M a\n  M b\n  ... and 1 more\n"
+        );
+        assert!(!render_changes("r", &changes, 3).contains("more"));
+    }
+}
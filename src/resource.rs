@@ -0,0 +1,43 @@
+use std::collections::BTreeSet;
+use std::sync::{Condvar, Mutex};
+
+use tracing::info;
+
+// Named locks for repos whose builds can't overlap, e.g. two sharing a GPU
+// with `resource = "gpu"`; repos without a resource are never held back
+static HELD: Mutex<BTreeSet<String>> = Mutex::new(BTreeSet::new());
+static FREED: Condvar = Condvar::new();
+
+// released on drop
+#[must_use]
+pub(crate) struct Hold(Option<String>);
+
+// wait until no other repo holds `name`, then take it
+pub(crate) fn hold(repo: &str, name: Option<&str>) -> Hold {
+    let Some(name) = name else {
+        return Hold(None);
+    };
+    let mut held = HELD.lock().unwrap();
+    if held.contains(name) {
+        info!(
+            repo,
+            event = "resource_wait",
+            "waiting for resource {}",
+            name
+        );
+    }
+    while held.contains(name) {
+        held = FREED.wait(held).unwrap();
+    }
+    held.insert(name.into());
+    Hold(Some(name.into()))
+}
+
+impl Drop for Hold {
+    fn drop(&mut self) {
+        if let Some(name) = self.0.take() {
+            HELD.lock().unwrap().remove(&name);
+            FREED.notify_all();
+        }
+    }
+}
@@ -0,0 +1,1895 @@
+use crate::bench;
+use crate::config::{Config, RemaConfig};
+use crate::errors::CommandError;
+use crate::events::{self, Event};
+use crate::explain;
+use crate::export;
+use crate::git;
+use crate::history;
+use crate::load::LoadCap;
+use crate::lock::Lock;
+use crate::migrate::{self, Kind};
+use crate::notify::Notify;
+use crate::output;
+use crate::plan;
+use crate::prompt;
+use crate::repo_list;
+use crate::report::{self, GroupBy, Outcome, ReportFormat, Summary, TagSort};
+use crate::state::{Oids, State, StateFormat};
+use crate::status::{self, RepoStatus};
+use crate::verify;
+
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::env;
+use std::fs;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use clap::ArgMatches;
+use tracing::{error, info, warn};
+
+// Settings shared by every subcommand
+#[allow(clippy::struct_excessive_bools)]
+pub(crate) struct Ctx {
+    pub(crate) state_dir: PathBuf,
+    pub(crate) state_format: StateFormat,
+    pub(crate) group_by: GroupBy,
+    // threads for the network bound fetches and the cpu bound builds
+    pub(crate) fetch_jobs: usize,
+    pub(crate) build_jobs: usize,
+    // --concurrency-from-load, fewer builds at once on a busy machine
+    pub(crate) load_cap: Option<LoadCap>,
+    // --dump-failures, where to list the repos that failed
+    pub(crate) dump_failures: Option<PathBuf>,
+    // --report, a machine readable copy of the summary
+    pub(crate) report: Option<(ReportFormat, PathBuf)>,
+    // --ignore-lockfile
+    pub(crate) ignore_lockfile: bool,
+    pub(crate) notify: Option<Notify>,
+    // --hash-cache
+    pub(crate) hash_cache: bool,
+    // --auto-clone, clone [clone] repos that have gone missing
+    pub(crate) auto_clone: bool,
+    // --yes, see prompt::confirm
+    pub(crate) assume_yes: bool,
+    // --benchmark, see bench::render
+    pub(crate) benchmark: bool,
+    // --color, resolved for stdout
+    pub(crate) color: bool,
+}
+
+impl Ctx {
+    fn lock(&self) -> Result<Lock, failure::Error> {
+        Lock::acquire(&self.state_dir, self.ignore_lockfile)
+    }
+}
+
+// --fail-on-dirty, checked before any repo is touched; update instead fails
+// just the dirty repos
+pub(crate) fn ensure_clean(config: &Config) -> Result<(), failure::Error> {
+    let repos = config.repos()?;
+    let dirty = status::dirty_repos(&repos)?;
+    if !dirty.is_empty() {
+        failure::bail!(
+            "refusing to run, {} repos have uncommitted changes: {}",
+            dirty.len(),
+            dirty.join(", ")
+        );
+    }
+    Ok(())
+}
+
+pub(crate) fn pull(config: &Config, ctx: &Ctx, m: &ArgMatches<'_>) -> Result<(), failure::Error> {
+    let apply_once = m.is_present("APPLY_ONCE");
+    let changed_files = m.is_present("CHANGED_FILES");
+    let print0 = print0(m)?;
+    let _lock = ctx.lock()?;
+    let started = Instant::now();
+    let mut state = State::load(&ctx.state_dir, ctx.state_format)?;
+    let mut summary = Summary::default();
+    handle_missing(config, ctx, &mut state, &mut summary);
+    let State {
+        updates: updated,
+        applied,
+        failed,
+        hashes,
+        built: good,
+        ..
+    } = &mut state;
+    let repos = config.repos()?;
+    let urls = url_overrides(m, &repos)?;
+    let repos = if m.is_present("PREFETCH_THEN_PROMPT") {
+        match prefetch_then_prompt(repos, &urls, ctx, |q| prompt::confirm(q, ctx.assume_yes))? {
+            Some(repos) => repos,
+            None => return Ok(()),
+        }
+    } else {
+        repos
+    };
+
+    let pulled = pull_all(repos, ctx, &urls, changed_files);
+    let mut to_build = Vec::new();
+    let mut updated_paths = Vec::new();
+    for (repo, old, pulled) in pulled {
+        let tags = repo.take_tag_updates();
+        let (pulled, held) = since_tag_gate(&repo, &tags, pulled);
+        let held = held || repo.take_pull_skipped();
+        summary.record_tags(repo.name(), tags);
+        let (pulled, old) = first_run(good, &repo, pulled, old, held);
+        let builds = repo.autoupdate() && !repo.skip_build();
+        let (outcome, error) = match pulled {
+            // a skipped repo waits for an update like any other
+            Ok(Ok(false)) if held => (Outcome::Skipped, None),
+            // --local-only builds the checkouts, and leaves nothing pending
+            Ok(Ok(moved)) if builds && (moved || config.local_only) => {
+                let hash = input_hash(ctx, &repo);
+                if unchanged(hashes, &repo, hash.as_deref()) {
+                    (Outcome::UpToDate, None)
+                } else {
+                    let last = applied.get(repo.path()).copied().flatten();
+                    to_build.push((repo, old, last, hash));
+                    continue;
+                }
+            }
+            Ok(Ok(true)) => {
+                // keep the oldest HEAD if pulled again before building
+                updated.entry(repo.path().to_path_buf()).or_insert(old);
+                (Outcome::Updated, None)
+            }
+            Ok(Ok(false)) => (Outcome::UpToDate, None),
+            Ok(Err(e)) => (
+                Outcome::Failed,
+                Some(failure(&repo, "pull_failed", "pull", &e)),
+            ),
+            Err(e) => (
+                Outcome::PrePullFailed,
+                Some(failure(&repo, "pre_pull_failed", "pre_pull", &e)),
+            ),
+        };
+        if outcome == Outcome::Updated {
+            updated_paths.push(repo.workdir().to_path_buf());
+        }
+        finish(&mut summary, &repo, outcome, error);
+    }
+
+    let built = build_map(ctx, to_build, |(repo, old, last, hash)| {
+        let _held = repo.hold_resource();
+        let url = urls.get(repo.name()).copied();
+        let built = build_retrying(&repo, old, last, apply_once, url, changed_files);
+        (repo, old, built, hash)
+    });
+    for (repo, old, built, hash) in built {
+        let (outcome, error) = record_built(&repo, built, applied);
+        record_failure(failed, &repo, old, timed_out(&repo, outcome));
+        record_hash(hashes, &repo, outcome, hash);
+        record_good(good, &repo, outcome);
+        if outcome == Outcome::Updated {
+            updated_paths.push(repo.workdir().to_path_buf());
+        }
+        finish(&mut summary, &repo, outcome, error);
+    }
+
+    history::record(
+        &mut state.history,
+        &summary,
+        "pull",
+        history::now(),
+        config.history_len(),
+    );
+    state.save(&ctx.state_dir, ctx.state_format)?;
+    if print0 {
+        io::stdout().write_all(&nul_separated(&updated_paths))?;
+    }
+    finish_run(ctx, &summary, started, m.is_present("COUNT"))
+}
+
+pub(crate) fn update(config: &Config, ctx: &Ctx, m: &ArgMatches<'_>) -> Result<(), failure::Error> {
+    if let Some(rev) = m.value_of("REF") {
+        return update_ref(config, ctx, m, rev);
+    }
+    let apply_once = m.is_present("APPLY_ONCE");
+    let only_failed = m.is_present("ONLY_FAILED");
+    let fail_on_dirty = m.is_present("FAIL_ON_DIRTY");
+    let _lock = ctx.lock()?;
+    let started = Instant::now();
+    let mut state = State::load(&ctx.state_dir, ctx.state_format)?;
+    let mut summary = Summary::default();
+    handle_missing(config, ctx, &mut state, &mut summary);
+    let State {
+        updates: updated,
+        applied,
+        failed,
+        hashes,
+        built: good,
+        ..
+    } = &mut state;
+    let targets = if only_failed { &*failed } else { &*updated };
+    let mut unchanged_paths = Vec::new();
+    let mut to_build = Vec::new();
+    // repos left out of this run, or skipped, stay pending for the next update
+    for repo in config.repos()? {
+        let Some(&old) = targets.get(repo.path()) else {
+            continue;
+        };
+        if repo.skip_build() {
+            summary.record(&repo, Outcome::Skipped, None);
+            continue;
+        }
+        let hash = input_hash(ctx, &repo);
+        if unchanged(hashes, &repo, hash.as_deref()) {
+            // nothing left to build, it drops out of updates
+            unchanged_paths.push(repo.path().to_path_buf());
+            summary.record(&repo, Outcome::UpToDate, None);
+            continue;
+        }
+        let last = applied.get(repo.path()).copied().flatten();
+        to_build.push((repo, old, last, hash));
+    }
+
+    let built = build_map(ctx, to_build, |(repo, old, last, hash)| {
+        events::emit(&Event::RepoStart {
+            repo: repo.name(),
+            action: "update",
+        });
+        let _held = repo.hold_resource();
+        repo.start_clock();
+        let res = if fail_on_dirty {
+            repo.ensure_clean().and_then(|()| repo.build(old))
+        } else {
+            repo.build(old)
+        };
+        let built = build_then_hooks(&repo, res, last, apply_once);
+        (repo, old, built, hash)
+    });
+
+    for (repo, old, built, hash) in built {
+        let (outcome, error) = record_built(&repo, built, applied);
+        record_failure(failed, &repo, old, timed_out(&repo, outcome));
+        record_hash(hashes, &repo, outcome, hash);
+        record_good(good, &repo, outcome);
+        updated.remove(repo.path());
+        finish(&mut summary, &repo, outcome, error);
+    }
+    for p in &unchanged_paths {
+        updated.remove(p);
+    }
+    history::record(
+        &mut state.history,
+        &summary,
+        "update",
+        history::now(),
+        config.history_len(),
+    );
+    state.save(&ctx.state_dir, ctx.state_format)?;
+    finish_run(ctx, &summary, started, false)?;
+    if fail_on_dirty {
+        bail_on_failures(&summary)?;
+    }
+    Ok(())
+}
+
+// update --ref, every repo built at `rev` whether it had updates or not
+fn update_ref(
+    config: &Config,
+    ctx: &Ctx,
+    m: &ArgMatches<'_>,
+    rev: &str,
+) -> Result<(), failure::Error> {
+    let apply_once = m.is_present("APPLY_ONCE");
+    let _lock = ctx.lock()?;
+    let started = Instant::now();
+    let mut state = State::load(&ctx.state_dir, ctx.state_format)?;
+    let mut summary = Summary::default();
+    let (skipped, repos): (Vec<_>, Vec<_>) = config
+        .repos()?
+        .into_iter()
+        .partition(RemaConfig::skip_build);
+    for repo in &skipped {
+        summary.record(repo, Outcome::Skipped, None);
+    }
+    let applied = &state.applied;
+    let built = build_map(ctx, repos, |repo| {
+        events::emit(&Event::RepoStart {
+            repo: repo.name(),
+            action: "update",
+        });
+        let _held = repo.hold_resource();
+        repo.start_clock();
+        let (old, built) = build_at_ref(&repo, rev, applied, apply_once);
+        (repo, old, built)
+    });
+
+    for (repo, old, built) in built {
+        if repo.repo().head_detached().unwrap_or(false) {
+            state.detached.insert(repo.path().to_path_buf(), rev.into());
+        } else {
+            state.detached.remove(repo.path());
+        }
+        let (outcome, error) = record_built(&repo, built, &mut state.applied);
+        record_failure(&mut state.failed, &repo, old, timed_out(&repo, outcome));
+        record_good(&mut state.built, &repo, outcome);
+        state.updates.remove(repo.path());
+        finish(&mut summary, &repo, outcome, error);
+    }
+    history::record(
+        &mut state.history,
+        &summary,
+        "update",
+        history::now(),
+        config.history_len(),
+    );
+    state.save(&ctx.state_dir, ctx.state_format)?;
+    finish_run(ctx, &summary, started, false)
+}
+
+// with the HEAD from before the checkout
+fn build_at_ref(
+    repo: &RemaConfig,
+    rev: &str,
+    applied: &Oids,
+    apply_once: bool,
+) -> (Option<git2::Oid>, Built) {
+    let old = repo.head();
+    let res = repo.checkout_ref(rev).and_then(|_| repo.build(old));
+    let last = applied.get(repo.path()).copied().flatten();
+    (old, build_then_hooks(repo, res, last, apply_once))
+}
+
+// clean then build every repo from scratch, whatever the state says
+pub(crate) fn rebuild(config: &Config, ctx: &Ctx) -> Result<(), failure::Error> {
+    let _lock = ctx.lock()?;
+    let started = Instant::now();
+    let mut state = State::load(&ctx.state_dir, ctx.state_format)?;
+    let mut summary = Summary::default();
+    let (skipped, repos): (Vec<_>, Vec<_>) = config
+        .repos()?
+        .into_iter()
+        .partition(RemaConfig::skip_build);
+    for repo in &skipped {
+        summary.record(repo, Outcome::Skipped, None);
+    }
+    let rebuilt = build_map(ctx, repos, |repo| {
+        events::emit(&Event::RepoStart {
+            repo: repo.name(),
+            action: "rebuild",
+        });
+        let _held = repo.hold_resource();
+        repo.start_clock();
+        let (outcome, error) = rebuild_one(&repo);
+        repo.flush_output();
+        (repo, outcome, error)
+    });
+
+    for (repo, outcome, error) in rebuilt {
+        // a clean failure leaves the repo as it was
+        if outcome != Outcome::CleanFailed {
+            record_failure(&mut state.failed, &repo, None, timed_out(&repo, outcome));
+            record_good(&mut state.built, &repo, outcome);
+            state.updates.remove(repo.path());
+        }
+        finish(&mut summary, &repo, outcome, error);
+    }
+    history::record(
+        &mut state.history,
+        &summary,
+        "rebuild",
+        history::now(),
+        config.history_len(),
+    );
+    state.save(&ctx.state_dir, ctx.state_format)?;
+    finish_run(ctx, &summary, started, false)
+}
+
+// a one-off build of another commit, the update state is left alone
+pub(crate) fn build(config: &Config, ctx: &Ctx, m: &ArgMatches<'_>) -> Result<(), failure::Error> {
+    let repo = find_repo(config, m.value_of("ONLY").unwrap_or_default())?;
+    let rev = m.value_of("AT").unwrap_or("HEAD");
+    // a pull mid-build would move the HEAD build_at puts back
+    let _lock = ctx.lock()?;
+    events::emit(&Event::RepoStart {
+        repo: repo.name(),
+        action: "build",
+    });
+    let held = repo.hold_resource();
+    repo.start_clock();
+    let res = repo.build_at(rev);
+    drop(held);
+    let (outcome, error) = match &res {
+        Ok(_) => (Outcome::Updated, None),
+        Err(e) => (
+            Outcome::Failed,
+            Some(failure(&repo, "build_failed", "build", &e)),
+        ),
+    };
+    let mut summary = Summary::default();
+    finish(&mut summary, &repo, outcome, error);
+    if let (Ok(oid), false) = (res, events::enabled()) {
+        println!("{}: built {}", repo.name(), oid);
+    }
+    print_summary(&summary, ctx.group_by, false);
+    write_report(ctx, &summary)?;
+    dump_failures(ctx, &summary)?;
+    bail_on_failures(&summary)
+}
+
+fn rebuild_one(repo: &RemaConfig) -> (Outcome, Option<String>) {
+    if let Err(e) = repo.clean() {
+        return (
+            Outcome::CleanFailed,
+            Some(failure(repo, "clean_failed", "clean", &e)),
+        );
+    }
+    match repo.build(None) {
+        Ok(()) => (Outcome::Updated, None),
+        Err(e) => (
+            Outcome::Failed,
+            Some(failure(repo, "build_failed", "build", &e)),
+        ),
+    }
+}
+
+// --parallel-clean cleans repos on the build threads, each repo's own clean
+// commands still run one after another
+pub(crate) fn clean(config: &Config, ctx: &Ctx, m: &ArgMatches<'_>) -> Result<(), failure::Error> {
+    let _lock = ctx.lock()?;
+    let started = Instant::now();
+    let repos = config.repos()?;
+    let mut summary = Summary::default();
+    if m.is_present("PARALLEL_CLEAN") {
+        let cleaned = build_map(ctx, repos, |repo| {
+            let (outcome, error) = clean_one(&repo);
+            repo.flush_output();
+            (repo, outcome, error)
+        });
+        for (repo, outcome, error) in cleaned {
+            finish(&mut summary, &repo, outcome, error);
+        }
+    } else {
+        for repo in repos {
+            let (outcome, error) = clean_one(&repo);
+            finish(&mut summary, &repo, outcome, error);
+        }
+    }
+    finish_run(ctx, &summary, started, false)?;
+    bail_on_failures(&summary)
+}
+
+fn clean_one(repo: &RemaConfig) -> (Outcome, Option<String>) {
+    events::emit(&Event::RepoStart {
+        repo: repo.name(),
+        action: "clean",
+    });
+    repo.start_clock();
+    match repo.clean() {
+        Ok(()) => (Outcome::Updated, None),
+        Err(e) => (
+            Outcome::Failed,
+            Some(failure(repo, "clean_failed", "clean", &e)),
+        ),
+    }
+}
+
+pub(crate) fn status(config: &Config, ctx: &Ctx, m: &ArgMatches<'_>) -> Result<(), failure::Error> {
+    if config.local_only && m.is_present("CHECK_REMOTES") {
+        warn!("--local-only, not checking remotes");
+    }
+    let mut statuses = Vec::new();
+    for repo in config.repos()? {
+        let res = RepoStatus::of(&repo).and_then(|mut s| {
+            if m.is_present("CHECK_REMOTES") && !config.local_only {
+                s.check_remotes(&repo)?;
+            }
+            Ok(s)
+        });
+        match res {
+            Ok(s) => statuses.push(s),
+            Err(e) => warn!(repo = %repo.name(), "could not read status: {}", e),
+        }
+    }
+    if m.is_present("COUNT") {
+        print!("{}", status::render_count(&statuses));
+    } else if m.is_present("COMPACT") {
+        print!("{}", status::render_compact(&statuses, ctx.color));
+    } else {
+        print!("{}", status::render_table(&statuses, ctx.color));
+    }
+    Ok(())
+}
+
+// what a pull would bring in going by the refs already fetched, no network
+pub(crate) fn pending(config: &Config) -> Result<(), failure::Error> {
+    let mut statuses = Vec::new();
+    for repo in config.repos()? {
+        match RepoStatus::of(&repo) {
+            Ok(s) => statuses.push(s),
+            Err(e) => warn!(repo = %repo.name(), "could not read status: {}", e),
+        }
+    }
+    print!("{}", status::render_pending(&statuses));
+    Ok(())
+}
+
+pub(crate) fn history(ctx: &Ctx, m: &ArgMatches<'_>) -> Result<(), failure::Error> {
+    let state = State::load(&ctx.state_dir, ctx.state_format)?;
+    let last = m.value_of("LAST").and_then(|n| n.parse().ok());
+    let picked = history::select(&state.history, m.value_of("REPO"), last);
+    if m.value_of("FORMAT") == Some("json") {
+        println!("{}", serde_json::to_string_pretty(&picked)?);
+    } else {
+        print!("{}", history::render(&picked));
+    }
+    Ok(())
+}
+
+pub(crate) fn verify(config: &Config) -> Result<(), failure::Error> {
+    let mut drifted = 0;
+    for repo in config.repos()? {
+        match verify::check(&repo) {
+            Ok(drift) if drift.is_empty() => {}
+            Ok(drift) => {
+                drifted += 1;
+                for d in drift {
+                    println!("{}: {}", repo.name(), d);
+                }
+            }
+            Err(e) => {
+                drifted += 1;
+                error!(repo = %repo.name(), event = "verify_failed", "could not verify: {}", e);
+            }
+        }
+    }
+    if drifted > 0 {
+        failure::bail!("{} repos are off their pin", drifted);
+    }
+    Ok(())
+}
+
+pub(crate) fn clone(config: &Config, m: &ArgMatches<'_>) -> Result<(), failure::Error> {
+    if config.local_only && !m.is_present("DRY_RUN") {
+        failure::bail!("clone needs the network, it can't run with --local-only");
+    }
+    let missing = config.missing_clones();
+    if m.is_present("DRY_RUN") {
+        for (name, url, path) in &missing {
+            println!("would clone {} from {} into {}", name, url, path.display());
+        }
+        return Ok(());
+    }
+    let mut failed = 0;
+    for (name, url, path) in missing {
+        info!(
+            repo = name,
+            event = "clone",
+            "cloning {} into {}",
+            url,
+            path.display()
+        );
+        if let Err(e) = config.clone_repo(url, &path) {
+            error!(repo = name, event = "clone_failed", "clone failed: {}", e);
+            failed += 1;
+        }
+    }
+    if failed > 0 {
+        failure::bail!("{} repos could not be cloned", failed);
+    }
+    Ok(())
+}
+
+// Repos under [clone] or in the state that have gone from disk. With
+// --auto-clone the [clone] ones are cloned back, the rest are reported
+// missing and dropped from the state.
+fn handle_missing(config: &Config, ctx: &Ctx, state: &mut State, summary: &mut Summary) {
+    let mut missing = BTreeSet::new();
+    for (name, url, path) in config.missing_clones() {
+        if (ctx.auto_clone || config.auto_clone) && !config.local_only {
+            info!(
+                repo = name,
+                event = "clone",
+                "{} is missing, cloning {}",
+                path.display(),
+                url
+            );
+            match config.clone_repo(url, &path) {
+                Ok(_) => continue,
+                Err(e) => error!(repo = name, event = "clone_failed", "clone failed: {}", e),
+            }
+        }
+        warn!(
+            repo = name,
+            event = "missing",
+            "{} is missing",
+            path.display()
+        );
+        missing.insert(name.to_string());
+    }
+
+    let gone = state
+        .updates
+        .keys()
+        .chain(state.applied.keys())
+        .chain(state.failed.keys())
+        .chain(state.hashes.keys())
+        .filter(|p| !p.exists())
+        .cloned()
+        .collect::<BTreeSet<_>>();
+    for p in gone {
+        state.updates.remove(&p);
+        state.applied.remove(&p);
+        state.failed.remove(&p);
+        state.hashes.remove(&p);
+        // the key is the .git dir, except for bare repos
+        let dir = if p.ends_with(".git") {
+            p.parent().unwrap_or(&p)
+        } else {
+            &p
+        };
+        let name = dir.file_name().map_or_else(
+            || dir.display().to_string(),
+            |n| n.to_string_lossy().into_owned(),
+        );
+        if missing.insert(name.clone()) {
+            warn!(repo = %name, event = "missing", "{} is missing", dir.display());
+        }
+    }
+    for name in &missing {
+        summary.record_missing(name);
+    }
+}
+
+fn find_repo(config: &Config, name: &str) -> Result<RemaConfig, failure::Error> {
+    config
+        .repos()?
+        .into_iter()
+        .find(|r| r.name() == name)
+        .ok_or_else(|| failure::format_err!("no repo named {:?}", name))
+}
+
+pub(crate) fn inspect(m: &ArgMatches<'_>) -> Result<(), failure::Error> {
+    let url = m.value_of("URL").unwrap_or_default();
+    print!("{}", inspect_url(url, &env::temp_dir())?);
+    Ok(())
+}
+
+// the clone lives under `tmp` only until this returns, error or not
+fn inspect_url(url: &str, tmp: &Path) -> Result<String, failure::Error> {
+    if !git::valid_url(url) {
+        failure::bail!("{:?} is not a valid git url", url);
+    }
+    let dir = tempfile::Builder::new()
+        .prefix("rema-inspect-")
+        .tempdir_in(tmp)?;
+    let name = url
+        .trim_end_matches('/')
+        .rsplit(['/', ':'])
+        .next()
+        .map(|n| n.trim_end_matches(".git"))
+        .filter(|n| !n.is_empty())
+        .unwrap_or("repo");
+    let path = dir.path().join(name);
+    git::clone(url, &path, None)
+        .map_err(|e| failure::format_err!("could not clone {}: {}", url, e))?;
+    let conf = RemaConfig::load(path, false)?;
+    Ok(explain::render_remote(url, &conf))
+}
+
+pub(crate) fn explain(config: &Config, m: &ArgMatches<'_>) -> Result<(), failure::Error> {
+    let repo = find_repo(config, m.value_of("REPO").unwrap_or_default())?;
+    let status = RepoStatus::of(&repo)?;
+    print!("{}", explain::render(&repo, &status));
+    if m.is_present("DUMP_ENV") {
+        print!("{}", explain::render_env(&repo));
+    }
+    Ok(())
+}
+
+// `root` is the global config, upgraded when no FILE is given
+pub(crate) fn upgrade_config(
+    root: &Path,
+    ctx: &Ctx,
+    m: &ArgMatches<'_>,
+) -> Result<(), failure::Error> {
+    let (path, kind) = match m.value_of("FILE") {
+        Some(f) => (Path::new(f), Kind::Repo),
+        None => (root, Kind::Root),
+    };
+    let mut v: toml::Value = toml::from_str(&fs::read_to_string(path)?)?;
+    let from = migrate::upgrade(&mut v, kind)
+        .map_err(|e| failure::format_err!("in {}: {}", path.display(), e))?;
+    let out = toml::to_string(&v)?;
+    if !m.is_present("WRITE") {
+        print!("{out}");
+    } else if from == migrate::VERSION {
+        info!("{} is already at version {}", path.display(), from);
+    } else if !prompt::confirm(
+        &format!("replace {}, dropping its comments?", path.display()),
+        ctx.assume_yes,
+    )? {
+        info!("left {} as it was", path.display());
+    } else {
+        fs::write(path, out)?;
+        info!(
+            event = "config_upgraded",
+            "upgraded {} from version {} to {}",
+            path.display(),
+            from,
+            migrate::VERSION
+        );
+    }
+    Ok(())
+}
+
+pub(crate) fn plan(config: &Config, m: &ArgMatches<'_>) -> Result<(), failure::Error> {
+    let current = plan::of(&config.repos()?);
+    let Some(p) = m.value_of("COMPARE_PLAN") else {
+        println!("{}", serde_json::to_string_pretty(&current)?);
+        return Ok(());
+    };
+    let saved: plan::Plan = serde_json::from_str(&fs::read_to_string(p)?)?;
+    let diff = plan::diff(&saved, &current);
+    if !diff.is_empty() {
+        print!("{diff}");
+        failure::bail!("the plan drifted from {}", p);
+    }
+    Ok(())
+}
+
+pub(crate) fn export(config: &Config) -> Result<(), failure::Error> {
+    print!("{}", export::script(&config.repos()?));
+    Ok(())
+}
+
+pub(crate) fn list_tags(config: &Config, m: &ArgMatches<'_>) -> Result<(), failure::Error> {
+    let sort = m
+        .value_of("SORT")
+        .unwrap_or("name")
+        .parse::<TagSort>()
+        .map_err(failure::err_msg)?;
+    let repos = config.repos()?;
+    print!(
+        "{}",
+        report::render_tags(repos.iter().map(RemaConfig::tags), sort)
+    );
+    Ok(())
+}
+
+fn finish(summary: &mut Summary, repo: &RemaConfig, outcome: Outcome, error: Option<String>) {
+    let outcome = timed_out(repo, outcome);
+    repo.flush_output();
+    events::emit(&Event::RepoEnd {
+        repo: repo.name(),
+        outcome,
+    });
+    summary.record(repo, outcome, error);
+}
+
+// log why a repo failed, the message is kept for --report
+fn failure(repo: &RemaConfig, event: &str, what: &str, e: &dyn std::fmt::Display) -> String {
+    let msg = format!("{what} failed: {e}");
+    error!(repo = %repo.name(), event, "{}", msg);
+    msg
+}
+
+// a failed pre_pull, or how the fetch went and whether HEAD moved
+type Pulled = Result<Result<bool, git2::Error>, CommandError>;
+
+// pull_one, again while repo_retries allows; HEAD moved by a failed attempt
+// still counts as an update
+fn pull_retrying(repo: &RemaConfig, url: Option<&str>, changed_files: bool) -> Pulled {
+    let old = repo.head();
+    loop {
+        let pulled = pull_one(repo, url, changed_files);
+        let why = match &pulled {
+            Ok(Ok(moved)) => return Ok(Ok(*moved || repo.head() != old)),
+            Ok(Err(e)) => e.to_string(),
+            Err(e) => e.to_string(),
+        };
+        if !repo.retry_attempt(&why) {
+            return pulled;
+        }
+    }
+}
+
+// a pull's build, and with repo_retries the pull and build again after it
+// fails; when that pull fails the HEAD already pulled is built again
+fn build_retrying(
+    repo: &RemaConfig,
+    old: Option<git2::Oid>,
+    last: Option<git2::Oid>,
+    apply_once: bool,
+    url: Option<&str>,
+    changed_files: bool,
+) -> Built {
+    loop {
+        let built = build_then_hooks(repo, repo.autoupdate_build(old), last, apply_once);
+        let why = match &built {
+            Built::Ok(_) => return built,
+            Built::BuildFailed(e) | Built::HooksFailed(e) => e.to_string(),
+        };
+        if !repo.retry_attempt(&why) {
+            return built;
+        }
+        match pull_one(repo, url, changed_files) {
+            Ok(Ok(_)) => {}
+            Ok(Err(e)) => warn!(repo = %repo.name(), "pull failed, building again anyway: {}", e),
+            Err(e) => warn!(repo = %repo.name(), "pre_pull failed, building again anyway: {}", e),
+        }
+    }
+}
+
+// pull --print0, stdout is kept for the paths
+fn print0(m: &ArgMatches<'_>) -> Result<bool, failure::Error> {
+    if !m.is_present("PRINT0") {
+        return Ok(false);
+    }
+    if events::enabled() {
+        failure::bail!("--print0 and --json-lines both want stdout");
+    }
+    output::reserve_stdout();
+    Ok(true)
+}
+
+// fetch everything first, network bound so it gets its own job count
+fn pull_all(
+    repos: Vec<RemaConfig>,
+    ctx: &Ctx,
+    urls: &HashMap<&str, &str>,
+    changed_files: bool,
+) -> Vec<(RemaConfig, Option<git2::Oid>, Pulled)> {
+    par_map(repos, ctx.fetch_jobs, |repo| {
+        events::emit(&Event::RepoStart {
+            repo: repo.name(),
+            action: "pull",
+        });
+        repo.start_clock();
+        let old = repo.head();
+        let pulled = pull_retrying(&repo, urls.get(repo.name()).copied(), changed_files);
+        (repo, old, pulled)
+    })
+}
+
+// pull --prefetch-then-prompt, run pre_pull and fetch every repo without
+// touching HEAD, list what the pull would bring in and ask once before
+// anything is merged; the repos to pull, which merge what was fetched here,
+// or None when declined. Off a terminal confirm doesn't ask, so it's a plain
+// pull.
+fn prefetch_then_prompt<F>(
+    repos: Vec<RemaConfig>,
+    urls: &HashMap<&str, &str>,
+    ctx: &Ctx,
+    ask: F,
+) -> Result<Option<Vec<RemaConfig>>, failure::Error>
+where
+    F: FnOnce(&str) -> io::Result<bool>,
+{
+    let (repos, statuses): (Vec<_>, Vec<_>) = par_map(repos, ctx.fetch_jobs, |repo| {
+        if let Err(e) = repo.prefetch(urls.get(repo.name()).copied()) {
+            warn!(repo = %repo.name(), event = "fetch_failed", "prefetch failed: {}", e);
+        }
+        let status = RepoStatus::of(&repo)
+            .map_err(|e| warn!(repo = %repo.name(), "could not read status: {}", e))
+            .ok();
+        (repo, status)
+    })
+    .into_iter()
+    .unzip();
+    let statuses = statuses.into_iter().flatten().collect::<Vec<_>>();
+    let pending = status::pending(&statuses);
+    if pending.is_empty() {
+        return Ok(Some(repos));
+    }
+    let name_w = pending.iter().map(|(n, _)| n.len()).max().unwrap_or(0);
+    for (name, behind) in &pending {
+        eprintln!("{name:<name_w$}  {behind} new commits");
+    }
+    let total = pending.iter().map(|(_, b)| b).sum::<usize>();
+    let go = ask(&format!(
+        "merge {total} commits into {} repos and build them?",
+        pending.len()
+    ))?;
+    if !go {
+        info!(event = "declined", "declined, nothing merged");
+        return Ok(None);
+    }
+    Ok(Some(repos))
+}
+
+// run pre_pull hooks then fetch, the outer error is a failed hook in which
+// case nothing was fetched
+fn pull_one(repo: &RemaConfig, url: Option<&str>, changed_files: bool) -> Pulled {
+    repo.pre_pull()?;
+    let old = repo.head();
+    let pulled = repo.pull(url);
+    if changed_files && matches!(pulled, Ok(true)) {
+        report_changes(repo, old);
+    }
+    Ok(pulled)
+}
+
+// With since_tag a build is due only for a new matching tag, moved branch or
+// not; `held` is whether the branch moved without one
+fn since_tag_gate(repo: &RemaConfig, tags: &git::TagUpdates, pulled: Pulled) -> (Pulled, bool) {
+    let Some(glob) = repo.since_tag() else {
+        return (pulled, false);
+    };
+    if let Some(tag) = tags.matching(glob) {
+        info!(
+            repo = %repo.name(),
+            event = "tag_trigger",
+            "new tag {} matches {}, building",
+            tag,
+            glob
+        );
+        return (pulled.map(|r| r.map(|_| true)), false);
+    }
+    let moved = matches!(pulled, Ok(Ok(true)));
+    if moved {
+        info!(
+            repo = %repo.name(),
+            event = "skip",
+            "skip: pulled, but no new tag matches {}",
+            glob
+        );
+    }
+    (pulled.map(|r| r.map(|_| false)), moved)
+}
+
+fn report_changes(repo: &RemaConfig, old: Option<git2::Oid>) {
+    let (Some(old), Some(new)) = (old, repo.head()) else {
+        return;
+    };
+    let changes = match git::file_changes(repo.repo(), old, new) {
+        Ok(c) => c,
+        Err(e) => {
+            warn!(repo = %repo.name(), "could not diff {}..{}: {}", old, new, e);
+            return;
+        }
+    };
+    if events::enabled() {
+        let shown = changes.len().min(report::MAX_CHANGED_FILES);
+        events::emit(&Event::ChangedFiles {
+            repo: repo.name(),
+            files: &changes[..shown],
+            truncated: changes.len() - shown,
+        });
+    } else {
+        print!(
+            "{}",
+            report::render_changes(repo.name(), &changes, report::MAX_CHANGED_FILES)
+        );
+    }
+}
+
+// fetch failures aren't recorded, the next pull retries those anyway
+fn record_failure(
+    failed: &mut Oids,
+    repo: &RemaConfig,
+    since: Option<git2::Oid>,
+    outcome: Outcome,
+) {
+    match outcome {
+        Outcome::Failed | Outcome::TimedOut => {
+            // keep the oldest HEAD so if_changed still sees every change
+            failed.entry(repo.path().to_path_buf()).or_insert(since);
+        }
+        Outcome::Updated | Outcome::UpToDate => {
+            failed.remove(repo.path());
+        }
+        Outcome::PrePullFailed | Outcome::Skipped | Outcome::CleanFailed | Outcome::Missing => {}
+    }
+}
+
+// a failure after the repo ran out of time is reported as the timeout
+fn timed_out(repo: &RemaConfig, outcome: Outcome) -> Outcome {
+    if outcome == Outcome::Failed && repo.timed_out() {
+        Outcome::TimedOut
+    } else {
+        outcome
+    }
+}
+
+// everything a pull, update or rebuild ends with once the state is saved
+fn finish_run(
+    ctx: &Ctx,
+    summary: &Summary,
+    started: Instant,
+    count: bool,
+) -> Result<(), failure::Error> {
+    print_summary(summary, ctx.group_by, count);
+    if ctx.benchmark {
+        print_benchmark(summary, started.elapsed());
+    }
+    write_report(ctx, summary)?;
+    dump_failures(ctx, summary)?;
+    if let Some(n) = &ctx.notify {
+        n.after(summary, started.elapsed());
+    }
+    Ok(())
+}
+
+// the run itself fails when any of its repos did
+fn bail_on_failures(summary: &Summary) -> Result<(), failure::Error> {
+    let failed = summary.failed().collect::<Vec<_>>();
+    if !failed.is_empty() {
+        failure::bail!("{} repos failed: {}", failed.len(), failed.join(", "));
+    }
+    Ok(())
+}
+
+// --report, the summary as junit or json, written after every run
+fn write_report(ctx: &Ctx, summary: &Summary) -> Result<(), failure::Error> {
+    match &ctx.report {
+        Some((ReportFormat::Junit, p)) => fs::write(p, summary.junit())?,
+        Some((ReportFormat::Json, p)) => {
+            fs::write(p, serde_json::to_string_pretty(&summary.json())?)?;
+        }
+        None => {}
+    }
+    Ok(())
+}
+
+// written only when something failed, a stale list from an earlier run is
+// left alone
+fn dump_failures(ctx: &Ctx, summary: &Summary) -> Result<(), failure::Error> {
+    let Some(p) = &ctx.dump_failures else {
+        return Ok(());
+    };
+    let mut failed = summary.failed().peekable();
+    if failed.peek().is_some() {
+        repo_list::write(p, failed)?;
+        info!(
+            event = "failures_dumped",
+            "failed repos listed in {}",
+            p.display()
+        );
+    }
+    Ok(())
+}
+
+// --print0, paths as raw bytes so any name survives
+fn nul_separated(paths: &[PathBuf]) -> Vec<u8> {
+    let mut out = Vec::new();
+    for p in paths {
+        out.extend_from_slice(p.as_os_str().as_encoded_bytes());
+        out.push(0);
+    }
+    out
+}
+
+// not when stdout is reserved for the event stream or --print0
+fn print_summary(summary: &Summary, group_by: GroupBy, count: bool) {
+    if output::stdout_reserved() {
+        return;
+    }
+    if count {
+        print!("{}", summary.render_count());
+    } else {
+        print!("{}", summary.render(group_by));
+    }
+}
+
+fn print_benchmark(summary: &Summary, wall: Duration) {
+    let stats = bench::stats(summary.build_times());
+    let table = bench::render(
+        stats.as_ref(),
+        wall,
+        summary.summed(),
+        bench::children_cpu(),
+    );
+    if output::stdout_reserved() {
+        eprint!("{table}");
+    } else {
+        print!("{table}");
+    }
+}
+
+// --repo-url name=url pairs, checked against the configured repos
+fn url_overrides<'a>(
+    m: &'a ArgMatches<'_>,
+    repos: &[RemaConfig],
+) -> Result<HashMap<&'a str, &'a str>, failure::Error> {
+    let mut urls = HashMap::new();
+    for v in m.values_of("REPO_URL").into_iter().flatten() {
+        let (name, url) = v
+            .split_once('=')
+            .ok_or_else(|| failure::format_err!("--repo-url expects name=url, got {:?}", v))?;
+        if !repos.iter().any(|r| r.name() == name) {
+            failure::bail!("--repo-url: no repo named {:?}", name);
+        }
+        if !git::valid_url(url) {
+            failure::bail!("--repo-url: {:?} is not a valid git url", url);
+        }
+        urls.insert(name, url);
+    }
+    Ok(urls)
+}
+
+// How a repo's build and post_update hooks went, worked out on a build
+// thread and recorded once every build is done
+enum Built {
+    // the commit the hooks ran for, None if they were skipped
+    Ok(Option<git2::Oid>),
+    BuildFailed(CommandError),
+    HooksFailed(CommandError),
+}
+
+// run the repo's post_update hooks after a successful build, `last` is the
+// commit they last ran for
+fn build_then_hooks(
+    repo: &RemaConfig,
+    build: Result<(), CommandError>,
+    last: Option<git2::Oid>,
+    apply_once: bool,
+) -> Built {
+    let res = match build {
+        Ok(()) => match repo.post_update(last, apply_once) {
+            Ok(oid) => Built::Ok(oid),
+            Err(e) => Built::HooksFailed(e),
+        },
+        Err(e) => Built::BuildFailed(e),
+    };
+    // so grouped output comes out in the order repos finish
+    repo.flush_output();
+    res
+}
+
+// --hash-cache, None when it's off or the inputs couldn't be hashed
+fn input_hash(ctx: &Ctx, repo: &RemaConfig) -> Option<String> {
+    if !ctx.hash_cache {
+        return None;
+    }
+    repo.input_hash()
+        .map_err(|e| warn!(repo = %repo.name(), "could not hash build inputs: {}", e))
+        .ok()
+}
+
+// whether the inputs hash the same as for the last good build, logging the
+// skip if so
+fn unchanged(hashes: &BTreeMap<PathBuf, String>, repo: &RemaConfig, hash: Option<&str>) -> bool {
+    let same = hash.is_some() && hashes.get(repo.path()).map(String::as_str) == hash;
+    if same {
+        info!(
+            repo = %repo.name(),
+            event = "skip",
+            "skip: build inputs unchanged since the last good build"
+        );
+    }
+    same
+}
+
+fn record_hash(
+    hashes: &mut BTreeMap<PathBuf, String>,
+    repo: &RemaConfig,
+    outcome: Outcome,
+    hash: Option<String>,
+) {
+    if let (Outcome::Updated, Some(h)) = (outcome, hash) {
+        hashes.insert(repo.path().to_path_buf(), h);
+    }
+}
+
+fn record_good(good: &mut Oids, repo: &RemaConfig, outcome: Outcome) {
+    if outcome == Outcome::Updated {
+        good.insert(repo.path().to_path_buf(), repo.head());
+        if let Err(e) = repo.write_built_commit() {
+            warn!(
+                repo = %repo.name(),
+                event = "built_commit_file",
+                "could not write the built commit: {}",
+                e
+            );
+        }
+    }
+}
+
+// build_on_first_run, an up to date pull of a repo with no good build yet
+// counts as an update from nowhere, so every command runs
+fn first_run(
+    good: &Oids,
+    repo: &RemaConfig,
+    pulled: Pulled,
+    old: Option<git2::Oid>,
+    held: bool,
+) -> (Pulled, Option<git2::Oid>) {
+    let never = matches!(pulled, Ok(Ok(false)))
+        && !held
+        && repo.build_on_first_run()
+        && !good.contains_key(repo.path());
+    if !never {
+        return (pulled, old);
+    }
+    info!(
+        repo = %repo.name(),
+        event = "first_run",
+        "up to date but never built, building"
+    );
+    (Ok(Ok(true)), None)
+}
+
+fn record_built(repo: &RemaConfig, built: Built, applied: &mut Oids) -> (Outcome, Option<String>) {
+    match built {
+        Built::Ok(Some(oid)) => {
+            applied.insert(repo.path().to_path_buf(), Some(oid));
+            (Outcome::Updated, None)
+        }
+        Built::Ok(None) => (Outcome::Updated, None),
+        Built::BuildFailed(e) => (
+            Outcome::Failed,
+            Some(failure(repo, "build_failed", "build", &e)),
+        ),
+        Built::HooksFailed(e) => (
+            Outcome::Failed,
+            Some(failure(repo, "post_update_failed", "post_update", &e)),
+        ),
+    }
+}
+
+// map `f` over `items` on up to `jobs` threads, results keep the order of
+// `items`
+fn par_map<T, R, F>(items: Vec<T>, jobs: usize, f: F) -> Vec<R>
+where
+    T: Send,
+    R: Send,
+    F: Fn(T) -> R + Sync,
+{
+    par_map_limited(items, jobs, &|| jobs, f)
+}
+
+// par_map for builds, held back by --concurrency-from-load
+fn build_map<T, R, F>(ctx: &Ctx, items: Vec<T>, f: F) -> Vec<R>
+where
+    T: Send,
+    R: Send,
+    F: Fn(T) -> R + Sync,
+{
+    match ctx.load_cap {
+        Some(cap) => par_map_limited(items, ctx.build_jobs, &|| cap.allowed(ctx.build_jobs), f),
+        None => par_map(items, ctx.build_jobs, f),
+    }
+}
+
+// how often a thread held back by the limit looks again
+const LIMIT_POLL: Duration = Duration::from_millis(250);
+
+// par_map where `limit` is asked before each item starts how many may run at
+// once, at most `jobs`
+fn par_map_limited<T, R, F>(
+    items: Vec<T>,
+    jobs: usize,
+    limit: &(dyn Fn() -> usize + Sync),
+    f: F,
+) -> Vec<R>
+where
+    T: Send,
+    R: Send,
+    F: Fn(T) -> R + Sync,
+{
+    if jobs < 2 || items.len() < 2 {
+        return items.into_iter().map(f).collect();
+    }
+    let n = items.len();
+    let queue = Mutex::new(items.into_iter().enumerate());
+    let running = Mutex::new(0);
+    let results = Mutex::new((0..n).map(|_| None).collect::<Vec<_>>());
+    let next = || loop {
+        let mut running = running.lock().unwrap();
+        if *running < limit().max(1) {
+            let item = queue.lock().unwrap().next();
+            if item.is_some() {
+                *running += 1;
+            }
+            return item;
+        }
+        drop(running);
+        thread::sleep(LIMIT_POLL);
+    };
+    thread::scope(|s| {
+        for _ in 0..jobs.min(n) {
+            s.spawn(|| {
+                while let Some((i, item)) = next() {
+                    let r = f(item);
+                    *running.lock().unwrap() -= 1;
+                    results.lock().unwrap()[i] = Some(r);
+                }
+            });
+        }
+    });
+    results
+        .into_inner()
+        .unwrap()
+        .into_iter()
+        .map(|r| r.expect("every item is mapped"))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::tests::{
+        base_config, clone_into, cloned_fixture, commit_all, fixture, track,
+    };
+
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    #[test]
+    fn test_failing_pre_pull_skips_fetch() {
+        let (dir, conf) = fixture(r#"pre_pull = ["false"]"#);
+        // fetching this would fail, so an Ok(Err) means the fetch ran
+        let missing = dir.path().join("missing");
+        track(&conf, missing.to_str().unwrap());
+        assert!(pull_one(&conf, None, false).is_err());
+
+        let (dir, conf) = fixture(r#"pre_pull = ["true"]"#);
+        let missing = dir.path().join("missing");
+        track(&conf, missing.to_str().unwrap());
+        assert!(matches!(pull_one(&conf, None, false), Ok(Err(_))));
+    }
+
+    #[test]
+    fn test_hash_cache() {
+        let (dir, conf) = fixture(r#"build = ["make"]"#);
+        commit_all(conf.repo(), "init");
+        let cached = Ctx {
+            hash_cache: true,
+            ..ctx()
+        };
+        let mut hashes = BTreeMap::new();
+        let hash = input_hash(&cached, &conf);
+        assert!(hash.is_some());
+        assert!(!unchanged(&hashes, &conf, hash.as_deref()));
+        record_hash(&mut hashes, &conf, Outcome::Updated, hash);
+
+        // unchanged inputs skip the build
+        assert!(unchanged(
+            &hashes,
+            &conf,
+            input_hash(&cached, &conf).as_deref()
+        ));
+        fs::write(dir.path().join("rema.toml"), r#"build = ["make all"]"#).unwrap();
+        assert!(!unchanged(
+            &hashes,
+            &conf,
+            input_hash(&cached, &conf).as_deref()
+        ));
+        assert_eq!(input_hash(&ctx(), &conf), None);
+    }
+
+    #[test]
+    fn test_missing_repos() {
+        let base = tempfile::tempdir().unwrap();
+        let (_dir, conf) = fixture(r#"build = ["make"]"#);
+        let config = base_config(base.path(), "[clone]\ngone = \"/srv/gone.git\"\n");
+        let mut state = State::default();
+        state.updates.insert(conf.path().to_path_buf(), None);
+        state.failed.insert("/srv/repos/old/.git".into(), None);
+
+        let mut summary = Summary::default();
+        handle_missing(&config, &ctx(), &mut state, &mut summary);
+        assert_eq!(summary.totals().missing, 2);
+        assert!(summary.failed().next().is_none());
+        assert!(summary.render_count().contains("2 missing"));
+        // repos still on disk are left alone
+        assert_eq!(state.updates.len(), 1);
+        assert!(state.failed.is_empty());
+    }
+
+    #[test]
+    fn test_history_per_run() {
+        let base = tempfile::tempdir().unwrap();
+        let dir = base.path().join("app");
+        let repo = git2::Repository::init(&dir).unwrap();
+        fs::write(dir.join("rema.toml"), r#"build = ["true"]"#).unwrap();
+        commit_all(&repo, "init");
+        let config = base_config(base.path(), "history_len = 5\n");
+        let state_dir = tempfile::tempdir().unwrap();
+        let ctx = state_ctx(state_dir.path());
+
+        rebuild(&config, &ctx).unwrap();
+        rebuild(&config, &ctx).unwrap();
+        let state = State::load(state_dir.path(), StateFormat::Toml).unwrap();
+        let runs = &state.history["app"];
+        assert_eq!(runs.len(), 2);
+        assert!(runs
+            .iter()
+            .all(|r| r.action == "rebuild" && r.outcome == Outcome::Updated));
+    }
+
+    #[test]
+    fn test_fetched_tags_reported() {
+        let cloned = cloned_fixture("fetch_tags = true");
+        let (upstream, conf) = (&cloned.upstream, cloned.load());
+
+        let head = upstream.repo().head().unwrap().peel_to_commit().unwrap();
+        upstream
+            .repo()
+            .tag_lightweight("v1", head.as_object(), false)
+            .unwrap();
+        assert!(!conf.pull(None).unwrap());
+        let mut summary = Summary::default();
+        summary.record_tags(conf.name(), conf.take_tag_updates());
+        finish(&mut summary, &conf, Outcome::UpToDate, None);
+
+        let report = summary.json();
+        assert_eq!(report["tags"]["app"]["created"], serde_json::json!(["v1"]));
+        assert_eq!(report["repos"][0]["outcome"], "up_to_date");
+        assert!(summary
+            .render(GroupBy::None)
+            .starts_with("app: tags created v1\n"));
+
+        // and gone again, kept without prune_tags
+        upstream.repo().tag_delete("v1").unwrap();
+        conf.pull(None).unwrap();
+        assert!(conf.take_tag_updates().is_empty());
+        fs::write(
+            cloned.dir.join("rema.toml"),
+            "fetch_tags = true\nprune_tags = true",
+        )
+        .unwrap();
+        let conf = cloned.load();
+        conf.pull(None).unwrap();
+        assert_eq!(conf.take_tag_updates().deleted, ["v1"]);
+    }
+
+    #[test]
+    fn test_inspect_url() {
+        let (up, _) = fixture(
+            r#"
+                build = ["make"]
+                clean = ["make clean"]
+                autoclean = true
+            "#,
+        );
+        let url = format!("file://{}", up.path().display());
+        let tmp = tempfile::tempdir().unwrap();
+        let out = inspect_url(&url, tmp.path()).unwrap();
+        let name = up.path().file_name().unwrap().to_str().unwrap();
+        assert!(out.starts_with(&format!("{name} ({url})\n")), "{}", out);
+        assert!(
+            out.ends_with("plan:\n  build        make\n  clean        make clean\n"),
+            "{}",
+            out
+        );
+        assert_eq!(fs::read_dir(tmp.path()).unwrap().count(), 0);
+
+        // nothing left behind when there's no rema.toml either
+        fs::remove_file(up.path().join("rema.toml")).unwrap();
+        commit_all(&git2::Repository::open(up.path()).unwrap(), "no config");
+        assert!(inspect_url(&url, tmp.path()).is_err());
+        assert_eq!(fs::read_dir(tmp.path()).unwrap().count(), 0);
+    }
+
+    #[test]
+    fn test_prefetch_then_prompt_declined() {
+        let base = tempfile::tempdir().unwrap();
+        let mut ups = Vec::new();
+        for name in ["app", "lib"] {
+            let (up, upstream, dir) = clone_into(base.path(), name, "");
+            fs::write(up.path().join("new"), name).unwrap();
+            commit_all(upstream.repo(), "new");
+            ups.push((up, dir));
+        }
+        let config = base_config(base.path(), "");
+        let heads = || {
+            config
+                .repos()
+                .unwrap()
+                .iter()
+                .map(RemaConfig::head)
+                .collect::<Vec<_>>()
+        };
+        let before = heads();
+
+        let mut asked = String::new();
+        let go = prefetch_then_prompt(config.repos().unwrap(), &HashMap::new(), &ctx(), |q| {
+            asked = q.to_string();
+            Ok(false)
+        })
+        .unwrap();
+        assert!(go.is_none());
+        assert_eq!(asked, "merge 2 commits into 2 repos and build them?");
+        assert_eq!(heads(), before);
+        for (_, dir) in &ups {
+            assert!(!dir.join("new").exists());
+        }
+    }
+
+    #[test]
+    fn test_prefetch_then_prompt_confirmed() {
+        // mkdir fails when pre_pull runs twice
+        let cloned = cloned_fixture("pre_pull = [\"mkdir ../pre_pulled\"]\n");
+        let (up, upstream) = (&cloned.up, &cloned.upstream);
+        fs::write(up.path().join("new"), "").unwrap();
+        let listed = commit_all(upstream.repo(), "new");
+        let repos =
+            prefetch_then_prompt(vec![cloned.load()], &HashMap::new(), &ctx(), |_| Ok(true))
+                .unwrap()
+                .unwrap();
+        assert!(cloned.base.path().join("pre_pulled").exists());
+
+        // the pull merges what was listed, without fetching again
+        fs::write(up.path().join("newer"), "").unwrap();
+        commit_all(upstream.repo(), "newer");
+        assert!(matches!(pull_one(&repos[0], None, false), Ok(Ok(true))));
+        assert_eq!(repos[0].head(), Some(listed));
+    }
+
+    #[test]
+    fn test_build_on_first_run() {
+        let cloned = cloned_fixture(
+            "autoupdate = true\nbuild_on_first_run = true\nbuild = [\"touch ../built\"]\n",
+        );
+        let (dir, config) = (&cloned.dir, cloned.config(""));
+        let state_dir = tempfile::tempdir().unwrap();
+        let ctx = state_ctx(state_dir.path());
+        let m = clap::App::new("pull").get_matches_from(["pull"]);
+        let built = cloned.base.path().join("built");
+
+        pull(&config, &ctx, &m).unwrap();
+        assert!(built.exists());
+        let state = State::load(state_dir.path(), StateFormat::Toml).unwrap();
+        assert!(state.built.contains_key(&dir.join(".git")));
+
+        fs::remove_file(&built).unwrap();
+        pull(&config, &ctx, &m).unwrap();
+        assert!(!built.exists());
+    }
+
+    #[test]
+    fn test_pull_local_only() {
+        let base = tempfile::tempdir().unwrap();
+        let _app = clone_into(
+            base.path(),
+            "app",
+            "autoupdate = true\nbuild = [\"touch ../built\"]\n",
+        );
+        let _lib = clone_into(base.path(), "lib", "build = [\"touch ../built\"]\n");
+        let mut config = base_config(base.path(), "");
+        config.local_only = true;
+        let state_dir = tempfile::tempdir().unwrap();
+        let ctx = state_ctx(state_dir.path());
+        let m = clap::App::new("pull").get_matches_from(["pull"]);
+
+        pull(&config, &ctx, &m).unwrap();
+        assert!(base.path().join("built").exists());
+        // nothing was pulled, so nothing waits for an update
+        let state = State::load(state_dir.path(), StateFormat::Toml).unwrap();
+        assert!(state.updates.is_empty(), "{:?}", state.updates);
+    }
+
+    #[test]
+    fn test_repo_retries() {
+        // fails until clean has run once
+        let cloned = cloned_fixture(
+            "repo_retries = 2\nrepo_retry_clean = true\nbuild = [\"test -e ../cleaned\"]\nclean = [\"touch ../cleaned\"]\n",
+        );
+        let (base, dir) = (&cloned.base, &cloned.dir);
+        let conf = cloned.load();
+        conf.start_clock();
+        let old = conf.head();
+        assert!(matches!(
+            build_retrying(&conf, old, None, false, None, false),
+            Built::Ok(_)
+        ));
+        assert_eq!(conf.attempts(), 2);
+        // the uncommitted rema.toml wasn't reset between attempts
+        assert!(fs::read_to_string(dir.join("rema.toml"))
+            .unwrap()
+            .contains("repo_retries"));
+        let mut summary = Summary::default();
+        summary.record(&conf, Outcome::Updated, None);
+        assert!(summary.render(GroupBy::None).contains("app: 2 attempts\n"));
+
+        // out of attempts
+        fs::remove_file(base.path().join("cleaned")).unwrap();
+        fs::write(
+            dir.join("rema.toml"),
+            "repo_retries = 1\nbuild = [\"test -e ../cleaned\"]\n",
+        )
+        .unwrap();
+        let conf = cloned.load();
+        assert!(matches!(
+            build_retrying(&conf, old, None, false, None, false),
+            Built::BuildFailed(_)
+        ));
+        assert_eq!(conf.attempts(), 2);
+    }
+
+    #[test]
+    fn test_since_tag() {
+        let cloned = cloned_fixture(r#"since_tag = "v*""#);
+        let (up, upstream, conf) = (&cloned.up, &cloned.upstream, cloned.load());
+        let tag = |name: &str| {
+            let head = upstream.repo().head().unwrap().peel_to_commit().unwrap();
+            upstream
+                .repo()
+                .tag_lightweight(name, head.as_object(), false)
+                .unwrap();
+        };
+        let pull = || {
+            let pulled = pull_one(&conf, None, false);
+            let (pulled, held) = since_tag_gate(&conf, &conf.take_tag_updates(), pulled);
+            (pulled.unwrap().unwrap(), held)
+        };
+
+        // commits alone, or with a tag not matching, hold the build back
+        fs::write(up.path().join("a"), "").unwrap();
+        commit_all(upstream.repo(), "a");
+        tag("nightly");
+        assert_eq!(pull(), (false, true));
+        assert_eq!(pull(), (false, false));
+
+        tag("v1.0");
+        assert_eq!(pull(), (true, false));
+    }
+
+    #[test]
+    fn test_resources_serialize_builds() {
+        let mut dirs = Vec::new();
+        let mut repos = Vec::new();
+        for name in ["test-gpu", "test-gpu", "test-port"] {
+            let (dir, conf) = fixture(&format!("resource = {name:?}"));
+            dirs.push(dir);
+            repos.push((name, conf));
+        }
+        let running = Mutex::new(Vec::new());
+        let overlaps = Mutex::new(Vec::new());
+        build_map(&ctx(), repos, |(name, repo)| {
+            let _held = repo.hold_resource();
+            {
+                let mut running = running.lock().unwrap();
+                let mut overlaps = overlaps.lock().unwrap();
+                overlaps.extend(running.iter().map(|r| (name, *r)));
+                running.push(name);
+            }
+            thread::sleep(Duration::from_millis(50));
+            running.lock().unwrap().retain(|r| *r != name);
+        });
+        let overlaps = overlaps.into_inner().unwrap();
+        // the gpu repos one after the other, next to the port one
+        assert!(overlaps.iter().all(|(a, b)| a != b), "{:?}", overlaps);
+        assert!(!overlaps.is_empty());
+    }
+
+    #[test]
+    fn test_build_at_ref() {
+        let (up, upstream) = fixture(r#"build = ["cp version built"]"#);
+        fs::write(up.path().join("version"), "1").unwrap();
+        let v1 = commit_all(upstream.repo(), "v1");
+        let commit = upstream.repo().find_commit(v1).unwrap();
+        upstream
+            .repo()
+            .tag_lightweight("v1", commit.as_object(), false)
+            .unwrap();
+        fs::write(up.path().join("version"), "2").unwrap();
+        commit_all(upstream.repo(), "v2");
+
+        let base = tempfile::tempdir().unwrap();
+        let dir = base.path().join("app");
+        git2::Repository::clone(up.path().to_str().unwrap(), &dir).unwrap();
+        let conf = RemaConfig::load(dir.clone(), false).unwrap();
+        let (old, built) = build_at_ref(&conf, "v1", &Oids::new(), false);
+        assert!(matches!(built, Built::Ok(_)));
+        assert_ne!(old, Some(v1));
+        assert_eq!(fs::read_to_string(dir.join("built")).unwrap(), "1");
+        assert_eq!(conf.head(), Some(v1));
+        assert!(conf.repo().head_detached().unwrap());
+
+        let (_, built) = build_at_ref(&conf, "no-such-tag", &Oids::new(), false);
+        assert!(matches!(built, Built::BuildFailed(_)));
+
+        // a tag only made here survives its fetch, prune_tags or not
+        let dir = base.path().join("lib");
+        git2::Repository::clone(up.path().to_str().unwrap(), &dir).unwrap();
+        fs::write(dir.join("rema.local.toml"), "prune_tags = true\n").unwrap();
+        let conf = RemaConfig::load(dir.clone(), false).unwrap();
+        let commit = conf.repo().find_commit(v1).unwrap();
+        conf.repo()
+            .tag_lightweight("local", commit.as_object(), false)
+            .unwrap();
+        let (_, built) = build_at_ref(&conf, "local", &Oids::new(), false);
+        assert!(matches!(built, Built::Ok(_)));
+    }
+
+    #[test]
+    fn test_filtered_update_keeps_others_pending() {
+        let base = tempfile::tempdir().unwrap();
+        let (_a_up, _, a) = clone_into(base.path(), "a", "build = [\"touch built\"]\n");
+        let (_b_up, _, b) = clone_into(base.path(), "b", "build = [\"touch built\"]\n");
+        let state_dir = tempfile::tempdir().unwrap();
+        let mut state = State::default();
+        state.updates.insert(a.join(".git"), None);
+        state.updates.insert(b.join(".git"), None);
+        state.save(state_dir.path(), StateFormat::Toml).unwrap();
+        let mut config = base_config(base.path(), "");
+        config.names = Some(vec!["a".into()]);
+        let m = clap::App::new("update").get_matches_from(["update"]);
+
+        update(&config, &state_ctx(state_dir.path()), &m).unwrap();
+        assert!(a.join("built").exists());
+        assert!(!b.join("built").exists());
+        let state = State::load(state_dir.path(), StateFormat::Toml).unwrap();
+        assert_eq!(
+            state.updates.keys().collect::<Vec<_>>(),
+            vec![&b.join(".git")]
+        );
+    }
+
+    #[test]
+    fn test_update_fail_on_dirty() {
+        let base = tempfile::tempdir().unwrap();
+        // clone_into's rema.toml is an uncommitted edit
+        let (_up, _, dir) = clone_into(base.path(), "app", "build = [\"touch built\"]\n");
+        let state_dir = tempfile::tempdir().unwrap();
+        let mut state = State::default();
+        state.updates.insert(dir.join(".git"), None);
+        state.save(state_dir.path(), StateFormat::Toml).unwrap();
+        let config = base_config(base.path(), "");
+        let m = clap::App::new("update")
+            .arg(clap::Arg::with_name("FAIL_ON_DIRTY").long("fail-on-dirty"))
+            .get_matches_from(["update", "--fail-on-dirty"]);
+
+        let err = update(&config, &state_ctx(state_dir.path()), &m).unwrap_err();
+        assert_eq!(err.to_string(), "1 repos failed: app");
+        assert!(!dir.join("built").exists());
+    }
+
+    #[test]
+    fn test_clean_fails_run() {
+        let base = tempfile::tempdir().unwrap();
+        let (_up, _, dir) = clone_into(
+            base.path(),
+            "app",
+            "clean = [\"true\"]\nclean_expect_absent = [\"out.log\"]\n",
+        );
+        fs::write(dir.join("out.log"), "").unwrap();
+        let config = base_config(base.path(), "");
+        let failures = base.path().join("failures");
+        let state_dir = tempfile::tempdir().unwrap();
+        let ctx = Ctx {
+            dump_failures: Some(failures.clone()),
+            ..state_ctx(state_dir.path())
+        };
+        let m = clap::App::new("clean").get_matches_from(["clean"]);
+
+        let err = clean(&config, &ctx, &m).unwrap_err();
+        assert_eq!(err.to_string(), "1 repos failed: app");
+        assert_eq!(fs::read_to_string(&failures).unwrap(), "app\n");
+    }
+
+    #[test]
+    fn test_build_at_fails_run() {
+        let base = tempfile::tempdir().unwrap();
+        let _app = clone_into(base.path(), "app", "build = [\"true\"]\n");
+        let config = base_config(base.path(), "");
+        let m = clap::App::new("build")
+            .arg(clap::Arg::with_name("ONLY").long("only").takes_value(true))
+            .arg(clap::Arg::with_name("AT").long("at").takes_value(true))
+            .get_matches_from(["build", "--only", "app", "--at", "no-such-rev"]);
+
+        let state_dir = tempfile::tempdir().unwrap();
+        let err = build(&config, &state_ctx(state_dir.path()), &m).unwrap_err();
+        assert_eq!(err.to_string(), "1 repos failed: app");
+    }
+
+    #[test]
+    fn test_update_ref_skipped() {
+        let base = tempfile::tempdir().unwrap();
+        let (_up, _, dir) = clone_into(base.path(), "app", "build = [\"touch built\"]\n");
+        fs::write(dir.join(".rema-skip"), "").unwrap();
+        let config = base_config(base.path(), "");
+        let state_dir = tempfile::tempdir().unwrap();
+        let m = clap::App::new("update").get_matches_from(["update"]);
+        update_ref(&config, &state_ctx(state_dir.path()), &m, "HEAD").unwrap();
+        assert!(!dir.join("built").exists());
+        let state = State::load(state_dir.path(), StateFormat::Toml).unwrap();
+        assert_eq!(state.history["app"][0].outcome, Outcome::Skipped);
+        assert!(state.detached.is_empty());
+    }
+
+    #[test]
+    fn test_rebuild_skipped() {
+        let base = tempfile::tempdir().unwrap();
+        let (_up, _, dir) = clone_into(base.path(), "app", "build = [\"touch built\"]\n");
+        fs::write(dir.join(".rema-skip"), "").unwrap();
+        let config = base_config(base.path(), "");
+        let state_dir = tempfile::tempdir().unwrap();
+        rebuild(&config, &state_ctx(state_dir.path())).unwrap();
+        assert!(!dir.join("built").exists());
+        let state = State::load(state_dir.path(), StateFormat::Toml).unwrap();
+        assert_eq!(state.history["app"][0].outcome, Outcome::Skipped);
+    }
+
+    #[test]
+    fn test_rebuild_cleans_first() {
+        let (dir, conf) = fixture(
+            r#"
+                clean = ["rm -f built"]
+                build = ["touch built"]
+            "#,
+        );
+        fs::write(dir.path().join("built"), "stale").unwrap();
+        assert_eq!(rebuild_one(&conf), (Outcome::Updated, None));
+        assert_eq!(fs::read(dir.path().join("built")).unwrap(), b"");
+
+        let (dir, conf) = fixture(
+            r#"
+                clean = ["false"]
+                build = ["touch built"]
+            "#,
+        );
+        assert_eq!(rebuild_one(&conf).0, Outcome::CleanFailed);
+        assert!(!dir.path().join("built").exists());
+    }
+
+    #[test]
+    fn test_nul_separated() {
+        let paths = [PathBuf::from("/srv/my repo"), PathBuf::from("/srv/app")];
+        assert_eq!(nul_separated(&paths), b"/srv/my repo\0/srv/app\0");
+        assert!(nul_separated(&[]).is_empty());
+    }
+
+    #[test]
+    fn test_par_map_keeps_order() {
+        let items = (0..20u64).collect::<Vec<_>>();
+        let out = par_map(items, 4, |i| {
+            // later items finish first
+            thread::sleep(Duration::from_millis(20 - i));
+            i * 2
+        });
+        assert_eq!(out, (0..20).map(|i| i * 2).collect::<Vec<_>>());
+        assert_eq!(par_map(vec![1, 2], 1, |i| i + 1), vec![2, 3]);
+    }
+
+    // ctx() keeping its state in `state_dir`
+    fn state_ctx(state_dir: &Path) -> Ctx {
+        Ctx {
+            state_dir: state_dir.into(),
+            ..ctx()
+        }
+    }
+
+    fn ctx() -> Ctx {
+        Ctx {
+            state_dir: PathBuf::new(),
+            state_format: StateFormat::Toml,
+            group_by: GroupBy::None,
+            fetch_jobs: 4,
+            build_jobs: 4,
+            load_cap: None,
+            dump_failures: None,
+            report: None,
+            ignore_lockfile: false,
+            notify: None,
+            hash_cache: false,
+            auto_clone: false,
+            assume_yes: false,
+            benchmark: false,
+            color: false,
+        }
+    }
+
+    #[test]
+    fn test_load_cap_limits_builds() {
+        let ctx = Ctx {
+            load_cap: Some(LoadCap {
+                target: 1.0,
+                source: || Some(16.0),
+            }),
+            ..ctx()
+        };
+        let running = AtomicUsize::new(0);
+        let most = AtomicUsize::new(0);
+        let out = build_map(&ctx, (0..6).collect(), |i| {
+            let now = running.fetch_add(1, Ordering::SeqCst) + 1;
+            most.fetch_max(now, Ordering::SeqCst);
+            thread::sleep(Duration::from_millis(10));
+            running.fetch_sub(1, Ordering::SeqCst);
+            i
+        });
+        assert_eq!(out, (0..6).collect::<Vec<_>>());
+        // a load of 16 against a target of 1 leaves a single build slot
+        assert_eq!(most.into_inner(), 1);
+    }
+}
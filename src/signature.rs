@@ -0,0 +1,151 @@
+use std::fmt::Write as _;
+use std::io::Write;
+use std::process::{Command, Stdio};
+use tempfile::NamedTempFile;
+
+// Check a commit's signature against trusted_keys and return who signed
+// it. SSH signatures are checked by ssh-keygen against the trusted public
+// keys, GPG ones by gpg against the user's keyring and then the signing
+// key's fingerprint has to be trusted.
+pub(crate) fn verify(signature: &str, data: &[u8], trusted: &[String]) -> Result<String, String> {
+    if signature.starts_with("-----BEGIN SSH SIGNATURE-----") {
+        verify_ssh(signature, data, trusted)
+    } else {
+        verify_gpg(signature, data, trusted)
+    }
+}
+
+fn verify_ssh(signature: &str, data: &[u8], trusted: &[String]) -> Result<String, String> {
+    let mut allowed = String::new();
+    for k in trusted.iter().filter(|k| !is_fingerprint(k)) {
+        let _ = writeln!(allowed, "rema namespaces=\"git\" {k}");
+    }
+    if allowed.is_empty() {
+        return Err("ssh signed, but no ssh key is trusted".into());
+    }
+    let allowed = temp_file("allowed_signers", allowed.as_bytes())?;
+    let sig = temp_file("commit.sig", signature.as_bytes())?;
+    let mut cmd = Command::new("ssh-keygen");
+    cmd.args(["-Y", "verify", "-I", "rema", "-n", "git", "-f"])
+        .arg(allowed.path())
+        .arg("-s")
+        .arg(sig.path());
+    let out = run(cmd, data)?;
+    // Good "git" signature for rema with ED25519 key SHA256:...
+    if !out.status.success() {
+        return Err("not signed by a trusted ssh key".into());
+    }
+    let stdout = String::from_utf8_lossy(&out.stdout);
+    Ok(stdout
+        .split_once(" with ")
+        .map_or_else(|| stdout.trim().to_string(), |(_, k)| k.trim().to_string()))
+}
+
+fn verify_gpg(signature: &str, data: &[u8], trusted: &[String]) -> Result<String, String> {
+    let sig = temp_file("commit.asc", signature.as_bytes())?;
+    let mut cmd = Command::new("gpg");
+    cmd.args(["--status-fd", "1", "--verify"])
+        .arg(sig.path())
+        .arg("-");
+    let out = run(cmd, data)?;
+    let status = String::from_utf8_lossy(&out.stdout);
+    let field = |tag: &str| {
+        status
+            .lines()
+            .find_map(|l| l.strip_prefix("[GNUPG:] ")?.strip_prefix(tag))
+            .map(str::trim)
+    };
+    let (Some(valid), Some(good)) = (field("VALIDSIG "), field("GOODSIG ")) else {
+        return Err("no good gpg signature".into());
+    };
+    let (fingerprint, primary) = validsig_fingerprints(valid);
+    let is_trusted = trusted
+        .iter()
+        .filter(|k| is_fingerprint(k))
+        .map(|k| k.replace(' ', ""))
+        .any(|k| {
+            fingerprint.eq_ignore_ascii_case(&k)
+                || primary.is_some_and(|p| p.eq_ignore_ascii_case(&k))
+        });
+    // GOODSIG <long key id> <user id>
+    let signer = good.split_once(' ').map_or(good, |(_, who)| who);
+    if !is_trusted {
+        return Err(format!(
+            "signed by {signer} ({fingerprint}), which isn't trusted"
+        ));
+    }
+    Ok(format!("{signer} ({fingerprint})"))
+}
+
+// VALIDSIG <fingerprint> <date> <timestamp> <expires> <version> <reserved>
+// <pubkey algo> <hash algo> <class> [<primary key fingerprint>], the
+// primary key's for a signature by a subkey, so trusting a key trusts its
+// subkeys
+fn validsig_fingerprints(valid: &str) -> (&str, Option<&str>) {
+    let mut fields = valid.split_whitespace();
+    let fingerprint = fields.next().unwrap_or_default();
+    (fingerprint, fields.nth(8))
+}
+
+// a GPG fingerprint rather than an ssh public key
+fn is_fingerprint(k: &str) -> bool {
+    k.chars().all(|c| c.is_ascii_hexdigit() || c == ' ')
+}
+
+fn run(mut cmd: Command, stdin: &[u8]) -> Result<std::process::Output, String> {
+    let name = cmd.get_program().to_string_lossy().into_owned();
+    let mut child = cmd
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .map_err(|e| format!("could not run {name}: {e}"))?;
+    if let Some(mut pipe) = child.stdin.take() {
+        pipe.write_all(stdin)
+            .map_err(|e| format!("could not write to {name}: {e}"))?;
+    }
+    child
+        .wait_with_output()
+        .map_err(|e| format!("could not run {name}: {e}"))
+}
+
+// `contents` in a new file only we can open, removed again when dropped
+fn temp_file(name: &str, contents: &[u8]) -> Result<NamedTempFile, String> {
+    let mut f = tempfile::Builder::new()
+        .prefix("rema-")
+        .suffix(&format!("-{name}"))
+        .tempfile()
+        .map_err(|e| format!("could not create a temporary {name}: {e}"))?;
+    f.write_all(contents)
+        .and_then(|()| f.flush())
+        .map_err(|e| format!("could not write {}: {e}", f.path().display()))?;
+    Ok(f)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_is_fingerprint() {
+        assert!(is_fingerprint(
+            "0123 4567 89AB CDEF 0123  4567 89ab cdef 0123 4567"
+        ));
+        assert!(!is_fingerprint(
+            "ssh-ed25519 AAAAC3NzaC1lZDI1NTE5AAAA me@host"
+        ));
+    }
+
+    #[test]
+    fn test_validsig_fingerprints() {
+        let subkey = "AAAA1111 2024-01-01 1704067200 0 4 0 22 10 00 BBBB2222";
+        assert_eq!(
+            validsig_fingerprints(subkey),
+            ("AAAA1111", Some("BBBB2222"))
+        );
+        assert_eq!(
+            validsig_fingerprints("AAAA1111 2024-01-01"),
+            ("AAAA1111", None)
+        );
+    }
+}
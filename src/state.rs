@@ -0,0 +1,258 @@
+use crate::history::History;
+
+use std::collections::BTreeMap;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+use serde::{Deserialize, Serialize};
+use tracing::info;
+
+// repo path -> commit, None when the commit is unknown
+pub(crate) type Oids = BTreeMap<PathBuf, Option<git2::Oid>>;
+
+// bumped whenever State changes in a way older versions can't read
+pub(crate) const VERSION: u32 = 1;
+
+// the line based files used before State existed
+const LEGACY_FILES: [&str; 3] = ["updates", "applied", "failed"];
+
+pub(crate) fn state_dir() -> PathBuf {
+    dirs::data_dir().unwrap_or_default().join("rema")
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) enum StateFormat {
+    Toml,
+    Json,
+}
+
+impl StateFormat {
+    fn file_name(self) -> &'static str {
+        match self {
+            Self::Toml => "state.toml",
+            Self::Json => "state.json",
+        }
+    }
+}
+
+impl FromStr for StateFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "toml" => Ok(Self::Toml),
+            "json" => Ok(Self::Json),
+            _ => Err(format!("unknown state format {s:?}, expected toml or json")),
+        }
+    }
+}
+
+// Everything rema remembers between runs
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+pub(crate) struct State {
+    pub(crate) version: u32,
+    // pulled but not built yet, with HEAD before the pull
+    #[serde(default, with = "oids")]
+    pub(crate) updates: Oids,
+    // the commit post_update hooks last ran for
+    #[serde(default, with = "oids")]
+    pub(crate) applied: Oids,
+    // last build failed, with the HEAD it was building from
+    #[serde(default, with = "oids")]
+    pub(crate) failed: Oids,
+    // --hash-cache, repo path -> hash of the inputs of its last good build
+    #[serde(default)]
+    pub(crate) hashes: BTreeMap<PathBuf, String>,
+    // the last few runs of each repo, for `rema history`
+    #[serde(default)]
+    pub(crate) history: History,
+    // update --ref, repo path -> the ref HEAD was left detached at
+    #[serde(default)]
+    pub(crate) detached: BTreeMap<PathBuf, String>,
+    // the commit of each repo's last good build
+    #[serde(default, with = "oids")]
+    pub(crate) built: Oids,
+}
+
+impl Default for State {
+    fn default() -> Self {
+        Self {
+            version: VERSION,
+            updates: Oids::new(),
+            applied: Oids::new(),
+            failed: Oids::new(),
+            hashes: BTreeMap::new(),
+            history: History::new(),
+            detached: BTreeMap::new(),
+            built: Oids::new(),
+        }
+    }
+}
+
+impl State {
+    pub(crate) fn load(dir: &Path, format: StateFormat) -> Result<Self, failure::Error> {
+        let p = dir.join(format.file_name());
+        let s = match fs::read_to_string(&p) {
+            Ok(s) => s,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Self::migrate(dir, format),
+            Err(e) => return Err(e.into()),
+        };
+        let state: Self = match format {
+            StateFormat::Toml => toml::from_str(&s)?,
+            StateFormat::Json => serde_json::from_str(&s)?,
+        };
+        if state.version > VERSION {
+            failure::bail!(
+                "{} was written by a newer rema (state version {})",
+                p.display(),
+                state.version
+            );
+        }
+        Ok(state)
+    }
+
+    pub(crate) fn save(&self, dir: &Path, format: StateFormat) -> Result<(), failure::Error> {
+        fs::create_dir_all(dir)?;
+        let out = match format {
+            StateFormat::Toml => toml::to_string(self)?,
+            StateFormat::Json => serde_json::to_string_pretty(self)?,
+        };
+        fs::write(dir.join(format.file_name()), out)?;
+        Ok(())
+    }
+
+    // pick up the line based files once, then replace them with State
+    fn migrate(dir: &Path, format: StateFormat) -> Result<Self, failure::Error> {
+        let legacy = LEGACY_FILES.map(|f| dir.join(f));
+        if !legacy.iter().any(|p| p.is_file()) {
+            return Ok(Self::default());
+        }
+        let [updates, applied, failed] = &legacy;
+        let state = Self {
+            updates: read_oids(updates)?,
+            applied: read_oids(applied)?,
+            failed: read_oids(failed)?,
+            ..Self::default()
+        };
+        state.save(dir, format)?;
+        for p in &legacy {
+            if p.is_file() {
+                fs::remove_file(p)?;
+            }
+        }
+        info!(
+            event = "state_migrated",
+            "migrated state to {}",
+            format.file_name()
+        );
+        Ok(state)
+    }
+}
+
+// one repo per line as "<path>\t<oid>", the oid is optional
+fn read_oids(p: &Path) -> io::Result<Oids> {
+    match fs::read_to_string(p) {
+        Ok(s) => Ok(s
+            .lines()
+            .map(|l| match l.split_once('\t') {
+                Some((path, oid)) => (PathBuf::from(path), git2::Oid::from_str(oid).ok()),
+                None => (PathBuf::from(l), None),
+            })
+            .collect()),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(BTreeMap::new()),
+        Err(e) => Err(e),
+    }
+}
+
+// toml has no null so an unknown commit is stored as ""
+mod oids {
+    use super::Oids;
+
+    use std::collections::BTreeMap;
+    use std::path::PathBuf;
+
+    use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+    pub(super) fn serialize<S: Serializer>(oids: &Oids, s: S) -> Result<S::Ok, S::Error> {
+        oids.iter()
+            .map(|(p, oid)| {
+                let oid = oid.map(|o| o.to_string()).unwrap_or_default();
+                (p.to_string_lossy().into_owned(), oid)
+            })
+            .collect::<BTreeMap<_, _>>()
+            .serialize(s)
+    }
+
+    pub(super) fn deserialize<'de, D: Deserializer<'de>>(d: D) -> Result<Oids, D::Error> {
+        let m = BTreeMap::<String, String>::deserialize(d)?;
+        Ok(m.into_iter()
+            .map(|(p, oid)| (PathBuf::from(p), git2::Oid::from_str(&oid).ok()))
+            .collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sample() -> State {
+        let oid = git2::Oid::from_str("0123456789abcdef0123456789abcdef01234567").unwrap();
+        let mut s = State::default();
+        s.updates.insert("/repos/a/.git".into(), Some(oid));
+        s.updates.insert("/repos/b/.git".into(), None);
+        s.applied.insert("/repos/a/.git".into(), Some(oid));
+        s
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let mut state = sample();
+        state.hashes.insert("/repos/a/.git".into(), "abc".into());
+        for format in &[StateFormat::Toml, StateFormat::Json] {
+            let dir = tempfile::tempdir().unwrap();
+            state.save(dir.path(), *format).unwrap();
+            assert_eq!(State::load(dir.path(), *format).unwrap(), state);
+        }
+    }
+
+    #[test]
+    fn test_missing_is_empty() {
+        let dir = tempfile::tempdir().unwrap();
+        let s = State::load(dir.path(), StateFormat::Toml).unwrap();
+        assert_eq!(s, State::default());
+        assert_eq!(s.version, VERSION);
+    }
+
+    #[test]
+    fn test_migrate_line_files() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::write(
+            dir.path().join("updates"),
+            "/repos/a/.git\t0123456789abcdef0123456789abcdef01234567\n/repos/b/.git\n",
+        )
+        .unwrap();
+        fs::write(
+            dir.path().join("applied"),
+            "/repos/a/.git\t0123456789abcdef0123456789abcdef01234567\n",
+        )
+        .unwrap();
+
+        let s = State::load(dir.path(), StateFormat::Json).unwrap();
+        assert_eq!(s, sample());
+        assert!(!dir.path().join("updates").exists());
+        assert!(dir.path().join("state.json").exists());
+        assert_eq!(
+            State::load(dir.path(), StateFormat::Json).unwrap(),
+            sample()
+        );
+    }
+
+    #[test]
+    fn test_newer_version_rejected() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::write(dir.path().join("state.toml"), "version = 99\n").unwrap();
+        assert!(State::load(dir.path(), StateFormat::Toml).is_err());
+    }
+}
@@ -0,0 +1,403 @@
+use crate::color::{self, Tone};
+use crate::config::RemaConfig;
+use crate::git;
+
+use std::fmt::Write;
+use std::path::PathBuf;
+use std::time::Duration;
+
+use git2::{Repository, StatusOptions};
+use tracing::debug;
+
+// how long --check-remotes waits for each remote
+const REMOTE_TIMEOUT: Duration = Duration::from_secs(5);
+
+// Local view of a repo's state, computed from refs already on disk so it
+// doesn't touch the network
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct RepoStatus {
+    pub(crate) name: String,
+    // None when HEAD is detached
+    pub(crate) branch: Option<String>,
+    // None when the branch has no upstream
+    pub(crate) ahead_behind: Option<(usize, usize)>,
+    pub(crate) dirty: bool,
+    // remotes that didn't answer, None unless checked with --check-remotes
+    pub(crate) unreachable: Option<Vec<String>>,
+}
+
+impl RepoStatus {
+    pub(crate) fn of(conf: &RemaConfig) -> Result<Self, git2::Error> {
+        let repo = conf.repo();
+        let head = repo.head()?;
+        let branch = if head.is_branch() {
+            head.shorthand().map(String::from)
+        } else {
+            None
+        };
+
+        Ok(Self {
+            name: conf.name().into(),
+            ahead_behind: branch.as_ref().and_then(|_| ahead_behind(repo, &head)),
+            branch,
+            dirty: is_dirty(repo)?,
+            unreachable: None,
+        })
+    }
+
+    // probe every configured remote, this goes over the network
+    pub(crate) fn check_remotes(&mut self, conf: &RemaConfig) -> Result<(), git2::Error> {
+        let repo = conf.repo();
+        let mut unreachable = Vec::new();
+        for name in repo.remotes()?.iter().flatten() {
+            let remote = repo.find_remote(name)?;
+            let url = remote.url().unwrap_or_default();
+            if let Err(e) = git::probe_remote(url, conf.token_env(), REMOTE_TIMEOUT) {
+                debug!(repo = %conf.name(), remote = name, "unreachable: {}", e);
+                unreachable.push(name.to_string());
+            }
+        }
+        self.unreachable = Some(unreachable);
+        Ok(())
+    }
+
+    fn has_unreachable(&self) -> bool {
+        self.unreachable.as_ref().is_some_and(|u| !u.is_empty())
+    }
+
+    // the short cell used by the compact grid
+    fn symbol(&self) -> String {
+        if self.has_unreachable() {
+            return "!".into();
+        }
+        if self.dirty {
+            return "*".into();
+        }
+        match self.ahead_behind {
+            None => "?".into(),
+            Some((0, 0)) => "✓".into(),
+            Some((0, b)) => format!("↓{b}"),
+            Some((a, 0)) => format!("↑{a}"),
+            Some(_) => "↕".into(),
+        }
+    }
+
+    // how --color shows it: diverged and unreachable need a hand, anything
+    // else off up to date is worth a look
+    fn tone(&self) -> Tone {
+        match self.ahead_behind {
+            _ if self.has_unreachable() => Tone::Bad,
+            Some((a, b)) if a > 0 && b > 0 => Tone::Bad,
+            Some((0, 0)) if !self.dirty => Tone::Good,
+            _ => Tone::Attention,
+        }
+    }
+
+    fn describe(&self) -> String {
+        let mut s = match self.ahead_behind {
+            None if self.branch.is_none() => "detached".to_string(),
+            None => "no upstream".into(),
+            Some((0, 0)) => "up to date".into(),
+            Some((0, b)) => format!("behind {b}"),
+            Some((a, 0)) => format!("ahead {a}"),
+            Some((a, b)) => format!("ahead {a}, behind {b}"),
+        };
+        if self.dirty {
+            s.push_str(", dirty");
+        }
+        if let Some(u) = self.unreachable.as_ref().filter(|u| !u.is_empty()) {
+            let _ = write!(s, ", unreachable: {}", u.join(" "));
+        }
+        s
+    }
+}
+
+// --only-dirty / --only-behind, a repo is kept when it matches any selected
+// state; with none selected every repo is
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub(crate) struct StateFilter {
+    pub(crate) dirty: bool,
+    pub(crate) behind: bool,
+}
+
+impl StateFilter {
+    pub(crate) fn is_active(self) -> bool {
+        self.dirty || self.behind
+    }
+
+    pub(crate) fn keep(self, s: &RepoStatus) -> bool {
+        !self.is_active()
+            || (self.dirty && s.dirty)
+            || (self.behind && s.ahead_behind.is_some_and(|(_, b)| b > 0))
+    }
+}
+
+fn ahead_behind(repo: &Repository, head: &git2::Reference<'_>) -> Option<(usize, usize)> {
+    let upstream = repo.branch_upstream_name(head.name()?).ok()?;
+    let theirs = repo.refname_to_id(upstream.as_str()?).ok()?;
+    repo.graph_ahead_behind(head.target()?, theirs).ok()
+}
+
+// names of the repos with uncommitted changes, for --fail-on-dirty
+pub(crate) fn dirty_repos(repos: &[RemaConfig]) -> Result<Vec<&str>, git2::Error> {
+    let mut dirty = Vec::new();
+    for r in repos {
+        if is_dirty(r.repo())? {
+            dirty.push(r.name());
+        }
+    }
+    Ok(dirty)
+}
+
+// tracked changes only, untracked files don't make a repo dirty
+fn is_dirty(repo: &Repository) -> Result<bool, git2::Error> {
+    Ok(!dirty_files(repo)?.is_empty())
+}
+
+pub(crate) fn dirty_files(repo: &Repository) -> Result<Vec<PathBuf>, git2::Error> {
+    let mut opts = StatusOptions::new();
+    opts.include_untracked(false).include_ignored(false);
+    Ok(repo
+        .statuses(Some(&mut opts))?
+        .iter()
+        .filter_map(|e| e.path().map(PathBuf::from))
+        .collect())
+}
+
+pub(crate) fn render_table(statuses: &[RepoStatus], color: bool) -> String {
+    let name_w = statuses.iter().map(|s| s.name.len()).max().unwrap_or(0);
+    let branch_w = statuses
+        .iter()
+        .map(|s| s.branch.as_deref().unwrap_or("-").len())
+        .max()
+        .unwrap_or(0);
+
+    let mut out = String::new();
+    for s in statuses {
+        let branch = s.branch.as_deref().unwrap_or("-");
+        let _ = writeln!(
+            out,
+            "{:<name_w$}  {branch:<branch_w$}  {}",
+            s.name,
+            color::paint(&s.describe(), s.tone(), color)
+        );
+    }
+    out
+}
+
+// status --count, e.g. "42 repos, 5 behind, 2 dirty"
+pub(crate) fn render_count(statuses: &[RepoStatus]) -> String {
+    let behind = statuses
+        .iter()
+        .filter(|s| s.ahead_behind.is_some_and(|(_, b)| b > 0))
+        .count();
+    let dirty = statuses.iter().filter(|s| s.dirty).count();
+    let mut out = format!("{} repos, {behind} behind, {dirty} dirty", statuses.len());
+    let unreachable = statuses.iter().filter(|s| s.has_unreachable()).count();
+    if unreachable > 0 {
+        let _ = write!(out, ", {unreachable} unreachable");
+    }
+    out.push('\n');
+    out
+}
+
+// repos whose upstream, as of the last fetch, has commits HEAD doesn't,
+// with how many
+pub(crate) fn pending(statuses: &[RepoStatus]) -> Vec<(&str, usize)> {
+    statuses
+        .iter()
+        .filter_map(|s| match s.ahead_behind {
+            Some((_, behind)) if behind > 0 => Some((s.name.as_str(), behind)),
+            _ => None,
+        })
+        .collect()
+}
+
+// `rema pending`, labelled since the refs may be long out of date
+pub(crate) fn render_pending(statuses: &[RepoStatus]) -> String {
+    let mut out = String::from("based on last fetch:\n");
+    let pending = pending(statuses);
+    if pending.is_empty() {
+        out.push_str("nothing to pull\n");
+    }
+    let name_w = pending.iter().map(|(n, _)| n.len()).max().unwrap_or(0);
+    for (name, behind) in pending {
+        let _ = writeln!(out, "{name:<name_w$}  {behind} new");
+    }
+    out
+}
+
+const COMPACT_COLUMNS: usize = 16;
+
+// one cell per repo in configured order, like a test runner's dots
+pub(crate) fn render_compact(statuses: &[RepoStatus], color: bool) -> String {
+    let mut out = String::new();
+    for row in statuses.chunks(COMPACT_COLUMNS) {
+        let mut cells = String::new();
+        for s in row {
+            // padded outside the color, escapes would count towards the width
+            let symbol = s.symbol();
+            let pad = 4usize.saturating_sub(symbol.chars().count());
+            let _ = write!(
+                cells,
+                "{}{:pad$}",
+                color::paint(&symbol, s.tone(), color),
+                ""
+            );
+        }
+        out.push_str(cells.trim_end());
+        out.push('\n');
+    }
+    out.push_str("✓ up to date  ↓N behind  ↑N ahead  ↕ diverged  * dirty  ? no upstream  ! remote unreachable\n");
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::tests::{commit_all, fixture, track};
+
+    fn status(ahead_behind: Option<(usize, usize)>, dirty: bool) -> RepoStatus {
+        RepoStatus {
+            name: "r".into(),
+            branch: Some("main".into()),
+            ahead_behind,
+            dirty,
+            unreachable: None,
+        }
+    }
+
+    #[test]
+    fn test_render_table_color() {
+        let statuses = vec![status(Some((0, 0)), false), status(Some((1, 2)), false)];
+        assert_eq!(
+            render_table(&statuses, true),
+            "r  main  \x1b[32mup to date\x1b[0m\nr  main  \x1b[31mahead 1, behind 2\x1b[0m\n"
+        );
+        assert_eq!(
+            render_table(&statuses, false),
+            "r  main  up to date\nr  main  ahead 1, behind 2\n"
+        );
+        let cells = render_compact(&statuses, true);
+        assert_eq!(
+            cells.lines().next().unwrap(),
+            "\x1b[32m✓\x1b[0m   \x1b[31m↕\x1b[0m"
+        );
+    }
+
+    #[test]
+    fn test_compact_cells() {
+        let statuses = vec![
+            status(Some((0, 0)), false),
+            status(Some((0, 3)), false),
+            status(Some((2, 0)), false),
+            status(Some((0, 3)), true),
+            status(None, false),
+        ];
+        let out = render_compact(&statuses, false);
+        assert_eq!(out.lines().next().unwrap(), "✓   ↓3  ↑2  *   ?");
+        assert!(out.lines().nth(1).unwrap().contains("↓N behind"));
+    }
+
+    #[test]
+    fn test_compact_wraps_rows() {
+        let statuses = vec![status(Some((0, 0)), false); COMPACT_COLUMNS + 1];
+        let out = render_compact(&statuses, false);
+        assert_eq!(out.lines().count(), 3);
+        assert_eq!(out.lines().nth(1).unwrap(), "✓");
+    }
+
+    #[test]
+    fn test_render_count() {
+        let statuses = [
+            status(Some((0, 0)), false),
+            status(Some((0, 3)), true),
+            status(Some((1, 2)), false),
+            status(None, true),
+        ];
+        assert_eq!(render_count(&statuses), "4 repos, 2 behind, 2 dirty\n");
+    }
+
+    #[test]
+    fn test_status_of_fixture() {
+        let (dir, conf) = fixture("build = []");
+        let s = RepoStatus::of(&conf).unwrap();
+        assert!(s.branch.is_some());
+        assert_eq!(s.ahead_behind, None);
+        assert!(!s.dirty);
+
+        std::fs::write(dir.path().join("rema.toml"), "build = [\"make\"]").unwrap();
+        assert!(RepoStatus::of(&conf).unwrap().dirty);
+    }
+
+    #[test]
+    fn test_pending() {
+        let (_dir, conf) = fixture("build = []");
+        let repo = conf.repo();
+        let head = commit_all(repo, "init");
+        track(&conf, "/srv/app.git");
+        // what a fetch would have left behind, a commit on origin/main only
+        let sig = git2::Signature::now("rema", "rema@example.com").unwrap();
+        let parent = repo.find_commit(head).unwrap();
+        let tree = parent.tree().unwrap();
+        let new = repo
+            .commit(None, &sig, &sig, "upstream", &tree, &[&parent])
+            .unwrap();
+        repo.reference("refs/remotes/origin/main", new, true, "fetched")
+            .unwrap();
+
+        let statuses = [RepoStatus::of(&conf).unwrap(), status(Some((0, 0)), false)];
+        assert_eq!(pending(&statuses), vec![(conf.name(), 1)]);
+        assert!(render_pending(&statuses).starts_with("based on last fetch:\n"));
+        assert!(render_pending(&statuses[1..]).ends_with("nothing to pull\n"));
+    }
+
+    #[test]
+    fn test_check_remotes() {
+        let (dir, conf) = fixture("build = []");
+        let upstream = tempfile::tempdir().unwrap();
+        Repository::init(upstream.path()).unwrap();
+        let repo = conf.repo();
+        repo.remote("origin", upstream.path().to_str().unwrap())
+            .unwrap();
+        repo.remote("old", dir.path().join("gone").to_str().unwrap())
+            .unwrap();
+
+        let mut s = RepoStatus::of(&conf).unwrap();
+        s.check_remotes(&conf).unwrap();
+        assert_eq!(s.unreachable, Some(vec!["old".to_string()]));
+        assert_eq!(s.symbol(), "!");
+        // not the same as being behind
+        assert!(s.describe().ends_with("unreachable: old"));
+    }
+
+    #[test]
+    fn test_state_filter() {
+        let behind = StateFilter {
+            behind: true,
+            ..StateFilter::default()
+        };
+        let (_dir, conf) = fixture("build = []");
+        // clean and up to date
+        assert!(!behind.keep(&status(Some((0, 0)), false)));
+        assert!(!behind.keep(&RepoStatus::of(&conf).unwrap()));
+        assert!(behind.keep(&status(Some((1, 2)), false)));
+
+        let dirty = StateFilter {
+            dirty: true,
+            ..StateFilter::default()
+        };
+        assert!(dirty.keep(&status(Some((0, 0)), true)));
+        assert!(!dirty.keep(&status(Some((0, 2)), false)));
+        assert!(StateFilter::default().keep(&status(None, false)));
+    }
+
+    #[test]
+    fn test_dirty_repos() {
+        let (_clean_dir, clean) = fixture("build = []");
+        let (dir, dirty) = fixture("build = []");
+        std::fs::write(dir.path().join("rema.toml"), "build = [\"make\"]").unwrap();
+        let repos = [clean, dirty];
+        assert_eq!(dirty_repos(&repos).unwrap(), vec![repos[1].name()]);
+    }
+}
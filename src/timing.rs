@@ -0,0 +1,85 @@
+use crate::config::Phase;
+
+use std::fmt::Write;
+use std::time::Duration;
+
+// The parts of a repo's run --trace-timing breaks its time down into
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) enum Step {
+    PrePull,
+    Fetch,
+    // the fast-forward after the fetch
+    Merge,
+    Build,
+    Clean,
+    PostUpdate,
+}
+
+const STEPS: [(Step, &str); 6] = [
+    (Step::PrePull, "pre_pull"),
+    (Step::Fetch, "fetch"),
+    (Step::Merge, "merge"),
+    (Step::Build, "build"),
+    (Step::Clean, "clean"),
+    (Step::PostUpdate, "post_update"),
+];
+
+// time spent in each step, steps that didn't run stay at zero
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub(crate) struct Timings([Duration; 6]);
+
+impl Step {
+    // the step a command of `phase` counts towards, skip_if's towards none
+    pub(crate) fn of(phase: Phase) -> Option<Self> {
+        match phase {
+            Phase::PrePull => Some(Self::PrePull),
+            Phase::Build => Some(Self::Build),
+            Phase::Clean => Some(Self::Clean),
+            Phase::PostUpdate => Some(Self::PostUpdate),
+            Phase::SkipIf => None,
+        }
+    }
+}
+
+impl Timings {
+    pub(crate) fn add(&mut self, step: Step, d: Duration) {
+        self.0[step as usize] += d;
+    }
+
+    pub(crate) fn get(&self, step: Step) -> Duration {
+        self.0[step as usize]
+    }
+
+    // every step by name, in the order they run
+    pub(crate) fn steps(&self) -> impl Iterator<Item = (&'static str, Duration)> + '_ {
+        STEPS
+            .iter()
+            .map(move |(s, name)| (*name, self.0[*s as usize]))
+    }
+
+    // one indented line per step, e.g. "    fetch        1.204s"
+    pub(crate) fn render(&self, indent: &str) -> String {
+        let mut out = String::new();
+        for (name, d) in self.steps() {
+            let _ = writeln!(out, "{indent}{name:<12} {:.3}s", d.as_secs_f64());
+        }
+        out
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_render() {
+        let mut t = Timings::default();
+        t.add(Step::Fetch, Duration::from_millis(1200));
+        t.add(Step::Fetch, Duration::from_millis(4));
+        t.add(Step::PostUpdate, Duration::from_millis(30));
+        assert_eq!(
+            t.render("  "),
+            "  pre_pull     0.000s\n  fetch        1.204s\n  merge        0.000s\n  build        0.000s\n  clean        0.000s\n  post_update  0.030s\n"
+        );
+    }
+}
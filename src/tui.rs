@@ -0,0 +1,221 @@
+use crate::config::Config;
+use crate::run::{self, Ctx};
+use crate::status::{self, RepoStatus};
+
+use std::collections::BTreeSet;
+use std::io::{self, BufRead, IsTerminal};
+
+use clap::ArgMatches;
+use ratatui::crossterm::event::{self, Event, KeyCode, KeyEventKind};
+use ratatui::layout::{Constraint, Layout};
+use ratatui::style::{Style, Stylize};
+use ratatui::widgets::{Block, List, ListItem, ListState, Paragraph};
+use ratatui::{DefaultTerminal, Frame};
+use tracing::warn;
+
+const HELP: &str = "↑/↓ move  space select  p pull  u update  r refresh  q quit";
+
+#[derive(Debug, PartialEq)]
+enum Action {
+    None,
+    Quit,
+    Refresh,
+    // on the selected repos, or the one under the cursor if none are
+    Pull(Vec<String>),
+    Update(Vec<String>),
+}
+
+// The repo list and what's picked in it, kept apart from the terminal so
+// the key handling can be tested
+struct App {
+    statuses: Vec<RepoStatus>,
+    selected: BTreeSet<usize>,
+    list: ListState,
+}
+
+impl App {
+    fn new(statuses: Vec<RepoStatus>) -> Self {
+        let mut list = ListState::default();
+        list.select((!statuses.is_empty()).then_some(0));
+        Self {
+            statuses,
+            selected: BTreeSet::new(),
+            list,
+        }
+    }
+
+    // after a refresh the cursor stays put as far as it can
+    fn set_statuses(&mut self, statuses: Vec<RepoStatus>) {
+        self.selected.retain(|&i| i < statuses.len());
+        let last = statuses.len().checked_sub(1);
+        self.list
+            .select(last.map(|l| self.list.selected().unwrap_or(0).min(l)));
+        self.statuses = statuses;
+    }
+
+    fn targets(&self) -> Vec<String> {
+        let picked = if self.selected.is_empty() {
+            self.list.selected().into_iter().collect()
+        } else {
+            self.selected.clone()
+        };
+        picked
+            .into_iter()
+            .filter_map(|i| self.statuses.get(i))
+            .map(|s| s.name.clone())
+            .collect()
+    }
+
+    fn handle(&mut self, key: KeyCode) -> Action {
+        match key {
+            KeyCode::Char('q') | KeyCode::Esc => return Action::Quit,
+            KeyCode::Char('r') => return Action::Refresh,
+            KeyCode::Char('p') => return Action::Pull(self.targets()),
+            KeyCode::Char('u') => return Action::Update(self.targets()),
+            KeyCode::Down | KeyCode::Char('j') => self.list.select_next(),
+            KeyCode::Up | KeyCode::Char('k') => self.list.select_previous(),
+            KeyCode::Char(' ') => {
+                if let Some(i) = self.list.selected() {
+                    if !self.selected.remove(&i) {
+                        self.selected.insert(i);
+                    }
+                }
+            }
+            _ => {}
+        }
+        Action::None
+    }
+
+    fn draw(&mut self, f: &mut Frame<'_>) {
+        let [list_area, help_area] =
+            Layout::vertical([Constraint::Min(1), Constraint::Length(1)]).areas(f.area());
+        let table = status::render_table(&self.statuses, false);
+        let items = table.lines().enumerate().map(|(i, l)| {
+            let mark = if self.selected.contains(&i) {
+                "[x]"
+            } else {
+                "[ ]"
+            };
+            ListItem::new(format!("{mark} {l}"))
+        });
+        let list = List::new(items)
+            .block(Block::bordered().title(" rema "))
+            .highlight_style(Style::new().reversed());
+        f.render_stateful_widget(list, list_area, &mut self.list);
+        f.render_widget(Paragraph::new(HELP).dim(), help_area);
+    }
+}
+
+fn statuses(config: &Config) -> Result<Vec<RepoStatus>, failure::Error> {
+    let mut out = Vec::new();
+    for repo in config.repos()? {
+        match RepoStatus::of(&repo) {
+            Ok(s) => out.push(s),
+            Err(e) => warn!(repo = %repo.name(), "could not read status: {}", e),
+        }
+    }
+    Ok(out)
+}
+
+// `rema tui`, falls back to plain status output when not on a terminal
+pub(crate) fn run(mut config: Config, ctx: &Ctx, m: &ArgMatches<'_>) -> Result<(), failure::Error> {
+    if !io::stdin().is_terminal() || !io::stdout().is_terminal() {
+        warn!("not a terminal, printing status instead");
+        return run::status(&config, ctx, m);
+    }
+    let mut app = App::new(statuses(&config)?);
+    let mut terminal = ratatui::init();
+    let res = event_loop(&mut terminal, &mut app, &mut config, ctx, m);
+    ratatui::restore();
+    res
+}
+
+fn event_loop(
+    terminal: &mut DefaultTerminal,
+    app: &mut App,
+    config: &mut Config,
+    ctx: &Ctx,
+    m: &ArgMatches<'_>,
+) -> Result<(), failure::Error> {
+    loop {
+        terminal.draw(|f| app.draw(f))?;
+        let Event::Key(key) = event::read()? else {
+            continue;
+        };
+        if key.kind != KeyEventKind::Press {
+            continue;
+        }
+        match app.handle(key.code) {
+            Action::None => continue,
+            Action::Quit => return Ok(()),
+            Action::Refresh => {}
+            Action::Pull(names) => suspended(terminal, config, names, |c| run::pull(c, ctx, m)),
+            Action::Update(names) => {
+                suspended(terminal, config, names, |c| run::update(c, ctx, m));
+            }
+        }
+        app.set_statuses(statuses(config)?);
+    }
+}
+
+// run `f` on just `names` with the terminal back in its normal mode, so
+// commands print as they would outside the tui
+fn suspended<F>(terminal: &mut DefaultTerminal, config: &mut Config, names: Vec<String>, f: F)
+where
+    F: FnOnce(&Config) -> Result<(), failure::Error>,
+{
+    if names.is_empty() {
+        return;
+    }
+    ratatui::restore();
+    let all = config.names.replace(names);
+    let res = f(config);
+    config.names = all;
+    if let Err(e) = &res {
+        eprintln!("error: {}", crate::errors::pretty_error(e));
+    }
+    println!("\npress enter to return");
+    let _ = io::stdin().lock().lines().next();
+    *terminal = ratatui::init();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn status(name: &str) -> RepoStatus {
+        RepoStatus {
+            name: name.into(),
+            branch: Some("main".into()),
+            ahead_behind: Some((0, 0)),
+            dirty: false,
+            unreachable: None,
+        }
+    }
+
+    #[test]
+    fn test_keys() {
+        let mut app = App::new(vec![status("a"), status("b"), status("c")]);
+        assert_eq!(
+            app.handle(KeyCode::Char('p')),
+            Action::Pull(vec!["a".into()])
+        );
+
+        app.handle(KeyCode::Down);
+        app.handle(KeyCode::Char(' '));
+        app.handle(KeyCode::Down);
+        app.handle(KeyCode::Char(' '));
+        assert_eq!(
+            app.handle(KeyCode::Char('u')),
+            Action::Update(vec!["b".into(), "c".into()])
+        );
+        // space again unselects
+        app.handle(KeyCode::Char(' '));
+        assert_eq!(app.targets(), ["b"]);
+        assert_eq!(app.handle(KeyCode::Char('q')), Action::Quit);
+
+        app.set_statuses(vec![status("a")]);
+        assert_eq!(app.list.selected(), Some(0));
+        assert!(app.selected.is_empty());
+    }
+}
@@ -0,0 +1,145 @@
+// run_as_user and run_as_group, resolved to ids before the command spawns
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) struct RunAs {
+    pub(crate) uid: Option<u32>,
+    pub(crate) gid: Option<u32>,
+}
+
+// A user without a group runs as that user's primary group. Names or
+// numeric ids work, only root can switch, and there's no switching at all
+// outside unix.
+#[cfg(unix)]
+pub(crate) fn resolve(user: Option<&str>, group: Option<&str>) -> Result<RunAs, String> {
+    let user = user.map(lookup_user).transpose()?;
+    let gid = match group {
+        Some(g) => Some(lookup_group(g)?),
+        None => user.map(|(_, gid)| gid),
+    };
+    let run_as = RunAs {
+        uid: user.map(|(uid, _)| uid),
+        gid,
+    };
+    // SAFETY: geteuid can't fail
+    let euid = unsafe { libc::geteuid() };
+    let switching = run_as.uid.is_some_and(|u| u != euid)
+        || run_as.gid.is_some_and(|g| g != unsafe { libc::getegid() });
+    if switching && euid != 0 {
+        return Err("run_as_user and run_as_group need rema to run as root".into());
+    }
+    Ok(run_as)
+}
+
+#[cfg(not(unix))]
+pub(crate) fn resolve(user: Option<&str>, group: Option<&str>) -> Result<RunAs, String> {
+    if user.is_some() || group.is_some() {
+        return Err("run_as_user and run_as_group are only supported on unix".into());
+    }
+    Ok(RunAs {
+        uid: None,
+        gid: None,
+    })
+}
+
+#[cfg(unix)]
+pub(crate) fn apply(command: &mut std::process::Command, run_as: RunAs) {
+    use std::os::unix::process::CommandExt;
+    // the gid goes first, std drops root's supplementary groups with the uid
+    if let Some(g) = run_as.gid {
+        command.gid(g);
+    }
+    if let Some(u) = run_as.uid {
+        command.uid(u);
+    }
+}
+
+#[cfg(not(unix))]
+pub(crate) fn apply(_: &mut std::process::Command, _: RunAs) {}
+
+// the reentrant lookups since commands spawn from several threads
+#[cfg(unix)]
+fn lookup_user(name: &str) -> Result<(u32, u32), String> {
+    use std::ffi::CString;
+
+    let c = CString::new(name).map_err(|_| format!("bad user name {name:?}"))?;
+    let mut pw: libc::passwd = unsafe { std::mem::zeroed() };
+    let mut buf = vec![0 as libc::c_char; 4096];
+    let mut found = std::ptr::null_mut();
+    // SAFETY: every pointer is valid for the length given
+    let res = unsafe {
+        match name.parse::<libc::uid_t>() {
+            Ok(uid) => libc::getpwuid_r(
+                uid,
+                &raw mut pw,
+                buf.as_mut_ptr(),
+                buf.len(),
+                &raw mut found,
+            ),
+            Err(_) => libc::getpwnam_r(
+                c.as_ptr(),
+                &raw mut pw,
+                buf.as_mut_ptr(),
+                buf.len(),
+                &raw mut found,
+            ),
+        }
+    };
+    if res != 0 || found.is_null() {
+        return Err(format!("no user {name:?}"));
+    }
+    Ok((pw.pw_uid, pw.pw_gid))
+}
+
+#[cfg(unix)]
+fn lookup_group(name: &str) -> Result<u32, String> {
+    use std::ffi::CString;
+
+    if let Ok(gid) = name.parse::<libc::gid_t>() {
+        return Ok(gid);
+    }
+    let c = CString::new(name).map_err(|_| format!("bad group name {name:?}"))?;
+    let mut gr: libc::group = unsafe { std::mem::zeroed() };
+    let mut buf = vec![0 as libc::c_char; 4096];
+    let mut found = std::ptr::null_mut();
+    // SAFETY: every pointer is valid for the length given
+    let res = unsafe {
+        libc::getgrnam_r(
+            c.as_ptr(),
+            &raw mut gr,
+            buf.as_mut_ptr(),
+            buf.len(),
+            &raw mut found,
+        )
+    };
+    if res != 0 || found.is_null() {
+        return Err(format!("no group {name:?}"));
+    }
+    Ok(gr.gr_gid)
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_resolve() {
+        assert_eq!(
+            resolve(None, None),
+            Ok(RunAs {
+                uid: None,
+                gid: None
+            })
+        );
+        assert!(resolve(Some("no-such-user-rema"), None).is_err());
+        assert!(resolve(None, Some("no-such-group-rema")).is_err());
+        let root = resolve(Some("root"), None);
+        if unsafe { libc::geteuid() } == 0 {
+            assert_eq!(
+                root,
+                Ok(RunAs {
+                    uid: Some(0),
+                    gid: Some(0)
+                })
+            );
+        }
+    }
+}
@@ -0,0 +1,102 @@
+use crate::config::RemaConfig;
+
+use std::fmt;
+
+// How a repo differs from the pin in its rema.toml
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) enum Drift {
+    // actual is None when HEAD is detached
+    Branch {
+        expected: String,
+        actual: Option<String>,
+    },
+    Commit {
+        expected: git2::Oid,
+        actual: Option<git2::Oid>,
+    },
+}
+
+impl fmt::Display for Drift {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Branch { expected, actual } => write!(
+                f,
+                "on branch {}, expected {expected}",
+                actual.as_deref().unwrap_or("(detached)")
+            ),
+            Self::Commit { expected, actual } => write!(
+                f,
+                "at {}, pinned to {expected}",
+                actual.map_or_else(|| "(no commit)".into(), |o| o.to_string())
+            ),
+        }
+    }
+}
+
+// read-only, an empty list means the repo is on its pin or has none
+pub(crate) fn check(conf: &RemaConfig) -> Result<Vec<Drift>, git2::Error> {
+    let repo = conf.repo();
+    let mut drift = Vec::new();
+
+    if let Some(expected) = conf.expected_branch() {
+        let head = repo.head()?;
+        let actual = if head.is_branch() {
+            head.shorthand().map(String::from)
+        } else {
+            None
+        };
+        if actual.as_deref() != Some(expected) {
+            drift.push(Drift::Branch {
+                expected: expected.into(),
+                actual,
+            });
+        }
+    }
+
+    if let Some(pin) = conf.pinned_commit() {
+        let expected = repo.revparse_single(pin)?.peel_to_commit()?.id();
+        let actual = conf.head();
+        if actual != Some(expected) {
+            drift.push(Drift::Commit { expected, actual });
+        }
+    }
+    Ok(drift)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::tests::{commit_all, fixture};
+
+    use std::fs;
+
+    #[test]
+    fn test_pinned_commit() {
+        let (dir, conf) = fixture("");
+        let head = conf.head().unwrap();
+        fs::write(
+            dir.path().join("rema.toml"),
+            format!(r#"pinned_commit = "{head}""#),
+        )
+        .unwrap();
+        let conf = RemaConfig::load(dir.path().to_path_buf(), false).unwrap();
+        assert_eq!(check(&conf).unwrap(), vec![]);
+
+        let new = commit_all(conf.repo(), "moved on");
+        assert_eq!(
+            check(&conf).unwrap(),
+            vec![Drift::Commit {
+                expected: head,
+                actual: Some(new)
+            }]
+        );
+    }
+
+    #[test]
+    fn test_expected_branch() {
+        let (_dir, conf) = fixture(r#"expected_branch = "release""#);
+        let drift = check(&conf).unwrap();
+        assert_eq!(drift.len(), 1);
+        assert!(drift[0].to_string().ends_with("expected release"));
+    }
+}
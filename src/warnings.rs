@@ -0,0 +1,140 @@
+use std::fmt::{self, Write};
+use std::sync::Mutex;
+
+use tracing::field::{Field, Visit};
+use tracing::{Event, Level, Subscriber};
+use tracing_subscriber::layer::{Context, Layer};
+
+// Every warning logged this run, whatever logged it. --strict fails the run
+// when there are any, which covers among others:
+// - repos skipped as missing, as a submodule or because their status can't
+//   be read
+// - --repos-from names with no repo
+// - fetch and repo_retries retries, unreachable remotes in status
+//   --check-remotes
+// - a stale or overridden lockfile
+// - a fetched config falling back to its cached copy
+// - builds left detached by update --ref
+// - notify commands, output, events and built_commit_files that couldn't be
+//   written
+// REMA_LOG doesn't hide warnings from it. Only a count and the first few are
+// kept, a daemon warns for as long as it runs.
+static SEEN: Mutex<Seen> = Mutex::new(Seen {
+    count: 0,
+    first: Vec::new(),
+});
+
+// warnings listed by check before the rest are only counted
+const SHOWN: usize = 5;
+
+struct Seen {
+    count: usize,
+    first: Vec<String>,
+}
+
+// the tracing layer feeding SEEN, installed by logging::init
+pub(crate) struct Sink;
+
+impl<S: Subscriber> Layer<S> for Sink {
+    fn on_event(&self, event: &Event<'_>, _: Context<'_, S>) {
+        if *event.metadata().level() == Level::WARN {
+            let mut message = Message(String::new());
+            event.record(&mut message);
+            let mut seen = SEEN.lock().unwrap();
+            seen.count += 1;
+            if seen.first.len() < SHOWN {
+                seen.first.push(message.0);
+            }
+        }
+    }
+}
+
+struct Message(String);
+
+impl Visit for Message {
+    fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
+        if field.name() == "message" {
+            self.0 = format!("{value:?}");
+        }
+    }
+}
+
+// with --strict, an error when anything warned
+pub(crate) fn check(strict: bool) -> Result<(), failure::Error> {
+    if strict {
+        check_since(0, "--strict")
+    } else {
+        Ok(())
+    }
+}
+
+// warnings so far, for check_since
+pub(crate) fn count() -> usize {
+    SEEN.lock().unwrap().count
+}
+
+// an error naming `flag` when anything warned after count() was `from`, e.g.
+// -W error around loading the configs
+pub(crate) fn check_since(from: usize, flag: &str) -> Result<(), failure::Error> {
+    let seen = SEEN.lock().unwrap();
+    let first = seen.first.get(from..).unwrap_or_default();
+    fail_on(seen.count.saturating_sub(from), first, flag)
+}
+
+// tests that count warnings take this, the sink is shared by every thread
+#[cfg(test)]
+pub(crate) static SERIAL: Mutex<()> = Mutex::new(());
+
+fn fail_on(count: usize, first: &[String], flag: &str) -> Result<(), failure::Error> {
+    if count == 0 {
+        return Ok(());
+    }
+    let mut msg = format!("{count} warnings with {flag}:");
+    for w in first.iter().take(SHOWN) {
+        msg.push_str("\n  ");
+        msg.push_str(w);
+    }
+    if count > SHOWN {
+        let _ = write!(msg, "\n  ... and {} more", count - SHOWN);
+    }
+    Err(failure::err_msg(msg))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use tracing::warn;
+    use tracing_subscriber::layer::SubscriberExt;
+
+    #[test]
+    fn test_sink_collects_warnings() {
+        let _serial = SERIAL.lock().unwrap();
+        let subscriber = tracing_subscriber::registry().with(Sink);
+        tracing::subscriber::with_default(subscriber, || {
+            tracing::info!("not a warning");
+            warn!(
+                repo = "app",
+                event = "missing",
+                "{}: missing, skipped",
+                "app"
+            );
+        });
+        let seen = SEEN.lock().unwrap();
+        assert!(seen.count >= 1);
+        assert!(seen.first.len() <= SHOWN);
+        drop(seen);
+        assert!(check(false).is_ok());
+    }
+
+    #[test]
+    fn test_fail_on() {
+        assert!(fail_on(0, &[], "--strict").is_ok());
+        let first = (0..SHOWN).map(|i| format!("w{i}")).collect::<Vec<_>>();
+        let err = fail_on(7, &first, "--strict").unwrap_err().to_string();
+        assert_eq!(
+            err,
+            "7 warnings with --strict:\n  w0\n  w1\n  w2\n  w3\n  w4\n  ... and 2 more"
+        );
+    }
+}